@@ -9,7 +9,7 @@ int fibonacci(int n, int b) {
     return 1;
   }
 
-  return fibonacci(n - 1) + fibonacci(n - 2);
+  return fibonacci(n - 1, b) + fibonacci(n - 2, b);
 }
 
 int main() {
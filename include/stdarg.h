@@ -0,0 +1,9 @@
+#ifndef __STDARG_H
+#define __STDARG_H
+
+// Only the type is provided, so that functions taking a va_list can be
+// declared. seu9cc cannot define variadic functions, so there is no
+// va_start, va_arg or va_end.
+typedef char *va_list;
+
+#endif
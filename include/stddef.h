@@ -0,0 +1,10 @@
+#ifndef __STDDEF_H
+#define __STDDEF_H
+
+#define NULL 0
+
+typedef unsigned long size_t;
+typedef long ptrdiff_t;
+typedef int wchar_t;
+
+#endif
@@ -0,0 +1,34 @@
+#ifndef __STDIO_H
+#define __STDIO_H
+
+#include <stddef.h>
+#include <stdarg.h>
+
+#define EOF (-1)
+
+typedef struct _IO_FILE FILE;
+
+extern FILE *stdin;
+extern FILE *stdout;
+extern FILE *stderr;
+
+int printf(char *fmt, ...);
+int fprintf(FILE *stream, char *fmt, ...);
+int sprintf(char *buf, char *fmt, ...);
+int snprintf(char *buf, size_t n, char *fmt, ...);
+int vprintf(char *fmt, va_list ap);
+int vfprintf(FILE *stream, char *fmt, va_list ap);
+int putchar(int c);
+int puts(char *s);
+int fputc(int c, FILE *stream);
+int fputs(char *s, FILE *stream);
+int getchar(void);
+int fgetc(FILE *stream);
+char *fgets(char *buf, int n, FILE *stream);
+FILE *fopen(char *path, char *mode);
+int fclose(FILE *stream);
+int fflush(FILE *stream);
+size_t fread(void *buf, size_t size, size_t n, FILE *stream);
+size_t fwrite(void *buf, size_t size, size_t n, FILE *stream);
+
+#endif
@@ -0,0 +1,24 @@
+#ifndef __STDLIB_H
+#define __STDLIB_H
+
+#include <stddef.h>
+
+#define EXIT_SUCCESS 0
+#define EXIT_FAILURE 1
+
+void *malloc(size_t size);
+void *calloc(size_t n, size_t size);
+void *realloc(void *ptr, size_t size);
+void free(void *ptr);
+void exit(int status);
+void abort(void);
+int atoi(char *s);
+long atol(char *s);
+long strtol(char *s, char **end, int base);
+unsigned long strtoul(char *s, char **end, int base);
+int abs(int x);
+long labs(long x);
+int rand(void);
+void srand(unsigned seed);
+
+#endif
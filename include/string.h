@@ -0,0 +1,20 @@
+#ifndef __STRING_H
+#define __STRING_H
+
+#include <stddef.h>
+
+void *memset(void *s, int c, size_t n);
+void *memcpy(void *dst, void *src, size_t n);
+void *memmove(void *dst, void *src, size_t n);
+int memcmp(void *s1, void *s2, size_t n);
+size_t strlen(char *s);
+char *strcpy(char *dst, char *src);
+char *strncpy(char *dst, char *src, size_t n);
+char *strcat(char *dst, char *src);
+int strcmp(char *s1, char *s2);
+int strncmp(char *s1, char *s2, size_t n);
+char *strchr(char *s, int c);
+char *strrchr(char *s, int c);
+char *strstr(char *haystack, char *needle);
+
+#endif
@@ -0,0 +1,153 @@
+use crate::gen_ir::{Function, IROp, IR};
+
+use std::collections::HashMap;
+
+// Control-flow graph.
+//
+// gen_ir emits a flat list of instructions in which labels, jumps and
+// returns are mixed with ordinary code. This module splits a function
+// into basic blocks with explicit successor and predecessor edges, so
+// that analyses don't have to rediscover control flow themselves.
+//
+// A block starts at a label or right after a jump, branch or return,
+// and only its last instruction may transfer control. Blocks keep the
+// order in which they appeared in the original code, and a block that
+// doesn't end with an unconditional jump or a return falls through to
+// the next one. Flattening the blocks in order therefore reproduces an
+// equivalent instruction list.
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub label: Option<usize>, // Label at the head of the block
+    pub ir: Vec<IR>,          // Instructions, without the label
+    pub succ: Vec<usize>,     // Successor block indices
+    pub pred: Vec<usize>,     // Predecessor block indices
+}
+
+impl BasicBlock {
+    fn new(label: Option<usize>) -> Self {
+        BasicBlock {
+            label,
+            ir: vec![],
+            succ: vec![],
+            pred: vec![],
+        }
+    }
+
+    fn is_terminated(&self) -> bool {
+        match self.ir.last() {
+            Some(ir) => matches!(ir.op, IROp::Jmp | IROp::If | IROp::Unless | IROp::Return),
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>, // blocks[0] is the entry block
+}
+
+impl Cfg {
+    pub fn new(f: &Function) -> Self {
+        let mut blocks = vec![BasicBlock::new(None)];
+        for ir in &f.ir {
+            if ir.op == IROp::Label {
+                blocks.push(BasicBlock::new(ir.lhs));
+                continue;
+            }
+            if blocks.last().unwrap().is_terminated() {
+                blocks.push(BasicBlock::new(None));
+            }
+            blocks.last_mut().unwrap().ir.push(ir.clone());
+        }
+
+        let mut cfg = Cfg { blocks };
+        cfg.link();
+        cfg
+    }
+
+    // Recomputes successor and predecessor edges from the instructions.
+    fn link(&mut self) {
+        let mut labels = HashMap::new();
+        for (i, bb) in self.blocks.iter().enumerate() {
+            if let Some(label) = bb.label {
+                labels.insert(label, i);
+            }
+        }
+        let target = |label: Option<usize>| -> usize {
+            *labels
+                .get(&label.unwrap())
+                .unwrap_or_else(|| panic!("undefined label: .L{}", label.unwrap()))
+        };
+
+        let n = self.blocks.len();
+        for i in 0..n {
+            let next = if i + 1 < n { vec![i + 1] } else { vec![] };
+            let succ = match self.blocks[i].ir.last().map(|ir| (&ir.op, ir)) {
+                Some((IROp::Jmp, ir)) => vec![target(ir.lhs)],
+                Some((IROp::If, ir)) | Some((IROp::Unless, ir)) => {
+                    let mut v = next;
+                    let t = target(ir.rhs);
+                    if !v.contains(&t) {
+                        v.push(t);
+                    }
+                    v
+                }
+                Some((IROp::Return, _)) => vec![],
+                _ => next,
+            };
+            self.blocks[i].succ = succ;
+        }
+
+        for bb in &mut self.blocks {
+            bb.pred.clear();
+        }
+        for i in 0..n {
+            for s in self.blocks[i].succ.clone() {
+                self.blocks[s].pred.push(i);
+            }
+        }
+    }
+
+    // Removes blocks that cannot be reached from the entry block, such
+    // as code following a `return`. A block only falls through to its
+    // immediate neighbour, and that neighbour is reachable whenever the
+    // block itself is, so the remaining blocks still flatten correctly.
+    pub fn remove_unreachable(&mut self) {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            if reachable[i] {
+                continue;
+            }
+            reachable[i] = true;
+            stack.extend(self.blocks[i].succ.iter().cloned());
+        }
+
+        let mut i = 0;
+        self.blocks.retain(|_| {
+            i += 1;
+            reachable[i - 1]
+        });
+        self.link();
+    }
+
+    pub fn flatten(&self) -> Vec<IR> {
+        let mut v = vec![];
+        for bb in &self.blocks {
+            if let Some(label) = bb.label {
+                v.push(IR::new(IROp::Label, Some(label), None));
+            }
+            v.extend(bb.ir.iter().cloned());
+        }
+        v
+    }
+}
+
+pub fn remove_unreachable(fns: &mut Vec<Function>) {
+    for f in fns {
+        let mut cfg = Cfg::new(f);
+        cfg.remove_unreachable();
+        f.ir = cfg.flatten();
+    }
+}
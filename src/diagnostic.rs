@@ -0,0 +1,135 @@
+use std::fmt;
+
+// Compiler diagnostics.
+//
+// Errors and warnings found in the input are reported as values of
+// `Diagnostic` instead of aborting the process, so that seu9cc can be
+// embedded as a library. A diagnostic carries the location it refers to
+// along with a copy of the source line, so it can be rendered after the
+// input buffers are gone.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: String,
+    pub line: usize,    // 1-based
+    pub col: usize,     // 1-based
+    pub len: usize,     // Number of characters, at least 1
+    pub source: String, // The whole line, for rendering
+}
+
+impl Span {
+    // Makes a span for buf[start..end].
+    pub fn new(file: &str, buf: &[char], start: usize, end: usize) -> Self {
+        let start = start.min(buf.len());
+        let line_start = buf[..start]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |i| i + 1);
+        let line_end = buf[start..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(buf.len(), |i| start + i);
+
+        Span {
+            file: file.into(),
+            line: buf[..start].iter().filter(|c| **c == '\n').count() + 1,
+            col: start - line_start + 1,
+            len: end.min(line_end).saturating_sub(start).max(1),
+            source: buf[line_start..line_end].iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Box<Span>>, // Boxed to keep Result<_, Diagnostic> small
+    pub notes: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: &str) -> Self {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            span: None,
+            notes: vec![],
+        }
+    }
+
+    pub fn error(message: &str) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: &str) -> Self {
+        Self::new(Severity::Warning, message)
+    }
+
+    pub fn note(message: &str) -> Self {
+        Self::new(Severity::Note, message)
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(Box::new(span));
+        self
+    }
+
+    pub fn with_note(mut self, note: Diagnostic) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+// Renders a diagnostic in the style of GCC and Clang:
+//
+//   foo.c:3:5: error: undefined variable: x
+//     x = 1;
+//     ^
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(ref span) => {
+                writeln!(
+                    f,
+                    "{}:{}:{}: {}: {}",
+                    span.file, span.line, span.col, self.severity, self.message
+                )?;
+                writeln!(f, "{}", span.source)?;
+                let pad: String = span
+                    .source
+                    .chars()
+                    .take(span.col - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                writeln!(f, "{}^{}", pad, "~".repeat(span.len - 1))?;
+            }
+            None => writeln!(f, "seu9cc: {}: {}", self.severity, self.message)?,
+        }
+        for note in &self.notes {
+            write!(f, "{}", note)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use crate::parse::{Node, NodeType};
+use crate::{TokenType, Type};
+
+// Constant folding.
+//
+// This pass evaluates integer expressions whose operands are known at
+// compile time, such as `1 + 2 * 3` or the multiplications sema inserts
+// to scale pointer arithmetic, and drops operations that don't change
+// their operand (`x * 1`, `x + 0`, ...). Arithmetic is done in the type
+// of each node and wraps around on overflow. Operations whose result is
+// undefined, like division by zero or shifting by the width of the
+// type or more, are left for the program to hit at runtime.
+//
+// The pass works on both typed and untyped trees, so the parser can use
+// it too to evaluate constant expressions such as array lengths.
+
+// Returns the value of a folded node if it is a constant.
+pub fn const_value(node: &Node) -> Option<i64> {
+    match node.op {
+        NodeType::Num(val) => Some(val),
+        _ => None,
+    }
+}
+
+// Wraps a value around to the range of an integer type.
+fn wrap(val: i64, ty: &Type) -> i64 {
+    match (ty.size, ty.is_unsigned) {
+        (1, false) => val as i8 as i64,
+        (1, true) => val as u8 as i64,
+        (2, false) => val as i16 as i64,
+        (2, true) => val as u16 as i64,
+        (4, false) => val as i32 as i64,
+        (4, true) => val as u32 as i64,
+        _ => val,
+    }
+}
+
+// Evaluates a binary operator over constant operands. Operands of
+// unsigned types are held zero-extended, so comparing or dividing
+// them as u64 gives the right result.
+fn eval_binop(op: &TokenType, lhs: &Node, rhs: &Node) -> Option<i64> {
+    use self::TokenType::*;
+    let (l, r) = (const_value(lhs)?, const_value(rhs)?);
+    let (ul, ur) = (l as u64, r as u64);
+    let is_unsigned = lhs.ty.is_unsigned || rhs.ty.is_unsigned;
+    let bits = lhs.ty.size.max(4) as i64 * 8;
+    let val = match op {
+        Plus => l.wrapping_add(r),
+        Minus => l.wrapping_sub(r),
+        Mul => l.wrapping_mul(r),
+        Div | Mod if r == 0 => return None,
+        Div if is_unsigned => (ul / ur) as i64,
+        Div => l.wrapping_div(r),
+        Mod if is_unsigned => (ul % ur) as i64,
+        Mod => l.wrapping_rem(r),
+        SHL | SHR if !(0..bits).contains(&r) => return None,
+        SHL => l.wrapping_shl(r as u32),
+        SHR if lhs.ty.is_unsigned => (ul >> r) as i64,
+        SHR => l >> r,
+        LeftAngleBracket if is_unsigned => (ul < ur) as i64,
+        LeftAngleBracket => (l < r) as i64,
+        RightAngleBracket if is_unsigned => (ul > ur) as i64,
+        RightAngleBracket => (l > r) as i64,
+        LE if is_unsigned => (ul <= ur) as i64,
+        LE => (l <= r) as i64,
+        GE if is_unsigned => (ul >= ur) as i64,
+        GE => (l >= r) as i64,
+        EQ => (l == r) as i64,
+        NE => (l != r) as i64,
+        And => l & r,
+        Hat => l ^ r,
+        VerticalBar => l | r,
+        Logand => (l != 0 && r != 0) as i64,
+        Logor => (l != 0 || r != 0) as i64,
+        Comma => r,
+        _ => return None,
+    };
+    Some(val)
+}
+
+// The parser's trees are untyped, so every operator in them is an int.
+// The result of an arithmetic operator is made at least as wide as its
+// operands, which is the type sema would give it; in typed trees this
+// changes nothing.
+fn wider(x: &Type, y: &Type) -> Box<Type> {
+    if y.size > x.size || (y.size == x.size && y.is_unsigned) {
+        Box::new(y.clone())
+    } else {
+        Box::new(x.clone())
+    }
+}
+
+fn binop_type(op: &TokenType, ty: &Type, lhs: &Type, rhs: &Type) -> Box<Type> {
+    use self::TokenType::*;
+    match op {
+        Plus | Minus | Mul | Div | Mod | And | Hat | VerticalBar => wider(&wider(ty, lhs), rhs),
+        SHL | SHR => wider(ty, lhs),
+        Comma => wider(ty, rhs),
+        _ => Box::new(ty.clone()),
+    }
+}
+
+// Simplifies `x op c` and `c op x` where the result is simply `x`.
+fn identity(op: &TokenType, lhs: &Node, rhs: &Node) -> Option<bool> {
+    use self::TokenType::*;
+    match (op, const_value(lhs), const_value(rhs)) {
+        (Plus, _, Some(0)) | (Minus, _, Some(0)) | (Mul, _, Some(1)) | (Div, _, Some(1)) => {
+            Some(true)
+        }
+        (SHL, _, Some(0)) | (SHR, _, Some(0)) | (VerticalBar, _, Some(0)) | (Hat, _, Some(0)) => {
+            Some(true)
+        }
+        (Plus, Some(0), _) | (Mul, Some(1), _) => Some(false),
+        _ => None,
+    }
+}
+
+#[allow(clippy::boxed_local)]
+fn fold_box(node: Box<Node>) -> Box<Node> {
+    Box::new(fold(*node))
+}
+
+fn fold_opt(node: Option<Box<Node>>) -> Option<Box<Node>> {
+    node.map(fold_box)
+}
+
+fn fold_vec(nodes: Vec<Node>) -> Vec<Node> {
+    nodes.into_iter().map(fold).collect()
+}
+
+pub fn fold(mut node: Node) -> Node {
+    use self::NodeType::*;
+    node.op = match node.op {
+        BinOp(op, lhs, rhs) => {
+            let lhs = fold_box(lhs);
+            let rhs = fold_box(rhs);
+
+            if let Some(val) = eval_binop(&op, &lhs, &rhs) {
+                node.ty = binop_type(&op, &node.ty, &lhs.ty, &rhs.ty);
+                node.op = Num(wrap(val, &node.ty));
+                return node;
+            }
+
+            match identity(&op, &lhs, &rhs) {
+                Some(true) => return *lhs,
+                Some(false) => return *rhs,
+                None => BinOp(op, lhs, rhs),
+            }
+        }
+        Neg(expr) => {
+            let expr = fold_box(expr);
+            match const_value(&expr) {
+                Some(val) => {
+                    node.ty = wider(&node.ty, &expr.ty);
+                    Num(wrap(val.wrapping_neg(), &node.ty))
+                }
+                None => Neg(expr),
+            }
+        }
+        UnaryPlus(expr) => {
+            let expr = fold_box(expr);
+            match const_value(&expr) {
+                Some(val) => {
+                    node.ty = wider(&node.ty, &expr.ty);
+                    Num(val)
+                }
+                None => UnaryPlus(expr),
+            }
+        }
+        BitNot(expr) => {
+            let expr = fold_box(expr);
+            match const_value(&expr) {
+                Some(val) => {
+                    node.ty = wider(&node.ty, &expr.ty);
+                    Num(wrap(!val, &node.ty))
+                }
+                None => BitNot(expr),
+            }
+        }
+        Cast(expr) => {
+            let expr = fold_box(expr);
+            match const_value(&expr) {
+                Some(val) if node.ty.is_integer() => Num(wrap(val, &node.ty)),
+                _ => Cast(expr),
+            }
+        }
+        Exclamation(expr) => {
+            let expr = fold_box(expr);
+            match const_value(&expr) {
+                Some(val) => Num((val == 0) as i64),
+                None => Exclamation(expr),
+            }
+        }
+        Ternary(cond, then, els) => {
+            let cond = fold_box(cond);
+            let then = fold_box(then);
+            let els = fold_box(els);
+            match const_value(&cond) {
+                Some(0) => return *els,
+                Some(_) => return *then,
+                None => Ternary(cond, then, els),
+            }
+        }
+        Vardef(name, init, scope) => Vardef(name, fold_opt(init), scope),
+        If(cond, then, els) => If(fold_box(cond), fold_box(then), fold_opt(els)),
+        For(init, cond, inc, body) => For(
+            fold_box(init),
+            fold_box(cond),
+            fold_box(inc),
+            fold_box(body),
+        ),
+        DoWhile(body, cond) => DoWhile(fold_box(body), fold_box(cond)),
+        Switch(cond, body, cases, has_default) => {
+            Switch(fold_box(cond), fold_box(body), cases, has_default)
+        }
+        Case(val, body, idx) => Case(val, fold_box(body), idx),
+        Default(body) => Default(fold_box(body)),
+        Label(name, body) => Label(name, fold_box(body)),
+        Addr(expr) => Addr(fold_box(expr)),
+        Deref(expr) => Deref(fold_box(expr)),
+        Dot(expr, name, offset) => Dot(fold_box(expr), name, offset),
+        PostInc(expr) => PostInc(fold_box(expr)),
+        PostDec(expr) => PostDec(fold_box(expr)),
+        Return(expr) => Return(fold_box(expr)),
+        Sizeof(expr) => Sizeof(fold_box(expr)),
+        Alignof(expr) => Alignof(fold_box(expr)),
+        Call(name, args) => Call(name, fold_vec(args)),
+        Func(name, args, body, stacksize) => Func(name, args, fold_box(body), stacksize),
+        CompStmt(stmts) => CompStmt(fold_vec(stmts)),
+        VecStmt(stmts) => VecStmt(fold_vec(stmts)),
+        ExprStmt(expr) => ExprStmt(fold_box(expr)),
+        StmtExpr(body) => StmtExpr(fold_box(body)),
+        op => op,
+    };
+    node
+}
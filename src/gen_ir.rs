@@ -10,18 +10,41 @@
 // > Such infinite number of registers are mapped to a finite registers
 // > in a later pass.
 
+use crate::diagnostic::Diagnostic;
 use crate::parse::{Node, NodeType};
+use crate::util::roundup;
 use crate::{Ctype, Scope, TokenType, Type};
 
+use std::collections::HashMap;
+use std::mem;
 use std::sync::Mutex;
 
+// Number of call arguments passed to backends in registers. This is
+// what x86-64 has, and no more than any backend can allocate at once.
+const MAX_REG_ARGS: usize = 6;
+
 lazy_static! {
     static ref NUM_REGS: Mutex<usize> = Mutex::new(0);
     static ref NLABEL: Mutex<usize> = Mutex::new(1);
     static ref RETURN_LABEL: Mutex<usize> = Mutex::new(0);
     static ref RETURN_REG: Mutex<usize> = Mutex::new(0);
     static ref BREAK_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref CONTINUE_LABEL: Mutex<usize> = Mutex::new(0);
+    static ref NAMED_LABELS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+    static ref CASE_LABELS: Mutex<Vec<usize>> = Mutex::new(vec![]);
+    static ref DEFAULT_LABEL: Mutex<usize> = Mutex::new(0);
     static ref CODE: Mutex<Vec<IR>> = Mutex::new(vec![]);
+    static ref STACKSIZE: Mutex<usize> = Mutex::new(0);
+    static ref ERROR: Mutex<Option<Diagnostic>> = Mutex::new(None);
+}
+
+// Records an error found while generating code. Generation goes on so
+// that the IR stays consistent, and gen_ir() reports the first error.
+fn error(node: &Node, msg: &str) {
+    let mut err = ERROR.lock().unwrap();
+    if err.is_none() {
+        *err = Some(node.error(msg));
+    }
 }
 
 fn add(op: IROp, lhs: Option<usize>, rhs: Option<usize>) {
@@ -71,56 +94,47 @@ pub enum IROp {
     Mul,
     MulImm,
     Div,
+    UDiv,
     Imm,
     Bprel,
     Mov,
     Return,
-    Call(String, usize, [usize; 6]),
+    Call(String, Vec<usize>, Vec<usize>), // name, args in registers, bp offsets of the rest
     Label,
     LabelAddr(String),
     EQ,
     NE,
     LE,
     LT,
+    GE,
+    GT,
+    ULE,
+    ULT,
+    UGE,
+    UGT,
     AND,
     OR,
     XOR,
     SHL,
     SHR,
+    USHR,
     Mod,
+    UMod,
     Neg,
+    Not,
+    Ext(u8),  // Sign-extend the low bytes
+    UExt(u8), // Zero-extend the low bytes
     Jmp,
     If,
     Unless,
-    Load(u8),
+    Load(u8),  // Sign-extending
+    ULoad(u8), // Zero-extending
     Store(u8),
     StoreArg(u8),
     Kill,
     Nop,
 }
 
-impl From<NodeType> for IROp {
-    fn from(node_type: NodeType) -> Self {
-        match node_type {
-            NodeType::BinOp(op, _, _) => Self::from(op),
-            e => panic!("cannot convert: {:?}", e),
-        }
-    }
-}
-
-impl From<TokenType> for IROp {
-    fn from(token_type: TokenType) -> Self {
-        match token_type {
-            TokenType::Plus => IROp::Add,
-            TokenType::Minus => IROp::Sub,
-            TokenType::Mul => IROp::Mul,
-            TokenType::Div => IROp::Div,
-            TokenType::LeftAngleBracket | TokenType::RightAngleBracket => IROp::LT,
-            e => panic!("cannot convert: {:?}", e),
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct IR {
     pub op: IROp,
@@ -129,7 +143,7 @@ pub struct IR {
 }
 
 impl IR {
-    fn new(op: IROp, lhs: Option<usize>, rhs: Option<usize>) -> Self {
+    pub fn new(op: IROp, lhs: Option<usize>, rhs: Option<usize>) -> Self {
         Self { op, lhs, rhs }
     }
 }
@@ -146,14 +160,74 @@ fn jmp(x: Option<usize>) {
     add(IROp::Jmp, x, None);
 }
 
+// Returns the IR label for a C label, allocating one on first use so
+// that forward gotos work.
+fn named_label(name: &str) -> usize {
+    if let Some(l) = NAMED_LABELS.lock().unwrap().get(name) {
+        return *l;
+    }
+    let l = *NLABEL.lock().unwrap();
+    *NLABEL.lock().unwrap() += 1;
+    NAMED_LABELS.lock().unwrap().insert(name.into(), l);
+    l
+}
+
 fn load(ty: &Type, dst: Option<usize>, src: Option<usize>) {
-    add(IROp::Load(ty.size as u8), dst, src);
+    if ty.is_unsigned {
+        add(IROp::ULoad(ty.size as u8), dst, src);
+    } else {
+        add(IROp::Load(ty.size as u8), dst, src);
+    }
 }
 
 fn store(ty: &Type, dst: Option<usize>, src: Option<usize>) {
+    if is_struct(ty) {
+        return copy_struct(ty, dst, src);
+    }
     add(IROp::Store(ty.size as u8), dst, src);
 }
 
+// A struct value is held in a register as its address, like an array,
+// so assigning one copies the memory it points to.
+fn is_struct(ty: &Type) -> bool {
+    matches!(ty.ty, Ctype::Struct(..))
+}
+
+// Copies a struct from the address in src to the address in dst, in the
+// widest words its size and alignment allow, in a loop.
+fn copy_struct(ty: &Type, dst: Option<usize>, src: Option<usize>) {
+    let unit = [8, 4, 2, 1]
+        .into_iter()
+        .find(|n| ty.size.is_multiple_of(*n) && ty.align.is_multiple_of(*n))
+        .unwrap();
+    let words = ty.size / unit;
+    if words == 0 {
+        return;
+    }
+
+    let x = Some(*NLABEL.lock().unwrap());
+    *NLABEL.lock().unwrap() += 1;
+    let mut regs = vec![];
+    for _ in 0..4 {
+        regs.push(Some(*NUM_REGS.lock().unwrap()));
+        *NUM_REGS.lock().unwrap() += 1;
+    }
+    let (to, from, n, val) = (regs[0], regs[1], regs[2], regs[3]);
+    add(IROp::Mov, to, dst);
+    add(IROp::Mov, from, src);
+    add(IROp::Imm, n, Some(words));
+    label(x);
+    add(IROp::Load(unit as u8), val, from);
+    add(IROp::Store(unit as u8), to, val);
+    add(IROp::AddImm, to, Some(unit));
+    add(IROp::AddImm, from, Some(unit));
+    add(IROp::SubImm, n, Some(1));
+    add(IROp::If, n, x);
+    for r in regs {
+        kill(r);
+    }
+}
+
 fn store_arg(ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
     add(IROp::StoreArg(ty.size as u8), bpoff, argreg);
 }
@@ -177,6 +251,7 @@ fn store_arg(ty: &Type, bpoff: Option<usize>, argreg: Option<usize>) {
 //
 // > This function evaluates a given node as an lvalue.
 
+#[allow(clippy::boxed_local)]
 fn gen_lval(node: Box<Node>) -> Option<usize> {
     match node.op {
         NodeType::Deref(expr) => gen_expr(expr),
@@ -191,7 +266,7 @@ fn gen_lval(node: Box<Node>) -> Option<usize> {
             add(IROp::Bprel, r, Some(offset));
             r
         }
-        NodeType::Gvar(name, _, _) => {
+        NodeType::Gvar(name, _) => {
             let r = Some(*NUM_REGS.lock().unwrap());
             *NUM_REGS.lock().unwrap() += 1;
             add(IROp::LabelAddr(name), r, None);
@@ -238,7 +313,58 @@ fn gen_post_inc(ty: &Type, expr: Box<Node>, num: i32) -> i32 {
         Some(val as usize),
         Some(num as usize * get_inc_scale(ty)),
     );
-    val as i32
+    val
+}
+
+// Registers hold integers sign- or zero-extended to 64 bits according to
+// their C type. This converts the value in r from one type to another
+// so that it stays that way. Widening needs no code unless it turns a
+// signed value into an unsigned one.
+fn gen_conv(from: &Type, to: &Type, r: Option<usize>) {
+    if !to.is_integer() || to.size >= 8 {
+        return;
+    }
+    if from.is_integer() && from.size < to.size && (from.is_unsigned || !to.is_unsigned) {
+        return;
+    }
+    if to.is_unsigned {
+        add(IROp::UExt(to.size as u8), r, None);
+    } else {
+        add(IROp::Ext(to.size as u8), r, None);
+    }
+}
+
+// Results of these operations may not fit in a narrow unsigned type,
+// and wrap around.
+fn wrap_unsigned(op: &IROp, ty: &Type, r: Option<usize>) {
+    if matches!(
+        op,
+        IROp::Add | IROp::Sub | IROp::Mul | IROp::SHL | IROp::Neg | IROp::Not
+    ) && ty.is_unsigned
+        && ty.size < 8
+    {
+        add(IROp::UExt(ty.size as u8), r, None);
+    }
+}
+
+// Pointers compare like unsigned integers.
+fn is_unsigned(ty: &Type) -> bool {
+    ty.is_unsigned || matches!(ty.ty, Ctype::Ptr(_))
+}
+
+// Replaces an operation whose result depends on signedness with its
+// unsigned counterpart.
+fn to_unsigned(op: IROp) -> IROp {
+    match op {
+        IROp::Div => IROp::UDiv,
+        IROp::Mod => IROp::UMod,
+        IROp::SHR => IROp::USHR,
+        IROp::LT => IROp::ULT,
+        IROp::LE => IROp::ULE,
+        IROp::GT => IROp::UGT,
+        IROp::GE => IROp::UGE,
+        op => op,
+    }
 }
 
 fn to_assign_op(op: &TokenType) -> IROp {
@@ -258,20 +384,42 @@ fn to_assign_op(op: &TokenType) -> IROp {
     }
 }
 
+// The value of the left operand is converted to the type sema gave the
+// right operand, except for shifts, which only promote it. The result
+// is converted back before it is stored.
 fn gen_assign_op(op: &TokenType, ty: &Type, lhs: Box<Node>, rhs: Box<Node>) -> Option<usize> {
+    let is_shift = matches!(op, TokenType::ShlEQ | TokenType::ShrEQ);
+    let op_ty = if matches!(ty.ty, Ctype::Ptr(_)) || !rhs.ty.is_integer() {
+        ty.clone()
+    } else if !is_shift {
+        (*rhs.ty).clone()
+    } else if ty.size < 4 {
+        Type::int_ty()
+    } else {
+        ty.clone()
+    };
+    let mut ir_op = to_assign_op(op);
+    if is_unsigned(&op_ty) {
+        ir_op = to_unsigned(ir_op);
+    }
+
     let src = gen_expr(rhs);
     let dst = gen_lval(lhs);
     let val = Some(*NUM_REGS.lock().unwrap());
     *NUM_REGS.lock().unwrap() += 1;
 
     load(ty, val, dst);
-    add(to_assign_op(op), val, src);
+    gen_conv(ty, &op_ty, val);
+    add(ir_op.clone(), val, src);
     kill(src);
+    wrap_unsigned(&ir_op, &op_ty, val);
+    gen_conv(&op_ty, ty, val);
     store(ty, dst, val);
     kill(dst);
     val
 }
 
+#[allow(clippy::boxed_local)]
 fn gen_expr(node: Box<Node>) -> Option<usize> {
     let node = *node;
     match node.op {
@@ -281,31 +429,58 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
             add(IROp::Imm, r, Some(val as usize));
             r
         }
-        NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _, _) => {
+        NodeType::Lvar(_) | NodeType::Dot(_, _, _) | NodeType::Gvar(_, _) => {
             let r = gen_lval(Box::new(node.clone()));
-            load(&node.ty, r, r);
+            if !is_struct(&node.ty) {
+                load(&node.ty, r, r);
+            }
             r
         }
         NodeType::Call(name, args) => {
-            let mut args_ir: [usize; 6] = [0; 6];
-            for i in 0..args.len() {
-                args_ir[i] = gen_expr(Box::new(args[i].clone())).unwrap();
+            // Only so many values can be live in registers at once, so
+            // arguments beyond the sixth are evaluated into stack slots
+            // of their own. Backends pass them on from there.
+            let mut args_ir = vec![];
+            let mut slots = vec![];
+            for arg in args {
+                let r = gen_expr(Box::new(arg));
+                if args_ir.len() < MAX_REG_ARGS {
+                    args_ir.push(r.unwrap());
+                    continue;
+                }
+                let off = {
+                    let mut stacksize = STACKSIZE.lock().unwrap();
+                    *stacksize = roundup(*stacksize, 8) + 8;
+                    *stacksize
+                };
+                let addr = Some(*NUM_REGS.lock().unwrap());
+                *NUM_REGS.lock().unwrap() += 1;
+                add(IROp::Bprel, addr, Some(off));
+                add(IROp::Store(8), addr, r);
+                kill(addr);
+                kill(r);
+                slots.push(off);
             }
 
             let r = Some(*NUM_REGS.lock().unwrap());
             *NUM_REGS.lock().unwrap() += 1;
 
-            add(IROp::Call(name, args.len(), args_ir), r, None);
+            add(IROp::Call(name, args_ir.clone(), slots), r, None);
+            // Callers cannot rely on the upper bits of a narrow return
+            // value, so extend it as if it was converted from long.
+            gen_conv(&Type::long_ty(), &node.ty, r);
 
-            for arg in args_ir.iter().take(args.len()) {
-                kill(Some(*arg));
+            for arg in args_ir {
+                kill(Some(arg));
             }
             r
         }
         NodeType::Addr(expr) => gen_lval(expr),
         NodeType::Deref(expr) => {
             let r = gen_expr(expr);
-            load(&node.ty, r, r);
+            if !is_struct(&node.ty) {
+                load(&node.ty, r, r);
+            }
             r
         }
         NodeType::StmtExpr(body) => {
@@ -326,6 +501,15 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
         }
         NodeType::BinOp(op, lhs, rhs) => {
             use self::TokenType::*;
+            // Shifts take their type from the left operand alone.
+            let unsigned = is_unsigned(&lhs.ty) || (op != SHR && is_unsigned(&rhs.ty));
+            let ty = node.ty.clone();
+            let gen_binop = |ir_op: IROp, lhs, rhs| {
+                let ir_op = if unsigned { to_unsigned(ir_op) } else { ir_op };
+                let r = gen_binop(ir_op.clone(), lhs, rhs);
+                wrap_unsigned(&ir_op, &ty, r);
+                r
+            };
             match op {
                 Equal => {
                     let rhs = gen_expr(rhs);
@@ -374,7 +558,6 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                 | BitorEQ => gen_assign_op(&op, &node.ty, lhs, rhs),
                 EQ => gen_binop(IROp::EQ, lhs, rhs),
                 NE => gen_binop(IROp::NE, lhs, rhs),
-                LE => gen_binop(IROp::LE, lhs, rhs),
                 And => gen_binop(IROp::AND, lhs, rhs),
                 VerticalBar => gen_binop(IROp::OR, lhs, rhs),
                 Hat => gen_binop(IROp::XOR, lhs, rhs),
@@ -385,12 +568,42 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
                     kill(gen_expr(lhs));
                     gen_expr(rhs)
                 }
-                _ => gen_binop(IROp::from(op), lhs, rhs),
+                Mul => gen_binop(IROp::Mul, lhs, rhs),
+                Div => gen_binop(IROp::Div, lhs, rhs),
+                LeftAngleBracket => gen_binop(IROp::LT, lhs, rhs),
+                RightAngleBracket => gen_binop(IROp::GT, lhs, rhs),
+                LE => gen_binop(IROp::LE, lhs, rhs),
+                GE => gen_binop(IROp::GE, lhs, rhs),
+                _ => {
+                    let msg = format!("unknown binary operator: {:?}", op);
+                    error(
+                        &Node {
+                            op: NodeType::Null,
+                            ..node
+                        },
+                        &msg,
+                    );
+                    None
+                }
             }
         }
         NodeType::Neg(expr) => {
             let r = gen_expr(expr);
             add(IROp::Neg, r, None);
+            wrap_unsigned(&IROp::Neg, &node.ty, r);
+            r
+        }
+        NodeType::UnaryPlus(expr) => gen_expr(expr),
+        NodeType::BitNot(expr) => {
+            let r = gen_expr(expr);
+            add(IROp::Not, r, None);
+            wrap_unsigned(&IROp::Not, &node.ty, r);
+            r
+        }
+        NodeType::Cast(expr) => {
+            let from = expr.ty.clone();
+            let r = gen_expr(expr);
+            gen_conv(&from, &node.ty, r);
             r
         }
         NodeType::PostInc(expr) => Some(gen_post_inc(&node.ty, expr, 1) as usize),
@@ -432,7 +645,7 @@ fn gen_expr(node: Box<Node>) -> Option<usize> {
 
 fn gen_stmt(node: Node) {
     match node.op {
-        NodeType::Null => return,
+        NodeType::Null => (),
         NodeType::Vardef(_, init_may, Scope::Local(offset)) => {
             if let Some(init) = init_may {
                 let rhs = gen_expr(init);
@@ -443,7 +656,27 @@ fn gen_stmt(node: Node) {
                 kill(lhs);
                 kill(rhs);
             }
-            return;
+        }
+        NodeType::ZeroFill(offset, words) => {
+            let x = Some(*NLABEL.lock().unwrap());
+            *NLABEL.lock().unwrap() += 1;
+            let mut regs = vec![];
+            for _ in 0..3 {
+                regs.push(Some(*NUM_REGS.lock().unwrap()));
+                *NUM_REGS.lock().unwrap() += 1;
+            }
+            let (addr, n, zero) = (regs[0], regs[1], regs[2]);
+            add(IROp::Bprel, addr, Some(offset));
+            add(IROp::Imm, n, Some(words));
+            add(IROp::Imm, zero, Some(0));
+            label(x);
+            add(IROp::Store(8), addr, zero);
+            add(IROp::AddImm, addr, Some(8));
+            add(IROp::SubImm, n, Some(1));
+            add(IROp::If, n, x);
+            kill(addr);
+            kill(n);
+            kill(zero);
         }
         NodeType::If(cond, then, els_may) => {
             if let Some(els) = els_may {
@@ -478,6 +711,9 @@ fn gen_stmt(node: Node) {
             let orig = *BREAK_LABEL.lock().unwrap();
             *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
             *NLABEL.lock().unwrap() += 1;
+            let orig_continue = *CONTINUE_LABEL.lock().unwrap();
+            *CONTINUE_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
 
             gen_stmt(*init);
             label(x);
@@ -487,6 +723,7 @@ fn gen_stmt(node: Node) {
                 kill(r2);
             }
             gen_stmt(*body);
+            label(Some(*CONTINUE_LABEL.lock().unwrap()));
             if !inc.is_null() {
                 gen_stmt(*inc);
             }
@@ -494,6 +731,7 @@ fn gen_stmt(node: Node) {
             label(y);
             label(Some(*BREAK_LABEL.lock().unwrap()));
             *BREAK_LABEL.lock().unwrap() = orig;
+            *CONTINUE_LABEL.lock().unwrap() = orig_continue;
         }
         NodeType::DoWhile(body, cond) => {
             let x = Some(*NLABEL.lock().unwrap());
@@ -501,21 +739,91 @@ fn gen_stmt(node: Node) {
             let orig = *BREAK_LABEL.lock().unwrap();
             *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
             *NLABEL.lock().unwrap() += 1;
+            let orig_continue = *CONTINUE_LABEL.lock().unwrap();
+            *CONTINUE_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
             label(x);
             gen_stmt(*body);
+            label(Some(*CONTINUE_LABEL.lock().unwrap()));
             let r = gen_expr(cond);
             add(IROp::If, r, x);
             kill(r);
             label(Some(*BREAK_LABEL.lock().unwrap()));
             *BREAK_LABEL.lock().unwrap() = orig;
+            *CONTINUE_LABEL.lock().unwrap() = orig_continue;
+        }
+        NodeType::Switch(cond, body, cases, has_default) => {
+            let orig_break = *BREAK_LABEL.lock().unwrap();
+            *BREAK_LABEL.lock().unwrap() = *NLABEL.lock().unwrap();
+            *NLABEL.lock().unwrap() += 1;
+
+            let mut case_labels = vec![];
+            for _ in &cases {
+                case_labels.push(*NLABEL.lock().unwrap());
+                *NLABEL.lock().unwrap() += 1;
+            }
+            let default_label = if has_default {
+                *NLABEL.lock().unwrap() += 1;
+                *NLABEL.lock().unwrap() - 1
+            } else {
+                *BREAK_LABEL.lock().unwrap()
+            };
+
+            // Compare the value against each case in turn.
+            let r = gen_expr(cond);
+            for (val, l) in cases.iter().zip(&case_labels) {
+                let t = Some(*NUM_REGS.lock().unwrap());
+                *NUM_REGS.lock().unwrap() += 1;
+                let c = Some(*NUM_REGS.lock().unwrap());
+                *NUM_REGS.lock().unwrap() += 1;
+                add(IROp::Mov, t, r);
+                add(IROp::Imm, c, Some(*val as usize));
+                add(IROp::EQ, t, c);
+                kill(c);
+                add(IROp::If, t, Some(*l));
+                kill(t);
+            }
+            kill(r);
+            jmp(Some(default_label));
+
+            let orig_cases = mem::replace(&mut *CASE_LABELS.lock().unwrap(), case_labels);
+            let orig_default = mem::replace(&mut *DEFAULT_LABEL.lock().unwrap(), default_label);
+            gen_stmt(*body);
+            *CASE_LABELS.lock().unwrap() = orig_cases;
+            *DEFAULT_LABEL.lock().unwrap() = orig_default;
+
+            label(Some(*BREAK_LABEL.lock().unwrap()));
+            *BREAK_LABEL.lock().unwrap() = orig_break;
+        }
+        NodeType::Case(_, body, idx) => {
+            let l = CASE_LABELS.lock().unwrap()[idx];
+            label(Some(l));
+            gen_stmt(*body);
+        }
+        NodeType::Default(body) => {
+            let l = *DEFAULT_LABEL.lock().unwrap();
+            label(Some(l));
+            gen_stmt(*body);
         }
         NodeType::Break => {
             let break_label = *BREAK_LABEL.lock().unwrap();
             if break_label == 0 {
-                panic!("stray 'break' statement");
+                return error(&node, "stray 'break' statement");
             }
             jmp(Some(break_label));
         }
+        NodeType::Continue => {
+            let continue_label = *CONTINUE_LABEL.lock().unwrap();
+            if continue_label == 0 {
+                return error(&node, "stray 'continue' statement");
+            }
+            jmp(Some(continue_label));
+        }
+        NodeType::Goto(name) => jmp(Some(named_label(&name))),
+        NodeType::Label(name, body) => {
+            label(Some(named_label(&name)));
+            gen_stmt(*body);
+        }
         NodeType::Return(expr) => {
             let r = gen_expr(expr);
 
@@ -539,16 +847,22 @@ fn gen_stmt(node: Node) {
                 gen_stmt(n);
             }
         }
-        e => panic!("unknown node: {:?}", e),
+        _ => error(&node, "unknown statement"),
     }
 }
 
-pub fn gen_ir(nodes: Vec<Node>) -> Vec<Function> {
+pub fn gen_ir(nodes: Vec<Node>) -> Result<Vec<Function>, Diagnostic> {
+    *ERROR.lock().unwrap() = None;
+    *BREAK_LABEL.lock().unwrap() = 0;
+    *CONTINUE_LABEL.lock().unwrap() = 0;
+
     let mut v = vec![];
     for node in nodes {
         match node.op {
             NodeType::Func(name, args, body, stacksize) => {
                 *CODE.lock().unwrap() = vec![];
+                *STACKSIZE.lock().unwrap() = stacksize;
+                NAMED_LABELS.lock().unwrap().clear();
                 // *NUM_REGS.lock().unwrap() = 0;
 
                 for (i, arg) in args.iter().enumerate() {
@@ -560,11 +874,16 @@ pub fn gen_ir(nodes: Vec<Node>) -> Vec<Function> {
                 }
                 gen_stmt(*body);
 
+                let stacksize = *STACKSIZE.lock().unwrap();
                 v.push(Function::new(name, CODE.lock().unwrap().clone(), stacksize));
             }
             NodeType::Vardef(_, _, _) => (),
-            _ => panic!("parse error."),
+            _ => error(&node, "function or variable definition expected"),
         }
     }
-    v
+
+    match ERROR.lock().unwrap().take() {
+        Some(err) => Err(err),
+        None => Ok(v),
+    }
 }
@@ -0,0 +1,267 @@
+use crate::gen_ir::{Function, IROp, IR};
+use crate::util::{gen_data, roundup};
+use crate::Var;
+
+use std::sync::Mutex;
+
+// This pass generates RV64GC assembly from IR, following the LP64
+// calling convention.
+//
+// Allocatable registers are the callee-saved s1-s11, so values survive
+// calls without any extra spilling. t0 and t1 are scratch registers for
+// immediates that do not fit in an instruction.
+//
+// The frame looks like this, with s0 as the frame pointer that `Bprel`
+// offsets are relative to:
+//
+//   s0+8      saved ra
+//   s0+0      saved s0
+//   s0-1 ...  local variables (Function::stacksize bytes)
+//   ...       saved s1-s11
+//   sp
+
+pub const REGS_N: usize = 11;
+
+const REGS: [&str; REGS_N] = [
+    "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11",
+];
+
+const ARGREGS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+lazy_static! {
+    static ref LABEL: Mutex<usize> = Mutex::new(0);
+}
+
+macro_rules! emit{
+    ($fmt:expr) => (print!(concat!("\t", $fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!("\t", $fmt, "\n"), $($arg)*));
+}
+
+fn is_imm12(val: i64) -> bool {
+    (-2048..2048).contains(&val)
+}
+
+fn load_insn(size: u8) -> &'static str {
+    match size {
+        1 => "lb",
+        2 => "lh",
+        4 => "lw",
+        8 => "ld",
+        _ => panic!("unknown load size: {}", size),
+    }
+}
+
+fn uload_insn(size: u8) -> &'static str {
+    match size {
+        1 => "lbu",
+        2 => "lhu",
+        4 => "lwu",
+        8 => "ld",
+        _ => panic!("unknown load size: {}", size),
+    }
+}
+
+fn store_insn(size: u8) -> &'static str {
+    match size {
+        1 => "sb",
+        2 => "sh",
+        4 => "sw",
+        8 => "sd",
+        _ => panic!("unknown store size: {}", size),
+    }
+}
+
+// dst = src + imm
+fn emit_addi(dst: &str, src: &str, imm: i64) {
+    if is_imm12(imm) {
+        emit!("addi {}, {}, {}", dst, src, imm);
+    } else {
+        emit!("li t0, {}", imm);
+        emit!("add {}, {}, t0", dst, src);
+    }
+}
+
+// Loads or stores `reg` at s0-offset.
+fn emit_fp_access(insn: &str, reg: &str, offset: usize) {
+    let off = -(offset as i64);
+    if is_imm12(off) {
+        emit!("{} {}, {}(s0)", insn, reg, off);
+    } else {
+        emit!("li t0, {}", off);
+        emit!("add t0, s0, t0");
+        emit!("{} {}, 0(t0)", insn, reg);
+    }
+}
+
+fn emit_cmp(ir: &IR) {
+    let lhs = REGS[ir.lhs.unwrap()];
+    let rhs = REGS[ir.rhs.unwrap()];
+    match ir.op {
+        IROp::EQ => {
+            emit!("sub {}, {}, {}", lhs, lhs, rhs);
+            emit!("seqz {}, {}", lhs, lhs);
+        }
+        IROp::NE => {
+            emit!("sub {}, {}, {}", lhs, lhs, rhs);
+            emit!("snez {}, {}", lhs, lhs);
+        }
+        IROp::LT => emit!("slt {}, {}, {}", lhs, lhs, rhs),
+        IROp::LE => {
+            emit!("slt {}, {}, {}", lhs, rhs, lhs);
+            emit!("xori {}, {}, 1", lhs, lhs);
+        }
+        IROp::GT => emit!("slt {}, {}, {}", lhs, rhs, lhs),
+        IROp::GE => {
+            emit!("slt {}, {}, {}", lhs, lhs, rhs);
+            emit!("xori {}, {}, 1", lhs, lhs);
+        }
+        IROp::ULT => emit!("sltu {}, {}, {}", lhs, lhs, rhs),
+        IROp::ULE => {
+            emit!("sltu {}, {}, {}", lhs, rhs, lhs);
+            emit!("xori {}, {}, 1", lhs, lhs);
+        }
+        IROp::UGT => emit!("sltu {}, {}, {}", lhs, rhs, lhs),
+        IROp::UGE => {
+            emit!("sltu {}, {}, {}", lhs, lhs, rhs);
+            emit!("xori {}, {}, 1", lhs, lhs);
+        }
+        _ => unreachable!(),
+    }
+}
+
+// `args` are in registers and the rest of the arguments in the stack
+// slots `slots`. Those fill up the remaining argument registers, and
+// arguments beyond the eighth are passed in an area at the bottom of
+// the caller's frame. sp stays 16-byte aligned.
+fn emit_call(name: &str, args: &[usize], slots: &[usize], lhs: usize) {
+    for (arg, argreg) in args.iter().zip(ARGREGS.iter()) {
+        emit!("mv {}, {}", argreg, REGS[*arg]);
+    }
+
+    let (reg_slots, stack_slots) = slots.split_at(slots.len().min(ARGREGS.len() - args.len()));
+    for (off, argreg) in reg_slots.iter().zip(ARGREGS[args.len()..].iter()) {
+        emit_fp_access("ld", argreg, *off);
+    }
+
+    let size = roundup(stack_slots.len() * 8, 16);
+    if size != 0 {
+        emit_addi("sp", "sp", -(size as i64));
+    }
+    for (i, off) in stack_slots.iter().enumerate() {
+        emit_fp_access("ld", "t1", *off);
+        emit!("sd t1, {}(sp)", i * 8);
+    }
+
+    emit!("call {}", name);
+    if size != 0 {
+        emit_addi("sp", "sp", size as i64);
+    }
+    emit!("mv {}, a0", REGS[lhs]);
+}
+
+fn gen(f: Function) {
+    use self::IROp::*;
+    let ret = format!(".Lend{}", *LABEL.lock().unwrap());
+    *LABEL.lock().unwrap() += 1;
+
+    let locals = roundup(f.stacksize, 16);
+    let saved = roundup(REGS_N * 8, 16);
+
+    println!(".text");
+    println!(".global {}", f.name);
+    println!("{}:", f.name);
+    emit!("addi sp, sp, -16");
+    emit!("sd ra, 8(sp)");
+    emit!("sd s0, 0(sp)");
+    emit!("mv s0, sp");
+    emit_addi("sp", "sp", -((locals + saved) as i64));
+    for (i, r) in REGS.iter().enumerate() {
+        emit_fp_access("sd", r, locals + (i + 1) * 8);
+    }
+
+    for ir in &f.ir {
+        let lhs = ir.lhs.unwrap_or(0);
+        let rhs = ir.rhs.unwrap_or(0);
+        match ir.op {
+            Imm => emit!("li {}, {}", REGS[lhs], rhs as i64),
+            Bprel => emit_addi(REGS[lhs], "s0", -(rhs as i64)),
+            Mov => emit!("mv {}, {}", REGS[lhs], REGS[rhs]),
+            Return => {
+                emit!("mv a0, {}", REGS[lhs]);
+                emit!("j {}", ret);
+            }
+            Call(ref name, ref args, ref slots) => emit_call(name, args, slots, lhs),
+            Label => println!(".L{}:", lhs),
+            LabelAddr(ref name) => emit!("lla {}, {}", REGS[lhs], name),
+            Neg => emit!("neg {}, {}", REGS[lhs], REGS[lhs]),
+            Not => emit!("not {}, {}", REGS[lhs], REGS[lhs]),
+            Ext(size) => {
+                emit!("slli {}, {}, {}", REGS[lhs], REGS[lhs], 64 - size * 8);
+                emit!("srai {}, {}, {}", REGS[lhs], REGS[lhs], 64 - size * 8);
+            }
+            UExt(size) => {
+                emit!("slli {}, {}, {}", REGS[lhs], REGS[lhs], 64 - size * 8);
+                emit!("srli {}, {}, {}", REGS[lhs], REGS[lhs], 64 - size * 8);
+            }
+            EQ | NE | LT | LE | GT | GE | ULT | ULE | UGT | UGE => emit_cmp(ir),
+            AND => emit!("and {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            OR => emit!("or {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            XOR => emit!("xor {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SHL => emit!("sll {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SHR => emit!("sra {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            USHR => emit!("srl {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Jmp => emit!("j .L{}", lhs),
+            If => emit!("bnez {}, .L{}", REGS[lhs], rhs),
+            Unless => emit!("beqz {}, .L{}", REGS[lhs], rhs),
+            // lb/lh/lw sign-extend, matching the x86-64 backend.
+            Load(size) => emit!("{} {}, 0({})", load_insn(size), REGS[lhs], REGS[rhs]),
+            ULoad(size) => emit!("{} {}, 0({})", uload_insn(size), REGS[lhs], REGS[rhs]),
+            Store(size) => emit!("{} {}, 0({})", store_insn(size), REGS[rhs], REGS[lhs]),
+            StoreArg(size) if rhs < ARGREGS.len() => {
+                emit_fp_access(store_insn(size), ARGREGS[rhs], lhs)
+            }
+            // The rest were stored by the caller at the bottom of its
+            // frame, which starts right above our saved ra and s0.
+            StoreArg(size) => {
+                emit!("ld t1, {}(s0)", 16 + (rhs - ARGREGS.len()) * 8);
+                emit_fp_access(store_insn(size), "t1", lhs);
+            }
+            Add => emit!("add {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            AddImm => emit_addi(REGS[lhs], REGS[lhs], rhs as i32 as i64),
+            Sub => emit!("sub {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            SubImm => emit_addi(REGS[lhs], REGS[lhs], -(rhs as i32 as i64)),
+            Mul => emit!("mul {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            MulImm => {
+                emit!("li t0, {}", rhs as i32);
+                emit!("mul {}, {}, t0", REGS[lhs], REGS[lhs]);
+            }
+            Div => emit!("div {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Mod => emit!("rem {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            UDiv => emit!("divu {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            UMod => emit!("remu {}, {}, {}", REGS[lhs], REGS[lhs], REGS[rhs]),
+            Nop | Kill => (),
+        }
+    }
+
+    println!("{}:", ret);
+    for (i, r) in REGS.iter().enumerate() {
+        emit_fp_access("ld", r, locals + (i + 1) * 8);
+    }
+    emit!("mv sp, s0");
+    emit!("ld ra, 8(sp)");
+    emit!("ld s0, 0(sp)");
+    emit!("addi sp, sp, 16");
+    emit!("ret");
+}
+
+pub fn gen_riscv(globals: Vec<Var>, fns: Vec<Function>) {
+    for var in &globals {
+        gen_data(var);
+    }
+
+    for f in fns {
+        gen(f);
+    }
+
+    println!(".section .note.GNU-stack,\"\",@progbits");
+}
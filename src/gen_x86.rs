@@ -0,0 +1,261 @@
+use crate::gen_ir::{Function, IROp, IR};
+use crate::util::{gen_data, roundup};
+use crate::Var;
+
+use std::sync::Mutex;
+
+// Quoted from 9cc
+// > This pass generates x86-64 assembly from IR.
+
+pub const REGS_N: usize = 7;
+
+const REGS: [&str; REGS_N] = ["%r10", "%r11", "%rbx", "%r12", "%r13", "%r14", "%r15"];
+const REGS8: [&str; REGS_N] = ["%r10b", "%r11b", "%bl", "%r12b", "%r13b", "%r14b", "%r15b"];
+const REGS16: [&str; REGS_N] = ["%r10w", "%r11w", "%bx", "%r12w", "%r13w", "%r14w", "%r15w"];
+const REGS32: [&str; REGS_N] = ["%r10d", "%r11d", "%ebx", "%r12d", "%r13d", "%r14d", "%r15d"];
+
+const ARGREGS: [&str; 6] = ["%rdi", "%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+const ARGREGS8: [&str; 6] = ["%dil", "%sil", "%dl", "%cl", "%r8b", "%r9b"];
+const ARGREGS16: [&str; 6] = ["%di", "%si", "%dx", "%cx", "%r8w", "%r9w"];
+const ARGREGS32: [&str; 6] = ["%edi", "%esi", "%edx", "%ecx", "%r8d", "%r9d"];
+
+// Callee-saved registers we hand out to the register allocator.
+const SAVED_REGS: [&str; 5] = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+
+lazy_static! {
+    static ref LABEL: Mutex<usize> = Mutex::new(0);
+}
+
+macro_rules! emit{
+    ($fmt:expr) => (print!(concat!("\t", $fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!("\t", $fmt, "\n"), $($arg)*));
+}
+
+fn reg(r: usize, size: u8) -> &'static str {
+    match size {
+        1 => REGS8[r],
+        2 => REGS16[r],
+        4 => REGS32[r],
+        8 => REGS[r],
+        _ => panic!("unknown register size: {}", size),
+    }
+}
+
+fn argreg(r: usize, size: u8) -> &'static str {
+    match size {
+        1 => ARGREGS8[r],
+        2 => ARGREGS16[r],
+        4 => ARGREGS32[r],
+        8 => ARGREGS[r],
+        _ => panic!("unknown register size: {}", size),
+    }
+}
+
+fn emit_cmp(ir: &IR, insn: &'static str) {
+    let lhs = ir.lhs.unwrap();
+    let rhs = ir.rhs.unwrap();
+    emit!("cmp {}, {}", REGS[rhs], REGS[lhs]);
+    emit!("{} {}", insn, REGS8[lhs]);
+    emit!("movzbq {}, {}", REGS8[lhs], REGS[lhs]);
+}
+
+fn rax(size: u8) -> &'static str {
+    match size {
+        1 => "%al",
+        2 => "%ax",
+        4 => "%eax",
+        8 => "%rax",
+        _ => panic!("unknown register size: {}", size),
+    }
+}
+
+// `args` go in registers. The arguments in the stack slots `slots` are
+// pushed right to left, after padding if needed so that rsp is 16-byte
+// aligned at the call, as the System V ABI requires. rsp is aligned
+// when a call sequence starts because the prologue sets it up that way.
+fn emit_call(name: &str, args: &[usize], slots: &[usize], lhs: usize) {
+    for (arg, argreg) in args.iter().zip(ARGREGS.iter()) {
+        emit!("mov {}, {}", REGS[*arg], argreg);
+    }
+    emit!("push %r10");
+    emit!("push %r11");
+
+    let pad = if slots.len() % 2 == 1 { 8 } else { 0 };
+    if pad != 0 {
+        emit!("sub ${}, %rsp", pad);
+    }
+    for off in slots.iter().rev() {
+        emit!("push -{}(%rbp)", off);
+    }
+
+    emit!("mov $0, %rax");
+    emit!("call {}@PLT", name);
+    if !slots.is_empty() {
+        emit!("add ${}, %rsp", slots.len() * 8 + pad);
+    }
+    emit!("pop %r11");
+    emit!("pop %r10");
+    emit!("mov %rax, {}", REGS[lhs]);
+}
+
+fn gen(f: Function) {
+    use self::IROp::*;
+    let ret = format!(".Lend{}", *LABEL.lock().unwrap());
+    *LABEL.lock().unwrap() += 1;
+
+    println!(".text");
+    println!(".global {}", f.name);
+    println!("{}:", f.name);
+    emit!("push %rbp");
+    emit!("mov %rsp, %rbp");
+    // Five pushes below leave rsp 16-byte aligned at call sites
+    // only if the frame itself is off by 8.
+    emit!("sub ${}, %rsp", roundup(f.stacksize, 16) + 8);
+    for r in SAVED_REGS.iter() {
+        emit!("push {}", r);
+    }
+
+    for ir in &f.ir {
+        let lhs = ir.lhs.unwrap_or(0);
+        let rhs = ir.rhs.unwrap_or(0);
+        match ir.op {
+            Imm => emit!("mov ${}, {}", rhs as i64, REGS[lhs]),
+            Bprel => emit!("lea -{}(%rbp), {}", rhs, REGS[lhs]),
+            Mov => emit!("mov {}, {}", REGS[rhs], REGS[lhs]),
+            Return => {
+                emit!("mov {}, %rax", REGS[lhs]);
+                emit!("jmp {}", ret);
+            }
+            Call(ref name, ref args, ref slots) => emit_call(name, args, slots, lhs),
+            Label => println!(".L{}:", lhs),
+            LabelAddr(ref name) => emit!("lea {}(%rip), {}", name, REGS[lhs]),
+            Neg => emit!("neg {}", REGS[lhs]),
+            Not => emit!("not {}", REGS[lhs]),
+            Ext(size) => match size {
+                1 => emit!("movsbq {}, {}", REGS8[lhs], REGS[lhs]),
+                2 => emit!("movswq {}, {}", REGS16[lhs], REGS[lhs]),
+                _ => emit!("movslq {}, {}", REGS32[lhs], REGS[lhs]),
+            },
+            UExt(size) => match size {
+                1 => emit!("movzbq {}, {}", REGS8[lhs], REGS[lhs]),
+                2 => emit!("movzwq {}, {}", REGS16[lhs], REGS[lhs]),
+                _ => emit!("mov {}, {}", REGS32[lhs], REGS32[lhs]),
+            },
+            EQ => emit_cmp(ir, "sete"),
+            NE => emit_cmp(ir, "setne"),
+            LT => emit_cmp(ir, "setl"),
+            LE => emit_cmp(ir, "setle"),
+            ULT => emit_cmp(ir, "setb"),
+            ULE => emit_cmp(ir, "setbe"),
+            GT => emit_cmp(ir, "setg"),
+            GE => emit_cmp(ir, "setge"),
+            UGT => emit_cmp(ir, "seta"),
+            UGE => emit_cmp(ir, "setae"),
+            AND => emit!("and {}, {}", REGS[rhs], REGS[lhs]),
+            OR => emit!("or {}, {}", REGS[rhs], REGS[lhs]),
+            XOR => emit!("xor {}, {}", REGS[rhs], REGS[lhs]),
+            SHL => {
+                emit!("mov {}, %cl", REGS8[rhs]);
+                emit!("shl %cl, {}", REGS[lhs]);
+            }
+            SHR => {
+                emit!("mov {}, %cl", REGS8[rhs]);
+                emit!("sar %cl, {}", REGS[lhs]);
+            }
+            USHR => {
+                emit!("mov {}, %cl", REGS8[rhs]);
+                emit!("shr %cl, {}", REGS[lhs]);
+            }
+            Jmp => emit!("jmp .L{}", lhs),
+            If => {
+                emit!("cmp $0, {}", REGS[lhs]);
+                emit!("jne .L{}", rhs);
+            }
+            Unless => {
+                emit!("cmp $0, {}", REGS[lhs]);
+                emit!("je .L{}", rhs);
+            }
+            // Loads sign-extend so that 64-bit comparisons and
+            // divisions see the same value as the narrower C type.
+            Load(size) => match size {
+                1 => emit!("movsbq ({}), {}", REGS[rhs], REGS[lhs]),
+                2 => emit!("movswq ({}), {}", REGS[rhs], REGS[lhs]),
+                4 => emit!("movslq ({}), {}", REGS[rhs], REGS[lhs]),
+                _ => emit!("mov ({}), {}", REGS[rhs], reg(lhs, size)),
+            },
+            // Writing a 32-bit register clears the upper half.
+            ULoad(size) => match size {
+                1 => emit!("movzbq ({}), {}", REGS[rhs], REGS[lhs]),
+                2 => emit!("movzwq ({}), {}", REGS[rhs], REGS[lhs]),
+                _ => emit!("mov ({}), {}", REGS[rhs], reg(lhs, size)),
+            },
+            Store(size) => emit!("mov {}, ({})", reg(rhs, size), REGS[lhs]),
+            StoreArg(size) if rhs < ARGREGS.len() => {
+                emit!("mov {}, -{}(%rbp)", argreg(rhs, size), lhs)
+            }
+            // The rest were pushed by the caller, above the return
+            // address and the saved rbp.
+            StoreArg(size) => {
+                emit!("mov {}(%rbp), %rax", 16 + (rhs - ARGREGS.len()) * 8);
+                emit!("mov {}, -{}(%rbp)", rax(size), lhs);
+            }
+            Add => emit!("add {}, {}", REGS[rhs], REGS[lhs]),
+            AddImm => emit!("add ${}, {}", rhs as i32, REGS[lhs]),
+            Sub => emit!("sub {}, {}", REGS[rhs], REGS[lhs]),
+            SubImm => emit!("sub ${}, {}", rhs as i32, REGS[lhs]),
+            Mul => emit!("imul {}, {}", REGS[rhs], REGS[lhs]),
+            MulImm => {
+                if rhs < 256 && rhs.count_ones() == 1 {
+                    emit!("shl ${}, {}", rhs.trailing_zeros(), REGS[lhs]);
+                } else {
+                    emit!("imul ${}, {}, {}", rhs as i32, REGS[lhs], REGS[lhs]);
+                }
+            }
+            Div => {
+                emit!("mov {}, %rax", REGS[lhs]);
+                emit!("cqo");
+                emit!("idiv {}", REGS[rhs]);
+                emit!("mov %rax, {}", REGS[lhs]);
+            }
+            Mod => {
+                emit!("mov {}, %rax", REGS[lhs]);
+                emit!("cqo");
+                emit!("idiv {}", REGS[rhs]);
+                emit!("mov %rdx, {}", REGS[lhs]);
+            }
+            UDiv => {
+                emit!("mov {}, %rax", REGS[lhs]);
+                emit!("xor %edx, %edx");
+                emit!("div {}", REGS[rhs]);
+                emit!("mov %rax, {}", REGS[lhs]);
+            }
+            UMod => {
+                emit!("mov {}, %rax", REGS[lhs]);
+                emit!("xor %edx, %edx");
+                emit!("div {}", REGS[rhs]);
+                emit!("mov %rdx, {}", REGS[lhs]);
+            }
+            Nop | Kill => (),
+        }
+    }
+
+    println!("{}:", ret);
+    for r in SAVED_REGS.iter().rev() {
+        emit!("pop {}", r);
+    }
+    emit!("mov %rbp, %rsp");
+    emit!("pop %rbp");
+    emit!("ret");
+}
+
+pub fn gen_x86(globals: Vec<Var>, fns: Vec<Function>) {
+    for var in &globals {
+        gen_data(var);
+    }
+
+    for f in fns {
+        gen(f);
+    }
+
+    println!(".section .note.GNU-stack,\"\",@progbits");
+}
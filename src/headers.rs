@@ -0,0 +1,27 @@
+// Headers bundled with the compiler.
+//
+// These are freestanding replacements for the C library headers that
+// test programs commonly include. They only use types seu9cc supports,
+// declare library functions without touching the host's headers, and
+// are resolved from memory, so compiling a program gives the same
+// result on every machine.
+
+// Pseudo directory that bundled headers appear to live in, for error
+// messages and __FILE__-like uses.
+pub const DIR: &str = "<built-in>";
+
+const HEADERS: [(&str, &str); 8] = [
+    ("limits.h", include_str!("../include/limits.h")),
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("stddef.h", include_str!("../include/stddef.h")),
+    ("stdint.h", include_str!("../include/stdint.h")),
+    ("stdio.h", include_str!("../include/stdio.h")),
+    ("stdlib.h", include_str!("../include/stdlib.h")),
+    ("string.h", include_str!("../include/string.h")),
+];
+
+// Returns the contents of a bundled header.
+pub fn find(name: &str) -> Option<&'static str> {
+    HEADERS.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+}
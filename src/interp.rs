@@ -0,0 +1,512 @@
+use crate::diagnostic::Diagnostic;
+use crate::gen_ir::{Function, IROp, IR};
+use crate::util::roundup;
+use crate::{Scope, Var};
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+
+// IR interpreter. This executes the virtual-register IR produced by
+// gen_ir directly, so that programs can be run without an assembler.
+//
+// Memory is a flat byte array. Address 0 is never handed out so that
+// null pointers fault. Global variables are laid out from the bottom
+// and the stack grows down from the top, one frame per call sized from
+// `Function::stacksize`. Registers hold 64-bit values and loads
+// sign-extend, just like the x86-64 backend.
+//
+// A program that faults, divides by zero or calls an unknown function
+// stops with an error instead of bringing the host process down. Frames
+// of interpreted calls are kept on a Vec rather than on the host's
+// stack, so deep recursion runs out of the program's stack and is
+// reported like any other fault.
+
+const STACK_SIZE: usize = 1 << 20;
+
+// Unwinds the interpreter: `exit()` with a status, or a runtime error.
+enum Trap {
+    Exit(i32),
+    Error(Diagnostic),
+}
+
+fn fault(msg: &str) -> Trap {
+    Trap::Error(Diagnostic::error(msg))
+}
+
+// A call being run.
+struct Frame<'a> {
+    f: &'a Function,
+    labels: Rc<HashMap<usize, usize>>,
+    args: Vec<i64>,
+    regs: Vec<i64>,
+    bp: usize,
+    sp: usize, // The caller's, restored on return
+    pc: usize,
+}
+
+impl Frame<'_> {
+    fn get(&self, r: Option<usize>) -> i64 {
+        r.and_then(|r| self.regs.get(r).cloned()).unwrap_or(0)
+    }
+
+    fn set(&mut self, r: Option<usize>, val: i64) {
+        let r = r.unwrap();
+        if self.regs.len() <= r {
+            self.regs.resize(r + 1, 0);
+        }
+        self.regs[r] = val;
+    }
+
+    fn jump(&mut self, label: Option<usize>) {
+        let label = label.unwrap();
+        self.pc = *self
+            .labels
+            .get(&label)
+            .unwrap_or_else(|| panic!("undefined label: .L{}", label));
+    }
+}
+
+pub struct Interpreter<'a> {
+    fns: HashMap<String, &'a Function>,
+    labels: HashMap<String, Rc<HashMap<usize, usize>>>, // function -> label -> index
+    globals: HashMap<String, usize>,                    // name -> address
+    mem: Vec<u8>,
+    sp: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(globals: &[Var], fns: &'a [Function]) -> Result<Self, Diagnostic> {
+        let mut interp = Interpreter {
+            fns: HashMap::new(),
+            labels: HashMap::new(),
+            globals: HashMap::new(),
+            mem: vec![0; 16],
+            sp: 0,
+        };
+
+        for var in globals {
+            if let Scope::Global(ref data, len, is_extern) = var.scope {
+                if is_extern {
+                    continue;
+                }
+                let addr = roundup(interp.mem.len(), var.ty.align.max(1));
+                let size = len.max(var.ty.size).max(data.bytes.len());
+                interp.mem.resize(addr + size, 0);
+                interp.mem[addr..addr + data.bytes.len()].copy_from_slice(&data.bytes);
+                interp.globals.insert(var.name.clone(), addr);
+            }
+        }
+
+        // Relocations are applied once every global has an address.
+        for var in globals {
+            if let Scope::Global(ref data, _, false) = var.scope {
+                let addr = interp.globals[&var.name];
+                for reloc in &data.relocs {
+                    let target = *interp.globals.get(&reloc.label).ok_or_else(|| {
+                        Diagnostic::error(&format!("undefined symbol: {}", reloc.label))
+                    })?;
+                    let val = target as i64 + reloc.addend;
+                    let at = &mut interp.mem[addr + reloc.offset..addr + reloc.offset + 8];
+                    at.copy_from_slice(&val.to_le_bytes());
+                }
+            }
+        }
+
+        let base = roundup(interp.mem.len(), 16);
+        interp.mem.resize(base + STACK_SIZE, 0);
+        interp.sp = interp.mem.len();
+
+        for f in fns {
+            let mut labels = HashMap::new();
+            for (i, ir) in f.ir.iter().enumerate() {
+                if ir.op == IROp::Label {
+                    labels.insert(ir.lhs.unwrap(), i);
+                }
+            }
+            interp.labels.insert(f.name.clone(), Rc::new(labels));
+            interp.fns.insert(f.name.clone(), f);
+        }
+        Ok(interp)
+    }
+
+    // Runs `main` and returns its exit status, which is either the
+    // return value of `main` or the argument passed to `exit()`.
+    pub fn run(&mut self, out: &mut dyn Write) -> Result<i32, Diagnostic> {
+        match self.exec(out) {
+            Ok(val) => Ok(val as i32),
+            Err(Trap::Exit(code)) => Ok(code),
+            Err(Trap::Error(e)) => Err(e),
+        }
+    }
+
+    fn check(&self, addr: usize, size: usize) -> Result<(), Trap> {
+        if addr == 0
+            || addr
+                .checked_add(size)
+                .is_none_or(|end| end > self.mem.len())
+        {
+            return Err(fault(&format!("segmentation fault at address {:#x}", addr)));
+        }
+        Ok(())
+    }
+
+    fn load(&self, addr: i64, size: u8) -> Result<i64, Trap> {
+        let addr = addr as usize;
+        self.check(addr, size as usize)?;
+        let m = &self.mem[addr..];
+        Ok(match size {
+            1 => m[0] as i8 as i64,
+            2 => i16::from_le_bytes([m[0], m[1]]) as i64,
+            4 => i32::from_le_bytes([m[0], m[1], m[2], m[3]]) as i64,
+            8 => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(&m[..8]);
+                i64::from_le_bytes(buf)
+            }
+            _ => return Err(fault(&format!("invalid load size: {}", size))),
+        })
+    }
+
+    fn store(&mut self, addr: i64, size: u8, val: i64) -> Result<(), Trap> {
+        let addr = addr as usize;
+        let size = size as usize;
+        if size > 8 {
+            return Err(fault(&format!("invalid store size: {}", size)));
+        }
+        self.check(addr, size)?;
+        self.mem[addr..addr + size].copy_from_slice(&val.to_le_bytes()[..size]);
+        Ok(())
+    }
+
+    fn cstr(&self, addr: i64) -> Result<Vec<u8>, Trap> {
+        let start = addr as usize;
+        self.check(start, 1)?;
+        let len = self.mem[start..]
+            .iter()
+            .position(|c| *c == 0)
+            .ok_or_else(|| fault(&format!("unterminated string at {:#x}", start)))?;
+        Ok(self.mem[start..start + len].to_vec())
+    }
+
+    // Sets up a frame to call f, with its stack frame below the caller's.
+    fn enter(&mut self, f: &'a Function, args: Vec<i64>) -> Result<Frame<'a>, Trap> {
+        let bp = self.sp - 16;
+        let size = roundup(f.stacksize, 16);
+        if bp < size + 16 || self.mem.len() - bp + size > STACK_SIZE {
+            return Err(fault(&format!("stack overflow in {}", f.name)));
+        }
+        let frame = Frame {
+            f,
+            labels: self.labels[&f.name].clone(),
+            args,
+            regs: vec![],
+            bp,
+            sp: self.sp,
+            pc: 0,
+        };
+        self.sp = bp - size;
+        Ok(frame)
+    }
+
+    // Returns from the innermost call with val. Returns the value of
+    // `main` once there are no calls left.
+    fn leave(&mut self, stack: &mut Vec<Frame<'a>>, val: i64) -> Option<i64> {
+        let frame = stack.pop().unwrap();
+        self.sp = frame.sp;
+        match stack.last_mut() {
+            Some(caller) => {
+                let call = &caller.f.ir[caller.pc - 1];
+                caller.set(call.lhs, val);
+                None
+            }
+            None => Some(val),
+        }
+    }
+
+    fn exec(&mut self, out: &mut dyn Write) -> Result<i64, Trap> {
+        use self::IROp::*;
+        let main = match self.fns.get("main") {
+            Some(f) => *f,
+            None => return self.builtin("main", &[], out),
+        };
+        let mut stack = vec![self.enter(main, vec![])?];
+
+        loop {
+            let frame = stack.last_mut().unwrap();
+            let f = frame.f;
+            let ir: &IR = match f.ir.get(frame.pc) {
+                Some(ir) => ir,
+                // Falling off the end of a function returns 0.
+                None => match self.leave(&mut stack, 0) {
+                    Some(val) => return Ok(val),
+                    None => continue,
+                },
+            };
+            frame.pc += 1;
+
+            let lhs = frame.get(ir.lhs);
+            let rhs = frame.get(ir.rhs);
+            let imm = ir.rhs.unwrap_or(0) as i64;
+            match ir.op {
+                Imm => frame.set(ir.lhs, imm),
+                Bprel => frame.set(ir.lhs, frame.bp as i64 - imm),
+                Mov => frame.set(ir.lhs, rhs),
+                Return => {
+                    if let Some(val) = self.leave(&mut stack, lhs) {
+                        return Ok(val);
+                    }
+                }
+                Call(ref name, ref regs, ref slots) => {
+                    let mut vals: Vec<i64> = regs.iter().map(|r| frame.get(Some(*r))).collect();
+                    for off in slots {
+                        vals.push(self.load(frame.bp as i64 - *off as i64, 8)?);
+                    }
+                    match self.fns.get(name) {
+                        Some(callee) => {
+                            let callee = self.enter(callee, vals)?;
+                            stack.push(callee);
+                        }
+                        None => {
+                            let val = self.builtin(name, &vals, out)?;
+                            frame.set(ir.lhs, val);
+                        }
+                    }
+                }
+                Label | Nop | Kill => (),
+                LabelAddr(ref name) => {
+                    let addr = *self
+                        .globals
+                        .get(name)
+                        .ok_or_else(|| fault(&format!("undefined symbol: {}", name)))?;
+                    frame.set(ir.lhs, addr as i64);
+                }
+                Neg => frame.set(ir.lhs, lhs.wrapping_neg()),
+                Not => frame.set(ir.lhs, !lhs),
+                Ext(size) => {
+                    let shift = 64 - size as u32 * 8;
+                    frame.set(ir.lhs, lhs.wrapping_shl(shift).wrapping_shr(shift));
+                }
+                UExt(size) => {
+                    let shift = 64 - size as u32 * 8;
+                    let val = (lhs as u64).wrapping_shl(shift).wrapping_shr(shift);
+                    frame.set(ir.lhs, val as i64);
+                }
+                EQ => frame.set(ir.lhs, (lhs == rhs) as i64),
+                NE => frame.set(ir.lhs, (lhs != rhs) as i64),
+                LT => frame.set(ir.lhs, (lhs < rhs) as i64),
+                LE => frame.set(ir.lhs, (lhs <= rhs) as i64),
+                ULT => frame.set(ir.lhs, ((lhs as u64) < rhs as u64) as i64),
+                ULE => frame.set(ir.lhs, (lhs as u64 <= rhs as u64) as i64),
+                GT => frame.set(ir.lhs, (lhs > rhs) as i64),
+                GE => frame.set(ir.lhs, (lhs >= rhs) as i64),
+                UGT => frame.set(ir.lhs, (lhs as u64 > rhs as u64) as i64),
+                UGE => frame.set(ir.lhs, (lhs as u64 >= rhs as u64) as i64),
+                AND => frame.set(ir.lhs, lhs & rhs),
+                OR => frame.set(ir.lhs, lhs | rhs),
+                XOR => frame.set(ir.lhs, lhs ^ rhs),
+                SHL => frame.set(ir.lhs, lhs.wrapping_shl(rhs as u32)),
+                SHR => frame.set(ir.lhs, lhs.wrapping_shr(rhs as u32)),
+                USHR => frame.set(ir.lhs, (lhs as u64).wrapping_shr(rhs as u32) as i64),
+                Jmp => frame.jump(ir.lhs),
+                If => {
+                    if lhs != 0 {
+                        frame.jump(ir.rhs);
+                    }
+                }
+                Unless => {
+                    if lhs == 0 {
+                        frame.jump(ir.rhs);
+                    }
+                }
+                Load(size) => {
+                    let val = self.load(rhs, size)?;
+                    frame.set(ir.lhs, val);
+                }
+                ULoad(size) => {
+                    let val = self.load(rhs, size)?;
+                    let mask = if size == 8 { !0 } else { (1 << (size * 8)) - 1 };
+                    frame.set(ir.lhs, val & mask);
+                }
+                Store(size) => self.store(lhs, size, rhs)?,
+                StoreArg(size) => {
+                    let val = frame.args.get(ir.rhs.unwrap()).cloned().unwrap_or(0);
+                    self.store(frame.bp as i64 - ir.lhs.unwrap() as i64, size, val)?;
+                }
+                Add => frame.set(ir.lhs, lhs.wrapping_add(rhs)),
+                AddImm => frame.set(ir.lhs, lhs.wrapping_add(imm)),
+                Sub => frame.set(ir.lhs, lhs.wrapping_sub(rhs)),
+                SubImm => frame.set(ir.lhs, lhs.wrapping_sub(imm)),
+                Mul => frame.set(ir.lhs, lhs.wrapping_mul(rhs)),
+                MulImm => frame.set(ir.lhs, lhs.wrapping_mul(imm)),
+                Div | Mod | UDiv | UMod => {
+                    if rhs == 0 {
+                        return Err(fault(&format!("division by zero in {}", f.name)));
+                    }
+                    let (ul, ur) = (lhs as u64, rhs as u64);
+                    let val = match ir.op {
+                        Div => lhs.wrapping_div(rhs),
+                        Mod => lhs.wrapping_rem(rhs),
+                        UDiv => (ul / ur) as i64,
+                        _ => (ul % ur) as i64,
+                    };
+                    frame.set(ir.lhs, val);
+                }
+            }
+        }
+    }
+
+    fn builtin(&mut self, name: &str, args: &[i64], out: &mut dyn Write) -> Result<i64, Trap> {
+        let arg = |i: usize| args.get(i).cloned().unwrap_or(0);
+        let write_err = |e: std::io::Error| fault(&format!("write failed: {}", e));
+        match name {
+            "printf" => {
+                let s = self.format(&self.cstr(arg(0))?, args.get(1..).unwrap_or(&[]))?;
+                out.write_all(&s).map_err(write_err)?;
+                Ok(s.len() as i64)
+            }
+            "putchar" => {
+                out.write_all(&[arg(0) as u8]).map_err(write_err)?;
+                Ok(arg(0) as u8 as i64)
+            }
+            "memset" => {
+                let (addr, n) = (arg(0) as usize, arg(2) as usize);
+                if n != 0 {
+                    self.check(addr, n)?;
+                    for b in &mut self.mem[addr..addr + n] {
+                        *b = arg(1) as u8;
+                    }
+                }
+                Ok(arg(0))
+            }
+            "exit" => Err(Trap::Exit(arg(0) as i32)),
+            _ => Err(fault(&format!("undefined function: {}", name))),
+        }
+    }
+
+    // A subset of printf: flags "-0+ ", width, precision, the h/l/ll
+    // length modifiers and the d, i, u, x, X, o, c, s, p and % conversions.
+    fn format(&self, fmt: &[u8], args: &[i64]) -> Result<Vec<u8>, Trap> {
+        let mut out = vec![];
+        let mut args = args.iter();
+        let mut i = 0;
+        while i < fmt.len() {
+            let c = fmt[i];
+            i += 1;
+            if c != b'%' {
+                out.push(c);
+                continue;
+            }
+
+            let (mut left, mut zero, mut plus, mut space) = (false, false, false, false);
+            while i < fmt.len() {
+                match fmt[i] {
+                    b'-' => left = true,
+                    b'0' => zero = true,
+                    b'+' => plus = true,
+                    b' ' => space = true,
+                    _ => break,
+                }
+                i += 1;
+            }
+
+            let mut width = 0;
+            while i < fmt.len() && fmt[i].is_ascii_digit() {
+                width = width * 10 + (fmt[i] - b'0') as usize;
+                i += 1;
+            }
+
+            let mut prec = None;
+            if i < fmt.len() && fmt[i] == b'.' {
+                i += 1;
+                let mut p = 0;
+                while i < fmt.len() && fmt[i].is_ascii_digit() {
+                    p = p * 10 + (fmt[i] - b'0') as usize;
+                    i += 1;
+                }
+                prec = Some(p);
+            }
+
+            let mut long = false;
+            while i < fmt.len() && (fmt[i] == b'l' || fmt[i] == b'h') {
+                long |= fmt[i] == b'l';
+                i += 1;
+            }
+
+            let conv = match fmt.get(i) {
+                Some(c) => *c,
+                None => break,
+            };
+            i += 1;
+
+            let mut next = || *args.next().unwrap_or(&0);
+            let body: Vec<u8> = match conv {
+                b'%' => {
+                    out.push(b'%');
+                    continue;
+                }
+                b'd' | b'i' => {
+                    let val = if long { next() } else { next() as i32 as i64 };
+                    let mut s = val.unsigned_abs().to_string();
+                    if let Some(p) = prec {
+                        while s.len() < p {
+                            s.insert(0, '0');
+                        }
+                    }
+                    if val < 0 {
+                        s.insert(0, '-');
+                    } else if plus {
+                        s.insert(0, '+');
+                    } else if space {
+                        s.insert(0, ' ');
+                    }
+                    s.into_bytes()
+                }
+                b'u' | b'x' | b'X' | b'o' => {
+                    let val = if long {
+                        next() as u64
+                    } else {
+                        next() as u32 as u64
+                    };
+                    let s = match conv {
+                        b'u' => val.to_string(),
+                        b'x' => format!("{:x}", val),
+                        b'X' => format!("{:X}", val),
+                        _ => format!("{:o}", val),
+                    };
+                    s.into_bytes()
+                }
+                b'p' => format!("{:#x}", next()).into_bytes(),
+                b'c' => vec![next() as u8],
+                b's' => {
+                    let mut s = self.cstr(next())?;
+                    if let Some(p) = prec {
+                        s.truncate(p);
+                    }
+                    s
+                }
+                _ => {
+                    let msg = format!("unsupported printf conversion: %{}", conv as char);
+                    return Err(fault(&msg));
+                }
+            };
+
+            if body.len() >= width {
+                out.extend(body);
+            } else if left {
+                out.extend(&body);
+                out.resize(out.len() + width - body.len(), b' ');
+            } else if zero && prec.is_none() && conv != b's' && conv != b'c' {
+                let sign = matches!(body.first(), Some(b'-') | Some(b'+') | Some(b' '));
+                let (sign, digits) = body.split_at(sign as usize);
+                out.extend(sign);
+                out.resize(out.len() + width - body.len(), b'0');
+                out.extend(digits);
+            } else {
+                out.resize(out.len() + width - body.len(), b' ');
+                out.extend(body);
+            }
+        }
+        Ok(out)
+    }
+}
@@ -22,6 +22,7 @@ impl<'a> From<&'a IROp> for IRInfo {
             AddImm => IRInfo::new("ADD", IRType::RegImm),
             Call(_, _, _) => IRInfo::new("CALL", IRType::Call),
             Div => IRInfo::new("DIV", IRType::RegReg),
+            UDiv => IRInfo::new("UDIV", IRType::RegReg),
             Imm => IRInfo::new("MOV", IRType::RegImm),
             Jmp => IRInfo::new("JMP", IRType::Jmp),
             Kill => IRInfo::new("KILL", IRType::Reg),
@@ -31,14 +32,26 @@ impl<'a> From<&'a IROp> for IRInfo {
             NE => IRInfo::new("NE", IRType::RegReg),
             LE => IRInfo::new("LE", IRType::RegReg),
             LT => IRInfo::new("LT", IRType::RegReg),
+            ULE => IRInfo::new("ULE", IRType::RegReg),
+            ULT => IRInfo::new("ULT", IRType::RegReg),
+            GE => IRInfo::new("GE", IRType::RegReg),
+            GT => IRInfo::new("GT", IRType::RegReg),
+            UGE => IRInfo::new("UGE", IRType::RegReg),
+            UGT => IRInfo::new("UGT", IRType::RegReg),
             AND => IRInfo::new("AND", IRType::RegReg),
             OR => IRInfo::new("OR", IRType::RegReg),
             XOR => IRInfo::new("XOR", IRType::RegReg),
             SHL => IRInfo::new("SHL", IRType::RegReg),
             SHR => IRInfo::new("SHR", IRType::RegReg),
+            USHR => IRInfo::new("USHR", IRType::RegReg),
             Mod => IRInfo::new("MOD", IRType::RegReg),
+            UMod => IRInfo::new("UMOD", IRType::RegReg),
             Neg => IRInfo::new("NEG", IRType::Reg),
+            Not => IRInfo::new("NOT", IRType::Reg),
+            Ext(_) => IRInfo::new("EXT", IRType::Reg),
+            UExt(_) => IRInfo::new("UEXT", IRType::Reg),
             Load(_) => IRInfo::new("LOAD", IRType::Mem),
+            ULoad(_) => IRInfo::new("ULOAD", IRType::Mem),
             Mov => IRInfo::new("MOV", IRType::RegReg),
             Mul => IRInfo::new("MUL", IRType::RegReg),
             MulImm => IRInfo::new("MUL", IRType::RegImm),
@@ -73,7 +86,7 @@ impl fmt::Display for IR {
             Jmp => write!(f, "  {} .L{}", info.name, lhs),
             RegReg => write!(f, "  {} r{}, r{}", info.name, lhs, self.rhs.unwrap()),
             Mem | StoreArg => match self.op {
-                IROp::Load(ref size) | IROp::Store(ref size) => {
+                IROp::Load(ref size) | IROp::ULoad(ref size) | IROp::Store(ref size) => {
                     write!(f, "  {}{} r{}, {}", info.name, size, lhs, self.rhs.unwrap())
                 }
                 IROp::StoreArg(ref size) => {
@@ -81,18 +94,20 @@ impl fmt::Display for IR {
                 }
                 _ => unreachable!(),
             },
-            RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i32),
+            RegImm => write!(f, "  {} r{}, {}", info.name, lhs, self.rhs.unwrap() as i64),
             RegLabel => write!(f, "  {} r{}, .L{}", info.name, lhs, self.rhs.unwrap()),
             Call => match self.op {
-                IROp::Call(ref name, nargs, args) => {
+                IROp::Call(ref name, ref args, ref slots) => {
                     let mut sb: String = format!("  r{} = {}(", lhs, name);
-                    for (i, arg) in args.iter().enumerate().take(nargs) {
+                    let args = args.iter().map(|arg| format!("r{}", arg));
+                    let slots = slots.iter().map(|off| format!("[{}]", off));
+                    for (i, arg) in args.chain(slots).enumerate() {
                         if i != 0 {
-                            sb.push_str(&", ".to_string());
+                            sb.push_str(", ");
                         }
-                        sb.push_str(&format!("r{}", *arg));
+                        sb.push_str(&arg);
                     }
-                    sb.push_str(")");
+                    sb.push(')');
                     write!(f, "{}", sb)
                 }
                 _ => unreachable!(),
@@ -1,4 +1,11 @@
+pub mod cfg;
+pub mod diagnostic;
+pub mod fold;
 pub mod gen_ir;
+pub mod gen_riscv;
+pub mod gen_x86;
+pub mod headers;
+pub mod interp;
 pub mod irdump;
 pub mod parse;
 pub mod preprocess;
@@ -10,8 +17,6 @@ mod util;
 #[macro_use]
 extern crate lazy_static;
 
-const REGS_N: usize = 7;
-
 #[macro_export]
 macro_rules! matches(
     ($e:expr, $p:pat) => (
@@ -25,8 +30,8 @@ macro_rules! matches(
 // Token type
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenType {
-    Num(i32),            // Number literal
-    Str(String, usize),  // String literal. (str, len)
+    Num(i64),            // Number literal
+    Str(Vec<u8>, usize), // String literal. (bytes, len)
     CharLiteral(String), // Char literal.
     Ident(String),       // Identifier
     Param(usize),        // Function-like macro parameter
@@ -35,8 +40,13 @@ pub enum TokenType {
     Typedef,             // "typedef"
     Int,                 // "int"
     Char,                // "char"
+    Short,               // "short"
+    Long,                // "long"
+    Signed,              // "signed"
+    Unsigned,            // "unsigned"
     Void,                // "void"
     Struct,              // "struct"
+    Enum,                // "enum"
     Plus,                // +
     Minus,               // -
     Mul,                 // *
@@ -48,14 +58,22 @@ pub enum TokenType {
     Question,            // ?
     VerticalBar,         // |
     Hat,                 // ^
+    Tilde,               // ~
     Colon,               // :
     HashMark,            // #
+    HashHash,            // ##
+    Ellipsis,            // ...
     If,                  // "if"
     Else,                // "else"
     For,                 // "for"
     Do,                  // "do"
     While,               // "while"
     Break,               // "break"
+    Switch,              // "switch"
+    Case,                // "case"
+    Default,             // "default"
+    Continue,            // "continue"
+    Goto,                // "goto"
     EQ,                  // ==
     NE,                  // !=
     LE,                  // <=
@@ -129,29 +147,38 @@ impl TokenType {
             '?' => Some(Question),
             '|' => Some(VerticalBar),
             '^' => Some(Hat),
+            '~' => Some(Tilde),
             '%' => Some(Mod),
             ':' => Some(Colon),
             '#' => Some(HashMark),
             _ => None,
         }
     }
+
+    // Returns how a punctuator or keyword is written, for messages.
+    pub fn spelling(&self) -> String {
+        "+-*/&;=()[]{}<>,.!?|^~%:#"
+            .chars()
+            .find(|c| Self::new_single_letter(*c).as_ref() == Some(self))
+            .map(|c| c.to_string())
+            .or_else(|| token::keyword_spelling(self))
+            .unwrap_or_else(|| format!("{:?}", self))
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Ctype {
+    #[default]
     Int,
     Char,
+    Short,
+    Long, // Also used for long long, which is the same in LP64
     Void,
-    Ptr(Box<Type>),           // ptr of
-    Ary(Box<Type>, usize),    // ary of, len
-    Struct(Vec<parse::Node>), // members
-    Func(Box<Type>),
-}
-
-impl Default for Ctype {
-    fn default() -> Ctype {
-        Ctype::Int
-    }
+    Ptr(Box<Type>),                           // ptr of
+    Ary(Box<Type>, usize),                    // ary of, len
+    Struct(usize, Vec<parse::Node>),          // id, members
+    Enum(String),                             // tag, empty if anonymous
+    Func(Box<Type>, Option<Vec<Type>>, bool), // returning, params (None if unspecified), is_variadic
 }
 
 #[derive(Debug, Clone)]
@@ -159,6 +186,7 @@ pub struct Type {
     pub ty: Ctype,
     pub size: usize,  // sizeof
     pub align: usize, // alignof
+    pub is_unsigned: bool,
 }
 
 impl Default for Type {
@@ -167,14 +195,33 @@ impl Default for Type {
             ty: Ctype::default(),
             size: 4,
             align: 4,
+            is_unsigned: false,
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Scope {
-    Local(usize),                // offset
-    Global(String, usize, bool), // data, len, is_extern
+    Local(usize),              // offset
+    Global(Data, usize, bool), // data, len, is_extern
+    Enum(i64),                 // value of an enumerator
+}
+
+// The initial contents of a global variable. Empty for variables that
+// are zero-initialized.
+#[derive(Debug, Clone, Default)]
+pub struct Data {
+    pub bytes: Vec<u8>,
+    pub relocs: Vec<Reloc>, // Sorted by offset
+}
+
+// An 8-byte address that the linker fills in: the address of `label`
+// plus `addend`, stored at `offset` in the data.
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    pub offset: usize,
+    pub label: String,
+    pub addend: i64,
 }
 
 #[derive(Debug, Clone)]
@@ -182,14 +229,20 @@ pub struct Var {
     ty: Box<Type>,
     pub name: String,
     pub scope: Scope,
+    loc: Option<token::Loc>, // Where it is declared, for error reporting
 }
 
 impl Var {
     fn new(ty: Box<Type>, name: String, scope: Scope) -> Self {
-        Var { ty, name, scope }
+        Var {
+            ty,
+            name,
+            scope,
+            loc: None,
+        }
     }
 
-    fn new_global(ty: Box<Type>, name: String, data: String, len: usize, is_extern: bool) -> Self {
+    fn new_global(ty: Box<Type>, name: String, data: Data, len: usize, is_extern: bool) -> Self {
         Var::new(ty, name.clone(), Scope::Global(data, len, is_extern))
     }
 }
@@ -1,21 +1,40 @@
 extern crate seu9cc;
 
+use seu9cc::cfg::remove_unreachable;
+use seu9cc::diagnostic::Diagnostic;
 use seu9cc::gen_ir::gen_ir;
+use seu9cc::gen_riscv;
+use seu9cc::gen_x86;
+use seu9cc::interp::Interpreter;
 use seu9cc::irdump::dump_ir;
 use seu9cc::parse::parse;
-use seu9cc::preprocess::Preprocessor;
+use seu9cc::preprocess::{source_text, Preprocessor};
 use seu9cc::regalloc::alloc_regs;
 use seu9cc::sema::sema;
 use seu9cc::token::tokenize;
 
 use std::env;
+use std::io;
 use std::process;
 
 fn usage() -> ! {
-    eprintln!("Usage: seu9cc [-t] <file>");
+    eprintln!(
+        "Usage: seu9cc [-I<dir>] [-target x86_64|riscv64] [-E | -t | -dump-ir1 | -dump-ir2 | -run] <file>"
+    );
     process::exit(1)
 }
 
+// Prints an error and exits.
+fn fail(err: Diagnostic) -> ! {
+    eprint!("{}", err);
+    process::exit(1)
+}
+
+enum Target {
+    X86_64,
+    Riscv64,
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() == 1 {
@@ -23,22 +42,49 @@ fn main() {
     }
 
     let mut dump_ir1 = false;
+    let mut dump_ir2 = false;
     let mut dump_tokens = false;
-    let path;
+    let mut preprocess_only = false;
+    let mut run = false;
+    let mut target = Target::X86_64;
+    let mut path = None;
+    let mut ctx = Preprocessor::new();
 
-    if args[1] == "-t" {
-        if args.len() < 3 {
-            usage();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-t" => dump_tokens = true,
+            "-E" => preprocess_only = true,
+            "-dump-ir1" => dump_ir1 = true,
+            "-dump-ir2" => dump_ir2 = true,
+            "-run" => run = true,
+            "-I" => {
+                i += 1;
+                ctx.add_include_path(args.get(i).cloned().unwrap_or_else(|| usage()));
+            }
+            s if s.starts_with("-I") => ctx.add_include_path(s[2..].to_string()),
+            "-target" => {
+                i += 1;
+                target = match args.get(i).map(|s| s.as_str()) {
+                    Some("x86_64") => Target::X86_64,
+                    Some("riscv64") => Target::Riscv64,
+                    _ => usage(),
+                };
+            }
+            _ if path.is_none() => path = Some(args[i].clone()),
+            _ => usage(),
         }
-        dump_tokens = true;
-        path = &args[2];
-    } else {
-        path = &args[1];
-        dump_ir1 = true;
+        i += 1;
     }
+    let path = path.unwrap_or_else(|| usage());
 
     // Tokenize and parse.
-    let tokens = tokenize(path.clone(), &mut Preprocessor::new());
+    let tokens = tokenize(path, &mut ctx).unwrap_or_else(|e| fail(e));
+
+    if preprocess_only {
+        print!("{}", source_text(&tokens));
+        return;
+    }
 
     if dump_tokens {
         for token in &tokens {
@@ -50,13 +96,50 @@ fn main() {
         return;
     }
 
-    let nodes = parse(&tokens);
-    let (nodes, globals) = sema(nodes);
-    let mut fns = gen_ir(nodes);
+    let mut errors = vec![];
+    let nodes = parse(&tokens, &mut errors);
+    if !errors.is_empty() {
+        for e in &errors {
+            eprint!("{}", e);
+        }
+        process::exit(1);
+    }
+    let mut warnings = vec![];
+    let res = sema(nodes, &mut warnings);
+    for w in &warnings {
+        eprint!("{}", w);
+    }
+    let (nodes, globals) = res.unwrap_or_else(|e| fail(e));
+    let mut fns = gen_ir(nodes).unwrap_or_else(|e| fail(e));
+    remove_unreachable(&mut fns);
 
     if dump_ir1 {
         dump_ir(&fns);
     }
 
-    // gen_x86(globals, fns);
+    if run {
+        let status = Interpreter::new(&globals, &fns)
+            .and_then(|mut interp| interp.run(&mut io::stdout()))
+            .unwrap_or_else(|e| fail(e));
+        process::exit(status);
+    }
+
+    let regs_n = match target {
+        Target::X86_64 => gen_x86::REGS_N,
+        Target::Riscv64 => gen_riscv::REGS_N,
+    };
+    alloc_regs(&mut fns, regs_n).unwrap_or_else(|e| fail(e));
+
+    if dump_ir2 {
+        dump_ir(&fns);
+    }
+
+    if dump_ir1 || dump_ir2 {
+        return;
+    }
+
+    match target {
+        Target::X86_64 => gen_x86::gen_x86(globals, fns),
+        Target::Riscv64 => gen_riscv::gen_riscv(globals, fns),
+    }
 }
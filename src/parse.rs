@@ -1,6 +1,8 @@
-use crate::token::Token;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::fold::{const_value, fold};
+use crate::token::{Loc, Token};
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type};
+use crate::{Ctype, Data, Scope, TokenType, Type};
 
 use std::collections::HashMap;
 
@@ -23,15 +25,33 @@ int main() {     ; +-+                        int   []         2
 }                ; +-+                  return        []      +->primary
                                                                  0
 */
-pub fn parse(tokens: &Vec<Token>) -> Vec<Node> {
+//
+// Syntax errors don't stop the parser. Each one is appended to `errors`,
+// the tokens up to the end of the statement or declaration are skipped,
+// and an Error node is left in their place.
+pub fn parse(tokens: &Vec<Token>, errors: &mut Vec<Diagnostic>) -> Vec<Node> {
+    // Nodes of an earlier translation unit are not needed anymore.
+    Loc::clear();
     let mut parser = Parser::new(tokens);
 
     let mut v = vec![];
-    while tokens.len() != parser.pos {
-        if let Some(node) = parser.toplevel() {
-            v.push(node);
+    while parser.pos < tokens.len() {
+        let start = parser.pos;
+        match parser.toplevel() {
+            Ok(Some(node)) => {
+                v.append(&mut parser.enumerators);
+                v.push(node);
+            }
+            Ok(None) => v.append(&mut parser.enumerators),
+            Err(e) => {
+                parser.enumerators.clear();
+                parser.errors.push(e);
+                parser.skip_toplevel(start);
+                v.push(Node::new(NodeType::Error).at(&tokens[start]));
+            }
         }
     }
+    errors.append(&mut parser.errors);
     v
 }
 
@@ -39,6 +59,9 @@ pub fn parse(tokens: &Vec<Token>) -> Vec<Node> {
 struct Env {
     tags: HashMap<String, Type>,
     typedefs: HashMap<String, Type>,
+    // Variables and enumerators in scope, with the values of the
+    // enumerators. A variable hides an enumerator of an outer scope.
+    vars: HashMap<String, Option<i64>>,
     next: Option<Box<Env>>,
 }
 
@@ -48,6 +71,7 @@ impl Env {
             next,
             tags: HashMap::new(),
             typedefs: HashMap::new(),
+            vars: HashMap::new(),
         }
     }
 }
@@ -60,42 +84,64 @@ macro_rules! new_expr(
 
 #[derive(Debug, Clone)]
 pub enum NodeType {
-    Num(i32),                                        // Number literal
-    Str(String, usize),                              // String literal, (data, len)
+    Num(i64),                                        // Number literal
+    Str(Vec<u8>, usize),                             // String literal, (data, len)
     Ident(String),                                   // Identifier
     Decl(String),                                    // declaration
     Vardef(String, Option<Box<Node>>, Scope),        // Variable definition, name = init
     Lvar(Scope),                                     // Variable reference
-    Gvar(String, String, usize),                     // Variable reference, (name, data, len)
+    Gvar(String, usize),                             // Variable reference, (name, len)
     BinOp(TokenType, Box<Node>, Box<Node>),          // left-hand, right-hand
     If(Box<Node>, Box<Node>, Option<Box<Node>>),     // "if" ( cond ) then "else" els
     Ternary(Box<Node>, Box<Node>, Box<Node>),        // cond ? then : els
     For(Box<Node>, Box<Node>, Box<Node>, Box<Node>), // "for" ( init; cond; inc ) body
+    Switch(Box<Node>, Box<Node>, Vec<i64>, bool),    // "switch" ( cond ) body, cases, has_default
+    Case(Box<Node>, Box<Node>, usize),               // "case" val: body, index into cases
+    Default(Box<Node>),                              // "default": body
     Break,
-    DoWhile(Box<Node>, Box<Node>), // do { body } while(cond)
-    Addr(Box<Node>),               // address-of operator("&"), expr
-    Deref(Box<Node>),              // pointer dereference ("*"), expr
-    Dot(Box<Node>, String, usize), // Struct member accessm, (expr, name, offset)
-    Exclamation(Box<Node>),        // !, expr
-    Neg(Box<Node>),                // -
-    PostInc(Box<Node>),            // post ++
-    PostDec(Box<Node>),            // post --
-    Return(Box<Node>),             // "return", stmt
-    Sizeof(Box<Node>),             // "sizeof", expr
-    Alignof(Box<Node>),            // "_Alignof", expr
-    Call(String, Vec<Node>),       // Function call(name, args)
+    Continue,
+    Goto(String),                              // "goto" name
+    Label(String, Box<Node>),                  // name: body
+    DoWhile(Box<Node>, Box<Node>),             // do { body } while(cond)
+    Addr(Box<Node>),                           // address-of operator("&"), expr
+    Deref(Box<Node>),                          // pointer dereference ("*"), expr
+    Dot(Box<Node>, String, usize),             // Struct member accessm, (expr, name, offset)
+    Exclamation(Box<Node>),                    // !, expr
+    Neg(Box<Node>),                            // -
+    UnaryPlus(Box<Node>),                      // +, expr
+    BitNot(Box<Node>),                         // ~, expr
+    Cast(Box<Node>),                           // Type conversion to ty, expr
+    PostInc(Box<Node>),                        // post ++
+    PostDec(Box<Node>),                        // post --
+    Return(Box<Node>),                         // "return", stmt
+    Sizeof(Box<Node>),                         // "sizeof", expr
+    Alignof(Box<Node>),                        // "_Alignof", expr
+    Call(String, Vec<Node>),                   // Function call(name, args)
     Func(String, Vec<Node>, Box<Node>, usize), // Function definition(name, args, body, stacksize)
-    CompStmt(Vec<Node>),           // Compound statement
-    VecStmt(Vec<Node>),            // For the purpose of assign a value when initializing an array.
-    ExprStmt(Box<Node>),           // Expression statement
-    StmtExpr(Box<Node>),           // Statement expression (GNU extn.)
+    CompStmt(Vec<Node>),                       // Compound statement
+    InitList(Vec<Node>),                       // Initializer "{" elements "}"
+    Designation(Vec<Designator>, Box<Node>),   // Element of InitList, designators "=" init
+    VecStmt(Vec<Node>), // For the purpose of assign a value when initializing an array.
+    ZeroFill(usize, usize), // Clears the stack from bp offset upward, (offset, words)
+    ExprStmt(Box<Node>), // Expression statement
+    StmtExpr(Box<Node>), // Statement expression (GNU extn.)
     Null,
+    Error, // Code that failed to parse
+}
+
+// Names the element of an aggregate that an initializer is for.
+#[derive(Debug, Clone)]
+pub enum Designator {
+    Index(usize),   // [index]
+    Member(String), // .name
 }
 
 #[derive(Debug, Clone)]
 pub struct Node {
     pub op: NodeType,  // Node type
     pub ty: Box<Type>, // C type
+    // For error reporting, (location, macro invocation it came from)
+    pub loc: Option<(Loc, Option<Loc>)>,
 }
 
 impl Node {
@@ -103,35 +149,63 @@ impl Node {
         Self {
             op,
             ty: Box::new(Type::default()),
+            loc: None,
         }
     }
 
-    pub fn new_int(val: i32) -> Self {
-        Node::new(NodeType::Num(val))
+    // Sets the token the node is reported at.
+    pub fn at(mut self, t: &Token) -> Self {
+        self.loc = Some(t.loc());
+        self
     }
 
-    pub fn scale_ptr(node: Box<Node>, ty: &Type) -> Self {
-        match ty.ty {
-            Ctype::Ptr(ref ptr_to) => {
-                Node::new_binop(TokenType::Mul, *node, Node::new_int(ptr_to.size as i32))
+    // Sets the location of a node made from another node.
+    pub fn at_node(mut self, node: &Node) -> Self {
+        self.loc = node.loc;
+        self
+    }
+
+    // Like Token::bad_token().
+    pub fn error(&self, msg: &str) -> Diagnostic {
+        match self.loc {
+            Some((loc, origin)) => {
+                let mut d = Diagnostic::error(msg).with_span(loc.span());
+                if let Some(origin) = origin {
+                    let note = Diagnostic::note("in expansion of macro");
+                    d = d.with_note(note.with_span(origin.span()));
+                }
+                d
             }
-            _ => panic!("expect ptr type"),
+            None => Diagnostic::error(msg),
         }
     }
 
+    pub fn new_int(val: i64) -> Self {
+        Node::new(NodeType::Num(val))
+    }
+
+    // Multiplies an offset added to a pointer by the size of what it
+    // points to.
+    #[allow(clippy::boxed_local)]
+    pub fn scale_ptr(node: Box<Node>, ptr_to: &Type) -> Self {
+        let loc = node.loc;
+        let mut size = Node::new_int(ptr_to.size as i64);
+        size.loc = loc;
+        let mut mul = Node::new_binop(TokenType::Mul, *node, size);
+        mul.loc = loc;
+        mul
+    }
+
     pub fn new_binop(ty: TokenType, lhs: Node, rhs: Node) -> Self {
         Node::new(NodeType::BinOp(ty, Box::new(lhs), Box::new(rhs)))
     }
 
-    pub fn new_num(val: i32) -> Self {
+    pub fn new_num(val: i64) -> Self {
         Node::new(NodeType::Num(val))
     }
 
     pub fn is_null(&self) -> bool {
-        match self.op {
-            NodeType::Null => true,
-            _ => false,
-        }
+        matches!(self.op, NodeType::Null)
     }
 }
 
@@ -141,6 +215,7 @@ impl Type {
             ty,
             size,
             align: size,
+            is_unsigned: false,
         }
     }
 
@@ -152,10 +227,34 @@ impl Type {
         Type::new(Ctype::Char, 1)
     }
 
+    pub fn short_ty() -> Self {
+        Type::new(Ctype::Short, 2)
+    }
+
     pub fn int_ty() -> Self {
         Type::new(Ctype::Int, 4)
     }
 
+    pub fn long_ty() -> Self {
+        Type::new(Ctype::Long, 8)
+    }
+
+    pub fn to_unsigned(mut self) -> Self {
+        self.is_unsigned = true;
+        self
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(
+            self.ty,
+            Ctype::Char | Ctype::Short | Ctype::Int | Ctype::Long | Ctype::Enum(_)
+        )
+    }
+
+    pub fn is_incomplete_array(&self) -> bool {
+        matches!(self.ty, Ctype::Ary(_, 0))
+    }
+
     pub fn ptr_to(base: Box<Type>) -> Self {
         Type::new(Ctype::Ptr(base), 8)
     }
@@ -173,6 +272,14 @@ pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
     pos: usize,
     env: Env,
+    errors: Vec<Diagnostic>,
+    // Enumerators declared since the last statement or top-level
+    // declaration, which are emitted in front of it.
+    enumerators: Vec<Node>,
+    in_const_expr: bool,
+    // Each struct type gets its own id, so that two structs with the
+    // same layout are still different types.
+    nstruct: usize,
 }
 
 impl<'a> Parser<'a> {
@@ -181,6 +288,10 @@ impl<'a> Parser<'a> {
             tokens,
             pos: 0,
             env: Env::new(None),
+            errors: vec![],
+            enumerators: vec![],
+            in_const_expr: false,
+            nstruct: 0,
         }
     }
 
@@ -214,21 +325,162 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn expect(&mut self, ty: TokenType) {
-        let t = &self.tokens[self.pos];
+    fn find_enum(&self, name: &str) -> Option<i64> {
+        let mut next: &Option<Box<Env>> = &Some(Box::new(self.env.clone()));
+        loop {
+            if let Some(ref e) = next {
+                if let Some(val) = e.vars.get(name) {
+                    return *val;
+                }
+                next = &e.next;
+            } else {
+                return None;
+            }
+        }
+    }
+
+    // Returns the current token. Running out of tokens is an error,
+    // reported just after the last one.
+    fn peek(&self) -> Result<&'a Token, Diagnostic> {
+        if let Some(t) = self.tokens.get(self.pos) {
+            return Ok(t);
+        }
+        let msg = "unexpected end of input";
+        Err(match self.tokens.last() {
+            Some(t) => {
+                Diagnostic::error(msg).with_span(Span::new(&t.filename, &t.buf, t.end, t.end))
+            }
+            None => Diagnostic::error(msg),
+        })
+    }
+
+    // Returns the token that has just been read.
+    fn prev(&self) -> &'a Token {
+        &self.tokens[self.pos - 1]
+    }
+
+    fn expect(&mut self, ty: TokenType) -> Result<(), Diagnostic> {
+        let t = self.peek()?;
         if t.ty != ty {
-            t.bad_token(&format!("{:?} expected", ty));
+            return Err(t.bad_token(&format!("'{}' expected", ty.spelling())));
         }
         self.pos += 1;
+        Ok(())
     }
 
     fn consume(&mut self, ty: TokenType) -> bool {
-        let t = &self.tokens[self.pos];
-        if t.ty != ty {
-            return false;
+        match self.tokens.get(self.pos) {
+            Some(t) if t.ty == ty => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Skips the rest of a statement after a syntax error: up to and
+    // including the next `;` or block, or up to the `}` that closes the
+    // enclosing block.
+    fn skip_stmt(&mut self) {
+        let mut depth = 0;
+        while let Some(t) = self.tokens.get(self.pos) {
+            match t.ty {
+                TokenType::Semicolon if depth == 0 => {
+                    self.pos += 1;
+                    return;
+                }
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth == 0 => return,
+                TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.pos += 1;
+                        return;
+                    }
+                }
+                _ => (),
+            }
+            self.pos += 1;
+        }
+    }
+
+    // Skips the rest of a top-level declaration after a syntax error.
+    // Besides `;` and the `}` that ends a function body, a declaration
+    // specifier at the beginning of a line is taken as the start of the
+    // next declaration. The error may be inside braces, such as those of
+    // a struct, so skipping starts at the depth of the error.
+    fn skip_toplevel(&mut self, start: usize) {
+        use self::TokenType::*;
+        let mut depth = 0;
+        let mut is_body = false; // Whether the outermost braces are a function body
+        for i in start.. {
+            let t = match self.tokens.get(i) {
+                Some(t) => t,
+                None => break,
+            };
+            let is_skipped = i >= self.pos;
+            let is_specifier = matches!(
+                t.ty,
+                Typedef
+                    | Extern
+                    | Int
+                    | Char
+                    | Short
+                    | Long
+                    | Signed
+                    | Unsigned
+                    | Void
+                    | Struct
+                    | Enum
+            );
+            if is_skipped && depth == 0 && i > start && is_specifier && t.starts_line() {
+                self.pos = i;
+                return;
+            }
+            let was_nested = depth > 0;
+            match t.ty {
+                LeftBrace if depth == 0 => {
+                    is_body = i > 0 && self.tokens[i - 1].ty == RightParen;
+                    depth = 1;
+                }
+                LeftBrace => depth += 1,
+                RightBrace if was_nested => depth -= 1,
+                _ => (),
+            }
+            let is_end = match t.ty {
+                Semicolon => depth == 0,
+                RightBrace => depth == 0 && (is_body || !was_nested),
+                _ => false,
+            };
+            if is_skipped && is_end {
+                self.pos = i + 1;
+                return;
+            }
+        }
+        self.pos = self.tokens.len();
+    }
+
+    // Parses a statement in a block. On a syntax error, the statement is
+    // skipped and replaced with an Error node. Running out of input is
+    // not recovered from.
+    fn block_item(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.peek()?;
+        let n = self.enumerators.len();
+        let res = self.stmt();
+        let mut enumerators: Vec<Node> = self.enumerators.drain(n..).collect();
+        match res {
+            Ok(node) if enumerators.is_empty() => Ok(node),
+            Ok(node) => {
+                enumerators.push(node);
+                Ok(Node::new(NodeType::VecStmt(enumerators)).at(start))
+            }
+            Err(e) if self.pos >= self.tokens.len() => Err(e),
+            Err(e) => {
+                self.errors.push(e);
+                self.skip_stmt();
+                Ok(Node::new(NodeType::Error).at(start))
+            }
         }
-        self.pos += 1;
-        true
     }
 
     fn is_typename(&self, t: &Token) -> bool {
@@ -236,13 +488,30 @@ impl<'a> Parser<'a> {
         if let TokenType::Ident(ref name) = t.ty {
             return self.find_typedef(name).is_some();
         }
-        t.ty == Int || t.ty == Char || t.ty == Void || t.ty == Struct
+        matches!(
+            t.ty,
+            Int | Char | Short | Long | Signed | Unsigned | Void | Struct | Enum
+        )
     }
 
-    fn set_offset(members: &mut Vec<Node>) -> (usize, usize) {
+    fn set_offset(members: &mut [Node]) -> Result<(usize, usize), Diagnostic> {
         let mut off = 0;
         let mut align = 0;
-        for node in members {
+        let n = members.len();
+        for (i, node) in members.iter_mut().enumerate() {
+            if node.ty.align == 0 {
+                return Err(node.error("field has incomplete type"));
+            }
+            // An array of unknown length may only be the last member, as
+            // a flexible array member that takes no space.
+            if node.ty.is_incomplete_array() {
+                if i + 1 < n {
+                    return Err(node.error("flexible array member is not at the end of struct"));
+                }
+                if n == 1 {
+                    return Err(node.error("flexible array member in otherwise empty struct"));
+                }
+            }
             if let NodeType::Vardef(_, _, Scope::Local(offset)) = &mut node.op {
                 let t = &node.ty;
                 off = roundup(off, t.align);
@@ -253,38 +522,47 @@ impl<'a> Parser<'a> {
                     align = t.align;
                 }
             } else {
-                panic!();
+                return Err(node.error("field expected"));
             }
         }
-        (off, align)
+        Ok((off, align))
     }
 
-    fn add_member(ty: &mut Type, mut members: Vec<Node>) {
-        let (off, align) = Self::set_offset(&mut members);
-        if let Ctype::Struct(ref mut members2) = ty.ty {
+    fn add_member(ty: &mut Type, mut members: Vec<Node>) -> Result<(), Diagnostic> {
+        let (off, align) = Self::set_offset(&mut members)?;
+        if let Ctype::Struct(_, ref mut members2) = ty.ty {
             *members2 = members;
         }
         ty.size = roundup(off, align);
+        ty.align = align;
+        Ok(())
     }
 
-    fn decl_specifiers(&mut self) -> Option<Type> {
-        let t = &self.tokens[self.pos];
+    fn decl_specifiers(&mut self) -> Result<Option<Type>, Diagnostic> {
+        let t = self.peek()?;
         self.pos += 1;
         match t.ty {
             TokenType::Ident(ref name) => {
                 if let Some(ty) = self.find_typedef(name) {
-                    return Some(ty.clone());
+                    Ok(Some(ty.clone()))
                 } else {
                     self.pos -= 1;
-                    return None;
+                    Ok(None)
                 }
             }
-            TokenType::Int => Some(Type::int_ty()),
-            TokenType::Char => Some(Type::char_ty()),
-            TokenType::Void => Some(Type::void_ty()),
+            TokenType::Void => Ok(Some(Type::void_ty())),
+            TokenType::Int
+            | TokenType::Char
+            | TokenType::Short
+            | TokenType::Long
+            | TokenType::Signed
+            | TokenType::Unsigned => {
+                self.pos -= 1;
+                self.int_specifiers().map(Some)
+            }
             TokenType::Struct => {
                 let mut tag_may: Option<String> = None;
-                let t = &self.tokens[self.pos];
+                let t = self.peek()?;
                 if let TokenType::Ident(ref name) = t.ty {
                     self.pos += 1;
                     tag_may = Some(name.clone())
@@ -293,278 +571,514 @@ impl<'a> Parser<'a> {
                 let mut members = vec![];
                 if self.consume(TokenType::LeftBrace) {
                     while !self.consume(TokenType::RightBrace) {
-                        members.push(self.declaration())
+                        let member = self.declaration()?;
+                        if !member.is_null() {
+                            members.push(member);
+                        }
                     }
                 }
 
-                let mut ty_may: Option<Type> = None;
-                if let Some(ref tag) = tag_may {
-                    if members.is_empty() {
-                        ty_may = self.find_tag(&tag);
+                // A tag without members refers to the struct of that tag,
+                // declaring it if there is none. A definition completes a
+                // struct declared earlier in the same scope.
+                let ty_may = match tag_may {
+                    Some(ref tag) if members.is_empty() => self.find_tag(tag),
+                    Some(ref tag) => self.env.tags.get(tag).cloned().filter(|ty| match ty.ty {
+                        Ctype::Struct(_, ref members) => members.is_empty(),
+                        _ => false,
+                    }),
+                    None => None,
+                };
+                let mut ty = match ty_may {
+                    Some(ty) => ty,
+                    None => {
+                        self.nstruct += 1;
+                        let ty = Type::new(Ctype::Struct(self.nstruct, vec![]), 10);
+                        if let Some(ref tag) = tag_may {
+                            self.env.tags.insert(tag.clone(), ty.clone());
+                        }
+                        ty
                     }
-                }
-                let mut ty = ty_may.unwrap_or(Type::new(Ctype::Struct(vec![]), 10));
+                };
 
                 if !members.is_empty() {
-                    Self::add_member(&mut ty, members);
+                    Self::add_member(&mut ty, members)?;
                     if let Some(tag) = tag_may {
                         self.env.tags.insert(tag, ty.clone());
                     }
                 }
-                Some(ty.clone())
+                Ok(Some(ty.clone()))
             }
-            _ => t.bad_token("typename expected"),
+            TokenType::Enum => self.enum_specifier().map(Some),
+            _ => {
+                self.pos -= 1;
+                Err(t.bad_token("typename expected"))
+            }
+        }
+    }
+
+    // Reads an enum specifier after `enum`. An enum is an int. Its
+    // enumerators become Vardef nodes to be declared in sema, and are
+    // also remembered here by value for constant expressions.
+    fn enum_specifier(&mut self) -> Result<Type, Diagnostic> {
+        let t = self.peek()?;
+        let mut tag = String::new();
+        if let TokenType::Ident(ref name) = t.ty {
+            self.pos += 1;
+            tag = name.clone();
+        }
+        let ty = Type::new(Ctype::Enum(tag.clone()), 4);
+        if !self.consume(TokenType::LeftBrace) {
+            if tag.is_empty() {
+                return Err(t.bad_token("enum name expected"));
+            }
+            return Ok(ty);
+        }
+
+        let t = self.peek()?;
+        if t.ty == TokenType::RightBrace {
+            return Err(t.bad_token("use of empty enum"));
+        }
+        let mut val = 0;
+        while !self.consume(TokenType::RightBrace) {
+            let t = self.peek()?;
+            let name = match t.ty {
+                TokenType::Ident(ref name) => name.clone(),
+                _ => return Err(t.bad_token("enumerator name expected")),
+            };
+            self.pos += 1;
+            if self.consume(TokenType::Equal) {
+                let t = self.peek()?;
+                val = self.const_expr()?.ok_or_else(|| {
+                    t.bad_token("expression is not an integer constant expression")
+                })?;
+            }
+            if i32::try_from(val).is_err() {
+                let msg = format!("enumerator value {} is not representable in int", val);
+                self.errors.push(t.bad_token(&msg));
+            }
+            self.env.vars.insert(name.clone(), Some(val));
+            let mut node = Node::new(NodeType::Vardef(name, None, Scope::Enum(val))).at(t);
+            node.ty = Box::new(Type::int_ty());
+            self.enumerators.push(node);
+            val += 1;
+
+            if !self.consume(TokenType::Comma) {
+                self.expect(TokenType::RightBrace)?;
+                break;
+            }
+        }
+        Ok(ty)
+    }
+
+    // Reads the keywords of an integer type, which may come in any
+    // order, as in `long unsigned int`.
+    fn int_specifiers(&mut self) -> Result<Type, Diagnostic> {
+        use self::TokenType::*;
+        let start = self.peek()?;
+        let (mut char, mut short, mut int, mut long) = (0, 0, 0, 0);
+        let (mut signed, mut unsigned) = (0, 0);
+        loop {
+            let t = self.peek()?;
+            let count = match t.ty {
+                Char => &mut char,
+                Short => &mut short,
+                Int => &mut int,
+                Long => &mut long,
+                Signed => &mut signed,
+                Unsigned => &mut unsigned,
+                _ => break,
+            };
+            *count += 1;
+            self.pos += 1;
+        }
+
+        let ty = match (char, short, int, long) {
+            (1, 0, 0, 0) => Type::char_ty(),
+            (0, 1, _, 0) if int <= 1 => Type::short_ty(),
+            (0, 0, _, 0) if int <= 1 => Type::int_ty(),
+            (0, 0, _, 1 | 2) if int <= 1 => Type::long_ty(),
+            _ => return Err(start.bad_token("invalid combination of type specifiers")),
+        };
+        match (signed, unsigned) {
+            (0, 0) | (1, 0) => Ok(ty),
+            (0, 1) => Ok(ty.to_unsigned()),
+            _ => Err(start.bad_token("invalid combination of type specifiers")),
         }
     }
 
-    fn ident(&mut self) -> String {
-        let t = &self.tokens[self.pos];
+    fn ident(&mut self) -> Result<String, Diagnostic> {
+        let t = self.peek()?;
         if let TokenType::Ident(ref name) = t.ty {
             self.pos += 1;
-            name.clone()
+            Ok(name.clone())
         } else {
-            t.bad_token("variable name expected");
+            Err(t.bad_token("variable name expected"))
         }
     }
 
-    fn primary(&mut self) -> Node {
-        let t = &self.tokens[self.pos];
+    // Returns the type of an integer literal: the first of the types
+    // its suffix allows that can represent the value. Unsuffixed octal
+    // and hexadecimal literals may also be unsigned.
+    fn literal_type(t: &Token, val: i64) -> Result<Type, Diagnostic> {
+        let suffix = t.int_suffix().to_lowercase();
+        let (is_unsigned, is_long) = match suffix.as_str() {
+            "" => (false, false),
+            "u" => (true, false),
+            "l" | "ll" => (false, true),
+            "ul" | "lu" | "ull" | "llu" => (true, true),
+            _ => return Err(t.bad_token(&format!("invalid suffix '{}'", t.int_suffix()))),
+        };
+        let is_decimal = !t.tokstr().starts_with('0');
+
+        let fits_int = i32::try_from(val).is_ok();
+        let fits_uint = u32::try_from(val).is_ok();
+        let ty = if !is_long && !is_unsigned && fits_int {
+            Type::int_ty()
+        } else if !is_long && (is_unsigned || !is_decimal) && fits_uint {
+            Type::int_ty().to_unsigned()
+        } else if !is_unsigned && val >= 0 {
+            Type::long_ty()
+        } else {
+            Type::long_ty().to_unsigned()
+        };
+        Ok(ty)
+    }
+
+    fn primary(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
         self.pos += 1;
         match t.ty {
-            TokenType::Num(val) => Node::new_num(val),
+            TokenType::Num(val) => {
+                let mut node = Node::new_num(val).at(t);
+                node.ty = Box::new(Self::literal_type(t, val)?);
+                Ok(node)
+            }
             TokenType::Str(ref str, len) => {
-                let mut node = Node::new(NodeType::Str(str.clone(), len));
+                let mut node = Node::new(NodeType::Str(str.clone(), len)).at(t);
                 node.ty = Box::new(Type::ary_of(Box::new(Type::char_ty()), len));
-                node
+                Ok(node)
             }
             TokenType::Ident(ref name) => {
                 if !self.consume(TokenType::LeftParen) {
-                    return Node::new(NodeType::Ident(name.clone()));
+                    if let Some(val) = self.find_enum(name).filter(|_| self.in_const_expr) {
+                        let mut node = Node::new_num(val).at(t);
+                        node.ty = Box::new(Type::int_ty());
+                        return Ok(node);
+                    }
+                    return Ok(Node::new(NodeType::Ident(name.clone())).at(t));
                 }
 
                 let mut args = vec![];
                 if self.consume(TokenType::RightParen) {
-                    return Node::new(NodeType::Call(name.clone(), args));
+                    return Ok(Node::new(NodeType::Call(name.clone(), args)).at(t));
                 }
 
-                args.push(self.assign());
+                args.push(self.assign()?);
                 while self.consume(TokenType::Comma) {
-                    args.push(self.assign());
+                    args.push(self.assign()?);
                 }
-                self.expect(TokenType::RightParen);
-                Node::new(NodeType::Call(name.clone(), args))
+                self.expect(TokenType::RightParen)?;
+                Ok(Node::new(NodeType::Call(name.clone(), args)).at(t))
             }
             TokenType::LeftParen => {
                 if self.consume(TokenType::LeftBrace) {
-                    let stmt = Box::new(self.compound_stmt());
-                    self.expect(TokenType::RightParen);
-                    return Node::new(NodeType::StmtExpr(stmt));
+                    let stmt = Box::new(self.compound_stmt()?);
+                    self.expect(TokenType::RightParen)?;
+                    return Ok(Node::new(NodeType::StmtExpr(stmt)).at(t));
                 }
-                let node = self.expr();
-                self.expect(TokenType::RightParen);
-                node
+                let node = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                Ok(node)
+            }
+            _ => {
+                self.pos -= 1;
+                Err(t.bad_token("number expected"))
             }
-            _ => t.bad_token("number expected"),
         }
     }
 
-    fn postfix(&mut self) -> Node {
-        let mut lhs = self.primary();
+    fn postfix(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.primary()?;
 
         loop {
             if self.consume(TokenType::Inc) {
-                lhs = new_expr!(NodeType::PostInc, lhs);
+                lhs = new_expr!(NodeType::PostInc, lhs).at(self.prev());
                 continue;
             }
 
             if self.consume(TokenType::Dec) {
-                lhs = new_expr!(NodeType::PostDec, lhs);
+                lhs = new_expr!(NodeType::PostDec, lhs).at(self.prev());
                 continue;
             }
 
             if self.consume(TokenType::Dot) {
                 // TODO: Use new_expr!
-                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident(), 0));
+                let t = self.prev();
+                lhs = Node::new(NodeType::Dot(Box::new(lhs), self.ident()?, 0)).at(t);
                 continue;
             }
 
             if self.consume(TokenType::Arrow) {
+                let t = self.prev();
                 lhs = Node::new(NodeType::Dot(
-                    Box::new(new_expr!(NodeType::Deref, lhs)),
-                    self.ident(),
+                    Box::new(new_expr!(NodeType::Deref, lhs).at(t)),
+                    self.ident()?,
                     0,
-                ));
+                ))
+                .at(t);
                 continue;
             }
 
             if self.consume(TokenType::LeftBracket) {
+                let t = self.prev();
                 lhs = new_expr!(
                     NodeType::Deref,
-                    Node::new_binop(TokenType::Plus, lhs, self.assign())
-                );
-                self.expect(TokenType::RightBracket);
+                    Node::new_binop(TokenType::Plus, lhs, self.assign()?).at(t)
+                )
+                .at(t);
+                self.expect(TokenType::RightBracket)?;
                 continue;
             }
-            return lhs;
+            return Ok(lhs);
         }
     }
 
-    fn unary(&mut self) -> Node {
+    // Returns true if a parenthesized type name follows, as in a cast.
+    fn is_paren_typename(&self) -> bool {
+        let is_lparen = self.tokens.get(self.pos).map(|t| &t.ty) == Some(&TokenType::LeftParen);
+        is_lparen
+            && self
+                .tokens
+                .get(self.pos + 1)
+                .is_some_and(|t| self.is_typename(t))
+    }
+
+    fn size_t(val: usize) -> Node {
+        let mut node = Node::new_num(val as i64);
+        node.ty = Box::new(Type::long_ty().to_unsigned());
+        node
+    }
+
+    // `sizeof` and `_Alignof` take either an expression or a type name.
+    // The type of a type name is carried by a Null node.
+    fn sizeof_operand(&mut self) -> Result<Node, Diagnostic> {
+        if !self.is_paren_typename() {
+            return self.unary();
+        }
+        let t = self.peek()?;
+        self.pos += 1;
+        let mut node = Node::new(NodeType::Null).at(t);
+        node.ty = Box::new(self.type_name()?);
+        self.expect(TokenType::RightParen)?;
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
+        if self.is_paren_typename() {
+            self.pos += 1;
+            let ty = self.type_name()?;
+            self.expect(TokenType::RightParen)?;
+            let mut node = new_expr!(NodeType::Cast, self.unary()?).at(t);
+            node.ty = Box::new(ty);
+            return Ok(node);
+        }
         if self.consume(TokenType::Minus) {
-            return new_expr!(NodeType::Neg, self.unary());
+            return Ok(new_expr!(NodeType::Neg, self.unary()?).at(t));
+        }
+        if self.consume(TokenType::Plus) {
+            return Ok(new_expr!(NodeType::UnaryPlus, self.unary()?).at(t));
+        }
+        if self.consume(TokenType::Tilde) {
+            return Ok(new_expr!(NodeType::BitNot, self.unary()?).at(t));
         }
         if self.consume(TokenType::Mul) {
-            return new_expr!(NodeType::Deref, self.unary());
+            return Ok(new_expr!(NodeType::Deref, self.unary()?).at(t));
         }
         if self.consume(TokenType::And) {
-            return new_expr!(NodeType::Addr, self.unary());
+            return Ok(new_expr!(NodeType::Addr, self.unary()?).at(t));
         }
         if self.consume(TokenType::Exclamation) {
-            return new_expr!(NodeType::Exclamation, self.unary());
+            return Ok(new_expr!(NodeType::Exclamation, self.unary()?).at(t));
         }
+        // The size and alignment of a type name are known here, which
+        // lets them appear in array sizes. Those of an expression, or of
+        // an array of unknown length, which is an error, are left to
+        // sema.
         if self.consume(TokenType::Sizeof) {
-            return new_expr!(NodeType::Sizeof, self.unary());
+            let expr = self.sizeof_operand()?;
+            if expr.is_null() && !expr.ty.is_incomplete_array() {
+                return Ok(Self::size_t(expr.ty.size).at(t));
+            }
+            return Ok(new_expr!(NodeType::Sizeof, expr).at(t));
         }
         if self.consume(TokenType::Alignof) {
-            return new_expr!(NodeType::Alignof, self.unary());
+            let expr = self.sizeof_operand()?;
+            if expr.is_null() && !expr.ty.is_incomplete_array() {
+                return Ok(Self::size_t(expr.ty.align).at(t));
+            }
+            return Ok(new_expr!(NodeType::Alignof, expr).at(t));
         }
 
         if self.consume(TokenType::Inc) {
-            return Node::new_binop(TokenType::AddEQ, self.unary(), Node::new_num(1));
+            let one = Node::new_num(1).at(t);
+            return Ok(Node::new_binop(TokenType::AddEQ, self.unary()?, one).at(t));
         }
         if self.consume(TokenType::Dec) {
-            return Node::new_binop(TokenType::SubEQ, self.unary(), Node::new_num(1));
+            let one = Node::new_num(1).at(t);
+            return Ok(Node::new_binop(TokenType::SubEQ, self.unary()?, one).at(t));
         }
 
         self.postfix()
     }
 
-    fn mul(&mut self) -> Node {
-        let mut lhs = self.unary();
+    fn mul(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.unary()?;
 
         loop {
             if self.consume(TokenType::Mul) {
-                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::Mul, lhs, self.unary()?).at(t);
             } else if self.consume(TokenType::Div) {
-                lhs = Node::new_binop(TokenType::Div, lhs, self.unary());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::Div, lhs, self.unary()?).at(t);
             } else if self.consume(TokenType::Mod) {
-                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::Mod, lhs, self.unary()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn add(&mut self) -> Node {
-        let mut lhs = self.mul();
+    fn add(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.mul()?;
 
         loop {
             if self.consume(TokenType::Plus) {
-                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::Plus, lhs, self.mul()?).at(t);
             } else if self.consume(TokenType::Minus) {
-                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::Minus, lhs, self.mul()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn shift(&mut self) -> Node {
-        let mut lhs = self.add();
+    fn shift(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.add()?;
         loop {
             if self.consume(TokenType::SHL) {
-                lhs = Node::new_binop(TokenType::SHL, lhs, self.add());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::SHL, lhs, self.add()?).at(t);
             } else if self.consume(TokenType::SHR) {
-                lhs = Node::new_binop(TokenType::SHR, lhs, self.add());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::SHR, lhs, self.add()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn relational(&mut self) -> Node {
-        let mut lhs = self.shift();
+    fn relational(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.shift()?;
         loop {
             if self.consume(TokenType::LeftAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::LeftAngleBracket, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::RightAngleBracket) {
-                lhs = Node::new_binop(TokenType::LeftAngleBracket, self.shift(), lhs);
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::RightAngleBracket, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::LE) {
-                lhs = Node::new_binop(TokenType::LE, lhs, self.shift())
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::LE, lhs, self.shift()?).at(t);
             } else if self.consume(TokenType::GE) {
-                lhs = Node::new_binop(TokenType::LE, self.shift(), lhs);
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::GE, lhs, self.shift()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn equality(&mut self) -> Node {
-        let mut lhs = self.relational();
+    fn equality(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.relational()?;
         loop {
             if self.consume(TokenType::EQ) {
-                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::EQ, lhs, self.relational()?).at(t);
             } else if self.consume(TokenType::NE) {
-                lhs = Node::new_binop(TokenType::NE, lhs, self.relational());
+                let t = self.prev();
+                lhs = Node::new_binop(TokenType::NE, lhs, self.relational()?).at(t);
             } else {
-                return lhs;
+                return Ok(lhs);
             }
         }
     }
 
-    fn bit_and(&mut self) -> Node {
-        let mut lhs = self.equality();
+    fn bit_and(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.equality()?;
         while self.consume(TokenType::And) {
-            lhs = Node::new_binop(TokenType::And, lhs, self.equality());
+            let t = self.prev();
+            lhs = Node::new_binop(TokenType::And, lhs, self.equality()?).at(t);
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn bit_xor(&mut self) -> Node {
-        let mut lhs = self.bit_and();
+    fn bit_xor(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.bit_and()?;
         while self.consume(TokenType::Hat) {
-            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and());
+            let t = self.prev();
+            lhs = Node::new_binop(TokenType::Hat, lhs, self.bit_and()?).at(t);
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn bit_or(&mut self) -> Node {
-        let mut lhs = self.bit_xor();
+    fn bit_or(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.bit_xor()?;
         while self.consume(TokenType::VerticalBar) {
-            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor());
+            let t = self.prev();
+            lhs = Node::new_binop(TokenType::VerticalBar, lhs, self.bit_xor()?).at(t);
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn logand(&mut self) -> Node {
-        let mut lhs = self.bit_or();
+    fn logand(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.bit_or()?;
         while self.consume(TokenType::Logand) {
-            lhs = Node::new_binop(TokenType::Logand, lhs, self.logand());
+            let t = self.prev();
+            lhs = Node::new_binop(TokenType::Logand, lhs, self.logand()?).at(t);
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn logor(&mut self) -> Node {
-        let mut lhs = self.logand();
+    fn logor(&mut self) -> Result<Node, Diagnostic> {
+        let mut lhs = self.logand()?;
         while self.consume(TokenType::Logor) {
-            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand());
+            let t = self.prev();
+            lhs = Node::new_binop(TokenType::Logor, lhs, self.logand()?).at(t);
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn conditional(&mut self) -> Node {
-        let cond = self.logor();
+    fn conditional(&mut self) -> Result<Node, Diagnostic> {
+        let cond = self.logor()?;
         if !self.consume(TokenType::Question) {
-            return cond;
+            return Ok(cond);
         }
-        let then = self.expr();
-        self.expect(TokenType::Colon);
-        let els = self.conditional();
-        Node::new(NodeType::Ternary(
+        let t = self.prev();
+        let then = self.expr()?;
+        self.expect(TokenType::Colon)?;
+        let els = self.conditional()?;
+        Ok(Node::new(NodeType::Ternary(
             Box::new(cond),
             Box::new(then),
             Box::new(els),
         ))
+        .at(t))
     }
 
     fn assign_op(ty: &TokenType) -> Option<&TokenType> {
@@ -576,81 +1090,157 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn assign(&mut self) -> Node {
-        let lhs = self.conditional();
-        if let Some(op) = Self::assign_op(&self.tokens[self.pos].ty) {
+    fn assign(&mut self) -> Result<Node, Diagnostic> {
+        let lhs = self.conditional()?;
+        if let Some(op) = self
+            .tokens
+            .get(self.pos)
+            .and_then(|t| Self::assign_op(&t.ty))
+        {
             self.pos += 1;
-            Node::new_binop(op.clone(), lhs, self.assign())
+            let t = self.prev();
+            Ok(Node::new_binop(op.clone(), lhs, self.assign()?).at(t))
         } else {
-            lhs
+            Ok(lhs)
         }
     }
 
-    fn expr(&mut self) -> Node {
-        let lhs = self.assign();
+    fn expr(&mut self) -> Result<Node, Diagnostic> {
+        let lhs = self.assign()?;
         if !self.consume(TokenType::Comma) {
-            return lhs;
+            return Ok(lhs);
         }
-        Node::new_binop(TokenType::Comma, lhs, self.expr())
+        let t = self.prev();
+        Ok(Node::new_binop(TokenType::Comma, lhs, self.expr()?).at(t))
     }
 
-    fn ctype(&mut self) -> Type {
-        let t = &self.tokens[self.pos];
-        if let Some(mut ty) = self.decl_specifiers() {
+    fn ctype(&mut self) -> Result<Type, Diagnostic> {
+        let t = self.peek()?;
+        if let Some(mut ty) = self.decl_specifiers()? {
             while self.consume(TokenType::Mul) {
                 ty = Type::ptr_to(Box::new(ty));
             }
-            ty
+            Ok(ty)
         } else {
-            t.bad_token("typename expected");
+            Err(t.bad_token("typename expected"))
+        }
+    }
+
+    // Reads a type without a declared name, as in `sizeof(int *[3])`.
+    fn type_name(&mut self) -> Result<Type, Diagnostic> {
+        let t = self.peek()?;
+        let ty = self
+            .decl_specifiers()?
+            .ok_or_else(|| t.bad_token("typename expected"))?;
+        self.abstract_declarator(ty)
+    }
+
+    // Like a declarator, but with the name left out. `int (*)[3]` is a
+    // pointer to an array of three ints.
+    fn abstract_declarator(&mut self, mut ty: Type) -> Result<Type, Diagnostic> {
+        while self.consume(TokenType::Mul) {
+            ty = Type::ptr_to(Box::new(ty));
+        }
+        let is_nested = self.tokens.get(self.pos).map(|t| &t.ty) == Some(&TokenType::LeftParen)
+            && matches!(
+                self.tokens.get(self.pos + 1).map(|t| &t.ty),
+                Some(TokenType::Mul) | Some(TokenType::LeftParen) | Some(TokenType::LeftBracket)
+            );
+        if !is_nested {
+            return self.read_array(Box::new(ty));
         }
+
+        self.pos += 1;
+        let mut inner = Box::new(self.abstract_declarator(Type::default())?);
+        self.expect(TokenType::RightParen)?;
+        let ty = self.read_array(Box::new(ty))?;
+        self.update_ptr_to(&mut inner, Box::new(ty));
+        Ok(*inner)
     }
 
-    fn read_array(&mut self, mut ty: Box<Type>) -> Type {
+    fn read_array(&mut self, mut ty: Box<Type>) -> Result<Type, Diagnostic> {
         let mut v: Vec<usize> = vec![];
         while self.consume(TokenType::LeftBracket) {
+            let t = self.peek()?;
             if self.consume(TokenType::RightBracket) {
-                v.push(0); // temporary value
+                // Only the outermost length may be left out.
+                if !v.is_empty() {
+                    return Err(t.bad_token("array has incomplete element type"));
+                }
+                v.push(0);
                 continue;
             }
 
-            let len = self.expr();
-            if let NodeType::Num(n) = len.op {
-                v.push(n as usize);
-                self.expect(TokenType::RightBracket);
-            } else {
-                panic!("number expected");
+            match self.const_expr()? {
+                Some(n) if n >= 0 => v.push(n as usize),
+                Some(_) => return Err(t.bad_token("array size is negative")),
+                None => return Err(t.bad_token("constant expression expected")),
             }
+            self.expect(TokenType::RightBracket)?;
         }
 
         v.reverse();
         for val in v {
             ty = Box::new(Type::ary_of(ty, val));
         }
-        *ty
+        Ok(*ty)
     }
 
-    fn array_init_rval(&mut self, ident: Node) -> Node {
-        let mut init = vec![];
-        let mut i = 0;
-        loop {
-            let val = self.primary();
-            let node = new_expr!(
-                NodeType::Deref,
-                Node::new_binop(TokenType::Plus, ident.clone(), Node::new(NodeType::Num(i)))
-            );
-            init.push(Node::new(NodeType::ExprStmt(Box::new(Node::new_binop(
-                TokenType::Equal,
-                node,
-                val,
-            )))));
+    // Reads a constant expression and returns its value, if it has one.
+    // Only here do enumerators stand for their values in the parser,
+    // unless a variable in a nearer scope hides them. Elsewhere they are
+    // left for sema to resolve.
+    fn const_expr(&mut self) -> Result<Option<i64>, Diagnostic> {
+        let in_const_expr = std::mem::replace(&mut self.in_const_expr, true);
+        let node = self.conditional();
+        self.in_const_expr = in_const_expr;
+        Ok(const_value(&fold(node?)))
+    }
+
+    // Reads an initializer: an expression, or a list of initializers in
+    // braces that may end with a comma.
+    fn initializer(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
+        if !self.consume(TokenType::LeftBrace) {
+            return self.assign();
+        }
+        let mut inits = vec![];
+        while !self.consume(TokenType::RightBrace) {
+            inits.push(self.designation()?);
             if !self.consume(TokenType::Comma) {
+                self.expect(TokenType::RightBrace)?;
                 break;
             }
-            i += 1;
         }
-        self.expect(TokenType::RightBrace);
-        Node::new(NodeType::VecStmt(init))
+        Ok(Node::new(NodeType::InitList(inits)).at(t))
+    }
+
+    // Reads an element of an initializer list, which may start with a
+    // chain of designators, as in `.pos[1] = 3`.
+    fn designation(&mut self) -> Result<Node, Diagnostic> {
+        let start = self.peek()?;
+        let mut desigs = vec![];
+        loop {
+            if self.consume(TokenType::Dot) {
+                desigs.push(Designator::Member(self.ident()?));
+            } else if self.consume(TokenType::LeftBracket) {
+                let t = self.peek()?;
+                match self.const_expr()? {
+                    Some(n) if n >= 0 => desigs.push(Designator::Index(n as usize)),
+                    Some(_) => return Err(t.bad_token("array designator value is negative")),
+                    None => return Err(t.bad_token("constant expression expected")),
+                }
+                self.expect(TokenType::RightBracket)?;
+            } else {
+                break;
+            }
+        }
+        if desigs.is_empty() {
+            return self.initializer();
+        }
+        self.expect(TokenType::Equal)?;
+        let init = Box::new(self.initializer()?);
+        Ok(Node::new(NodeType::Designation(desigs, init)).at(start))
     }
 
     fn update_ptr_to(&mut self, src: &mut Box<Type>, dst: Box<Type>) {
@@ -660,255 +1250,355 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn direct_decl(&mut self, ty: Box<Type>) -> Node {
-        let t = &self.tokens[self.pos];
+    fn direct_decl(&mut self, ty: Box<Type>) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
         let mut placeholder = Box::new(Type::default());
         let mut node;
 
         if let TokenType::Ident(_) = t.ty {
-            node = Node::new(NodeType::Vardef(self.ident(), None, Scope::Local(0)));
+            node = Node::new(NodeType::Vardef(self.ident()?, None, Scope::Local(0))).at(t);
         } else if self.consume(TokenType::LeftParen) {
-            node = self.declarator(&mut placeholder);
-            self.expect(TokenType::RightParen);
+            node = self.declarator(&mut placeholder)?;
+            self.expect(TokenType::RightParen)?;
         } else {
-            t.bad_token("bad direct-declarator");
+            return Err(t.bad_token("bad direct-declarator"));
         }
 
         // Read the second half of type name (e.g. `[3][5]`).
-        let ty = self.read_array(ty);
+        let ty = self.read_array(ty)?;
         self.update_ptr_to(&mut node.ty, Box::new(ty));
 
         // Read an initializer.
-        let init: Option<Box<Node>>;
         if self.consume(TokenType::Equal) {
-            // Assign a value when initializing an array.
-            if let TokenType::Ident(ref name) = t.ty {
-                if self.consume(TokenType::LeftBrace) {
-                    let mut stmts = vec![];
-                    let mut ary_declaration =
-                        Node::new(NodeType::Vardef(name.clone(), None, Scope::Local(0)));
-                    ary_declaration.ty = node.ty;
-                    stmts.push(ary_declaration);
-                    let init_ary = self.array_init_rval(Node::new(NodeType::Ident(name.clone())));
-                    stmts.push(init_ary);
-                    return Node::new(NodeType::VecStmt(stmts));
-                }
-            }
-
-            init = Some(Box::new(self.assign()));
+            let init = Some(Box::new(self.initializer()?));
             match node.op {
                 NodeType::Vardef(_, ref mut init2, _) => *init2 = init,
                 _ => unreachable!(),
             }
         }
-        node
+        Ok(node)
     }
 
-    fn declarator(&mut self, ty: &mut Type) -> Node {
+    fn declarator(&mut self, ty: &mut Type) -> Result<Node, Diagnostic> {
         while self.consume(TokenType::Mul) {
             *ty = Type::ptr_to(Box::new(ty.clone()));
         }
         self.direct_decl(Box::new(ty.clone()))
     }
 
-    fn declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let node = self.declarator(&mut ty);
-        self.expect(TokenType::Semicolon);
-        node
+    fn declaration(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
+        let mut ty = self
+            .decl_specifiers()?
+            .ok_or_else(|| t.bad_token("typename expected"))?;
+        // A declaration of nothing but a tag or enumerators.
+        if self.consume(TokenType::Semicolon) {
+            return Ok(Node::new(NodeType::Null).at(t));
+        }
+        let node = self.declarator(&mut ty)?;
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
     }
 
-    fn param_declaration(&mut self) -> Node {
-        let mut ty = self.decl_specifiers().unwrap();
-        let mut node = self.declarator(&mut ty);
+    // Reads the declaration of a local variable, which hides any
+    // enumerator of the same name from here on.
+    fn local_declaration(&mut self) -> Result<Node, Diagnostic> {
+        let node = self.declaration()?;
+        if let NodeType::Vardef(ref name, _, _) = node.op {
+            self.env.vars.insert(name.clone(), None);
+        }
+        Ok(node)
+    }
+
+    // Parameter names may be left out, as in `int puts(char *);`.
+    fn param_declaration(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
+        let mut ty = self
+            .decl_specifiers()?
+            .ok_or_else(|| t.bad_token("typename expected"))?;
+        while self.consume(TokenType::Mul) {
+            ty = Type::ptr_to(Box::new(ty));
+        }
+        let mut node = match self.tokens.get(self.pos).map(|t| &t.ty) {
+            Some(TokenType::Comma) | Some(TokenType::RightParen) => {
+                let mut node = Node::new(NodeType::Vardef(String::new(), None, Scope::Local(0)));
+                node.ty = Box::new(ty);
+                node.at(t)
+            }
+            _ => self.declarator(&mut ty)?,
+        };
         if let Ctype::Ary(ary_of, _) = node.ty.ty {
             node.ty = Box::new(Type::ptr_to(ary_of));
         }
-        node
+        Ok(node)
     }
 
-    fn expr_stmt(&mut self) -> Node {
-        let expr = self.expr();
-        let node = new_expr!(NodeType::ExprStmt, expr);
-        self.expect(TokenType::Semicolon);
-        node
+    // Reads a parameter list after `(`. Returns the parameters and the
+    // type of a function returning `returning`. `()` leaves the
+    // parameters unspecified, unlike `(void)`.
+    fn params(&mut self, returning: Type) -> Result<(Vec<Node>, Type), Diagnostic> {
+        let func_ty = |params, is_variadic| {
+            Type::new(Ctype::Func(Box::new(returning), params, is_variadic), 0)
+        };
+        let mut args = vec![];
+        if self.consume(TokenType::RightParen) {
+            return Ok((args, func_ty(None, false)));
+        }
+        let is_void = |t: Option<&Token>| t.map(|t| &t.ty) == Some(&TokenType::Void);
+        let is_rparen = |t: Option<&Token>| t.map(|t| &t.ty) == Some(&TokenType::RightParen);
+        if is_void(self.tokens.get(self.pos)) && is_rparen(self.tokens.get(self.pos + 1)) {
+            self.pos += 2;
+            return Ok((args, func_ty(Some(vec![]), false)));
+        }
+
+        let mut is_variadic = false;
+        loop {
+            if !args.is_empty() && self.consume(TokenType::Ellipsis) {
+                is_variadic = true;
+                self.expect(TokenType::RightParen)?;
+                break;
+            }
+            args.push(self.param_declaration()?);
+            if self.consume(TokenType::RightParen) {
+                break;
+            }
+            self.expect(TokenType::Comma)?;
+        }
+        let params = args.iter().map(|arg| (*arg.ty).clone()).collect();
+        Ok((args, func_ty(Some(params), is_variadic)))
     }
 
-    fn stmt(&mut self) -> Node {
-        let t = &self.tokens[self.pos];
+    fn expr_stmt(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
+        let expr = self.expr()?;
+        let node = new_expr!(NodeType::ExprStmt, expr).at(t);
+        self.expect(TokenType::Semicolon)?;
+        Ok(node)
+    }
+
+    fn stmt(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.peek()?;
         self.pos += 1;
 
-        match t.ty {
+        let node = match t.ty {
             TokenType::Typedef => {
-                let node = self.declaration();
+                let node = self.declaration()?;
                 if let NodeType::Vardef(name, _, _) = node.op {
                     self.env.typedefs.insert(name, *node.ty);
-                    return Node::new(NodeType::Null);
+                    Node::new(NodeType::Null)
                 } else {
                     unreachable!();
                 }
             }
             TokenType::If => {
                 let mut els = None;
-                self.expect(TokenType::LeftParen);
-                let cond = self.expr();
-                self.expect(TokenType::RightParen);
-                let then = self.stmt();
+                self.expect(TokenType::LeftParen)?;
+                let cond = self.expr()?;
+                self.expect(TokenType::RightParen)?;
+                let then = self.stmt()?;
                 if self.consume(TokenType::Else) {
-                    els = Some(Box::new(self.stmt()));
+                    els = Some(Box::new(self.stmt()?));
                 }
                 Node::new(NodeType::If(Box::new(cond), Box::new(then), els))
             }
-            TokenType::For => {
-                self.expect(TokenType::LeftParen);
+            // Like a block, the loop is a scope of its own.
+            TokenType::For => self.in_scope(|p| {
+                p.expect(TokenType::LeftParen)?;
 
-                let init: Box<Node> = if self.is_typename(&self.tokens[self.pos]) {
-                    Box::new(self.declaration())
-                } else if self.consume(TokenType::Semicolon) {
+                let init: Box<Node> = if p.is_typename(p.peek()?) {
+                    Box::new(p.local_declaration()?)
+                } else if p.consume(TokenType::Semicolon) {
                     Box::new(Node::new(NodeType::Null))
                 } else {
-                    Box::new(self.expr_stmt())
+                    Box::new(p.expr_stmt()?)
                 };
 
                 let cond;
-                if !self.consume(TokenType::Semicolon) {
-                    cond = Box::new(self.expr());
-                    self.expect(TokenType::Semicolon);
+                if !p.consume(TokenType::Semicolon) {
+                    cond = Box::new(p.expr()?);
+                    p.expect(TokenType::Semicolon)?;
                 } else {
                     cond = Box::new(Node::new(NodeType::Null))
                 }
 
                 let inc;
-                if !self.consume(TokenType::RightParen) {
-                    inc = Box::new(new_expr!(NodeType::ExprStmt, self.expr()));
-                    self.expect(TokenType::RightParen);
+                if !p.consume(TokenType::RightParen) {
+                    let t = p.peek()?;
+                    inc = Box::new(new_expr!(NodeType::ExprStmt, p.expr()?).at(t));
+                    p.expect(TokenType::RightParen)?;
                 } else {
                     inc = Box::new(Node::new(NodeType::Null))
                 }
 
-                let body = Box::new(self.stmt());
-                Node::new(NodeType::For(init, cond, inc, body))
-            }
+                let body = Box::new(p.stmt()?);
+                Ok(Node::new(NodeType::For(init, cond, inc, body)))
+            })?,
             TokenType::While => {
-                self.expect(TokenType::LeftParen);
+                self.expect(TokenType::LeftParen)?;
                 let init = Box::new(Node::new(NodeType::Null));
                 let inc = Box::new(Node::new(NodeType::Null));
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                let body = Box::new(self.stmt());
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                let body = Box::new(self.stmt()?);
                 Node::new(NodeType::For(init, cond, inc, body))
             }
             TokenType::Do => {
-                let body = Box::new(self.stmt());
-                self.expect(TokenType::While);
-                self.expect(TokenType::LeftParen);
-                let cond = Box::new(self.expr());
-                self.expect(TokenType::RightParen);
-                self.expect(TokenType::Semicolon);
+                let body = Box::new(self.stmt()?);
+                self.expect(TokenType::While)?;
+                self.expect(TokenType::LeftParen)?;
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::DoWhile(body, cond))
             }
+            TokenType::Switch => {
+                self.expect(TokenType::LeftParen)?;
+                let cond = Box::new(self.expr()?);
+                self.expect(TokenType::RightParen)?;
+                let body = Box::new(self.stmt()?);
+                Node::new(NodeType::Switch(cond, body, vec![], false))
+            }
+            TokenType::Case => {
+                let val = Box::new(self.conditional()?);
+                self.expect(TokenType::Colon)?;
+                let body = Box::new(self.stmt()?);
+                Node::new(NodeType::Case(val, body, 0))
+            }
+            TokenType::Default => {
+                self.expect(TokenType::Colon)?;
+                new_expr!(NodeType::Default, self.stmt()?)
+            }
             TokenType::Break => {
-                self.expect(TokenType::Semicolon);
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::Break)
             }
+            TokenType::Continue => {
+                self.expect(TokenType::Semicolon)?;
+                Node::new(NodeType::Continue)
+            }
+            TokenType::Goto => {
+                let t = self.peek()?;
+                let name = match t.ty {
+                    TokenType::Ident(ref name) => name.clone(),
+                    _ => return Err(t.bad_token("label name expected")),
+                };
+                self.pos += 1;
+                self.expect(TokenType::Semicolon)?;
+                Node::new(NodeType::Goto(name))
+            }
             TokenType::Return => {
-                let expr = self.expr();
-                self.expect(TokenType::Semicolon);
+                let expr = self.expr()?;
+                self.expect(TokenType::Semicolon)?;
                 Node::new(NodeType::Return(Box::new(expr)))
             }
-            TokenType::LeftBrace => {
-                let mut stmts = vec![];
-                while !self.consume(TokenType::RightBrace) {
-                    stmts.push(self.stmt());
-                }
-                Node::new(NodeType::CompStmt(stmts))
-            }
+            TokenType::LeftBrace => self.compound_stmt()?,
             TokenType::Semicolon => Node::new(NodeType::Null),
+            TokenType::Ident(ref name) if self.consume(TokenType::Colon) => {
+                Node::new(NodeType::Label(name.clone(), Box::new(self.stmt()?)))
+            }
             _ => {
                 self.pos -= 1;
-                if self.is_typename(&self.tokens[self.pos]) {
-                    return self.declaration();
+                if self.is_typename(t) {
+                    return self.local_declaration();
                 }
-                self.expr_stmt()
+                return self.expr_stmt();
             }
-        }
+        };
+        Ok(node.at(t))
     }
 
-    fn compound_stmt(&mut self) -> Node {
-        let mut stmts = vec![];
-
+    // Runs `f` in a new scope, which is left even if `f` fails.
+    fn in_scope<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, Diagnostic>,
+    ) -> Result<T, Diagnostic> {
         let new_env = Env::new(Some(Box::new(self.env.clone())));
-        self.env = new_env;
-        while !self.consume(TokenType::RightBrace) {
-            stmts.push(self.stmt());
-        }
-        let next = self.env.next.clone();
-        self.env = *next.unwrap();
-        Node::new(NodeType::CompStmt(stmts))
+        let env = std::mem::replace(&mut self.env, new_env);
+        let ret = f(self);
+        self.env = env;
+        ret
     }
 
-    fn toplevel(&mut self) -> Option<Node> {
+    fn compound_stmt(&mut self) -> Result<Node, Diagnostic> {
+        let t = self.prev();
+        self.in_scope(|p| {
+            let mut stmts = vec![];
+            while !p.consume(TokenType::RightBrace) {
+                stmts.push(p.block_item()?);
+            }
+            Ok(Node::new(NodeType::CompStmt(stmts)).at(t))
+        })
+    }
+
+    fn toplevel(&mut self) -> Result<Option<Node>, Diagnostic> {
         let is_typedef = self.consume(TokenType::Typedef);
         let is_extern = self.consume(TokenType::Extern);
 
-        let mut ty = self.ctype();
-        let t = &self.tokens[self.pos];
-        let name: String;
-        if let TokenType::Ident(ref name2) = t.ty {
-            name = name2.clone();
-        } else {
-            t.bad_token("function or variable name expected");
+        let mut ty = self.ctype()?;
+        if self.consume(TokenType::Semicolon) {
+            return Ok(None);
         }
+        let name_tok = self.peek()?;
+        let name = match name_tok.ty {
+            TokenType::Ident(ref name) => name.clone(),
+            _ => return Err(name_tok.bad_token("function or variable name expected")),
+        };
         self.pos += 1;
 
         // Function
         if self.consume(TokenType::LeftParen) {
-            let mut args = vec![];
-            if !self.consume(TokenType::RightParen) {
-                args.push(self.param_declaration());
-                while self.consume(TokenType::Comma) {
-                    args.push(self.param_declaration());
-                }
-                self.expect(TokenType::RightParen);
-            }
+            let (args, func_ty) = self.params(ty)?;
 
             if self.consume(TokenType::Semicolon) {
-                let mut node = Node::new(NodeType::Decl(name));
-                node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-                return Some(node);
+                let mut node = Node::new(NodeType::Decl(name)).at(name_tok);
+                node.ty = Box::new(func_ty);
+                return Ok(Some(node));
             }
 
-            let t = &self.tokens[self.pos];
-            self.expect(TokenType::LeftBrace);
+            let t = self.peek()?;
+            self.expect(TokenType::LeftBrace)?;
             if is_typedef {
-                t.bad_token("typedef {} has function definition");
+                return Err(t.bad_token(&format!("typedef {} has function definition", name)));
             }
-            let body = self.compound_stmt();
+            // The parameters hide enumerators in the body.
+            let body = self.in_scope(|p| {
+                for arg in &args {
+                    if let NodeType::Vardef(ref name, _, _) = arg.op {
+                        p.env.vars.insert(name.clone(), None);
+                    }
+                }
+                p.compound_stmt()
+            })?;
 
-            let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0));
-            node.ty = Box::new(Type::new(Ctype::Func(Box::new(ty)), 0));
-            return Some(node);
+            let mut node = Node::new(NodeType::Func(name, args, Box::new(body), 0)).at(name_tok);
+            node.ty = Box::new(func_ty);
+            return Ok(Some(node));
         }
 
-        ty = self.read_array(Box::new(ty));
-        self.expect(TokenType::Semicolon);
+        ty = self.read_array(Box::new(ty))?;
+        let mut init = None;
+        if !is_typedef && self.consume(TokenType::Equal) {
+            init = Some(Box::new(self.initializer()?));
+        }
+        self.expect(TokenType::Semicolon)?;
 
         if is_typedef {
             self.env.typedefs.insert(name.clone(), ty.clone());
-            return None;
+            return Ok(None);
         }
 
-        // Global variable
+        // Global variable. sema turns the initializer into data.
         let mut node = Node::new(NodeType::Vardef(
             name,
-            None,
+            init,
             if is_extern {
-                Scope::Global(String::new(), 0, true)
+                Scope::Global(Data::default(), 0, true)
             } else {
-                Scope::Global(String::new(), ty.size, false)
+                Scope::Global(Data::default(), ty.size, false)
             },
-        ));
+        ))
+        .at(name_tok);
         node.ty = Box::new(ty);
-        Some(node)
+        Ok(Some(node))
     }
 }
@@ -1,17 +1,20 @@
 // C preprocessor
 
-use crate::token::{tokenize, Token};
+use crate::diagnostic::Diagnostic;
+use crate::headers;
+use crate::token::{tokenize, tokenize_input, tokenize_str, Token};
 use crate::TokenType;
 
 use std::collections::HashMap;
 use std::mem;
+use std::path::Path;
 use std::rc::Rc;
 
-pub fn preprocess(tokens: Vec<Token>, ctx: &mut Preprocessor) -> Vec<Token> {
+pub fn preprocess(tokens: Vec<Token>, ctx: &mut Preprocessor) -> Result<Vec<Token>, Diagnostic> {
     ctx.preprocess_impl(tokens)
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Env {
     input: Vec<Token>,
     output: Vec<Token>,
@@ -19,17 +22,6 @@ pub struct Env {
     next: Option<Box<Env>>,
 }
 
-impl Default for Env {
-    fn default() -> Env {
-        Env {
-            input: vec![],
-            output: vec![],
-            pos: 0,
-            next: None,
-        }
-    }
-}
-
 impl Env {
     pub fn new(input: Vec<Token>, next: Option<Box<Env>>) -> Self {
         Env {
@@ -43,7 +35,7 @@ impl Env {
 #[derive(Debug, Clone)]
 enum MacroType {
     Objlike,
-    Funclike(Vec<String>),
+    Funclike(Vec<String>, bool), // (params, is variadic)
 }
 
 #[derive(Debug, Clone)]
@@ -59,7 +51,7 @@ impl Macro {
 
     fn replace_params(mut self) -> Self {
         match self.ty {
-            MacroType::Funclike(ref params) => {
+            MacroType::Funclike(ref params, _) => {
                 let mut map = HashMap::new();
                 for (i, item) in params.iter().enumerate() {
                     let name = item.clone();
@@ -127,25 +119,71 @@ impl Macro {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+// An open #if, #ifdef or #ifndef.
+#[derive(Debug, Clone)]
+struct CondIncl {
+    ctx: CondCtx,
+    included: bool, // Whether one of the groups has been taken
+    tok: Token,     // The directive, for error reporting
+}
+
+// Maximum depth of nested #include, as in GCC.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+// A file being included, with the index of the search path it was found
+// in, if any. #include_next resumes the search after that index. The
+// bundled headers count as one search path.
+#[derive(Debug, Clone)]
+struct IncludeFile {
+    path: String,
+    dir_idx: Option<usize>,
+    contents: Option<&'static str>, // Set for bundled headers
+}
+
 pub struct Preprocessor {
     macros: HashMap<String, Macro>,
+    cond_incl: Vec<CondIncl>,
+    include_paths: Vec<String>,        // -I directories
+    system_include_paths: Vec<String>, // Searched after include_paths
+    include_stack: Vec<IncludeFile>,
     pub env: Box<Env>,
 }
 
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Preprocessor {
     pub fn new() -> Self {
         Preprocessor {
             macros: HashMap::new(),
+            cond_incl: vec![],
+            include_paths: vec![],
+            system_include_paths: vec!["/usr/local/include".into(), "/usr/include".into()],
+            include_stack: vec![],
             env: Box::new(Env::new(vec![], None)),
         }
     }
 
+    pub fn add_include_path(&mut self, dir: String) {
+        self.include_paths.push(dir);
+    }
+
     fn next(&mut self) -> Option<Token> {
         if self.eof() {
             return None;
         }
         let pos = self.env.pos;
-        let t = Some(mem::replace(&mut self.env.input[pos], Token::default()));
+        let t = Some(mem::take(&mut self.env.input[pos]));
         self.env.pos += 1;
         t
     }
@@ -154,19 +192,25 @@ impl Preprocessor {
         self.env.pos == self.env.input.len()
     }
 
-    fn get(&mut self, ty: TokenType, msg: &str) -> Token {
-        let t = self.next().expect(msg);
+    // Like next(), but running out of input is an error.
+    fn next_or(&mut self, msg: &str) -> Result<Token, Diagnostic> {
+        self.next().ok_or_else(|| Diagnostic::error(msg))
+    }
+
+    fn get(&mut self, ty: TokenType, msg: &str) -> Result<Token, Diagnostic> {
+        let t = self.next_or(msg)?;
         if t.ty != ty {
-            t.bad_token(msg);
+            return Err(t.bad_token(msg));
         }
-        t
+        Ok(t)
     }
 
-    fn ident(&mut self, msg: &str) -> String {
-        let t = self.next().expect(msg);
+    fn ident(&mut self, msg: &str) -> Result<String, Diagnostic> {
+        let t = self.next_or(msg)?;
         match t.ty {
-            TokenType::Ident(s) | TokenType::Str(s, _) => s,
-            _ => t.bad_token(msg),
+            TokenType::Ident(s) => Ok(s),
+            TokenType::Str(s, _) => Ok(String::from_utf8_lossy(&s).into_owned()),
+            _ => Err(t.bad_token(msg)),
         }
     }
 
@@ -197,18 +241,18 @@ impl Preprocessor {
         v
     }
 
-    fn read_one_arg(&mut self) -> Vec<Token> {
+    // Reads a macro argument. If `read_rest` is true, commas don't end
+    // the argument, which is how __VA_ARGS__ is read.
+    fn read_one_arg(&mut self, read_rest: bool, start: &Token) -> Result<Vec<Token>, Diagnostic> {
         let mut v = vec![];
-        let msg = "unclosed macro argument";
-        let start = self.peek().expect(msg).clone();
         let mut level = 0;
 
-        while !self.eof() {
-            let t = self.peek().expect(msg).clone();
-            if level == 0 {
-                if t.ty == TokenType::RightParen || t.ty == TokenType::Comma {
-                    return v;
-                }
+        while let Some(t) = self.peek() {
+            let t = t.clone();
+            if level == 0
+                && (t.ty == TokenType::RightParen || (!read_rest && t.ty == TokenType::Comma))
+            {
+                return Ok(v);
             }
 
             self.next();
@@ -216,23 +260,43 @@ impl Preprocessor {
                 level += 1;
             } else if t.ty == TokenType::RightParen {
                 level -= 1;
+            } else if t.ty == TokenType::NewLine {
+                continue;
             }
             v.push(t);
         }
-        start.bad_token(msg);
+        Err(start.bad_token("unterminated argument list invoking macro"))
     }
 
-    fn read_args(&mut self) -> Vec<Vec<Token>> {
-        let mut v = vec![];
-        if self.consume(TokenType::RightParen) {
-            return v;
+    // Reads the arguments of a function-like macro call and returns them
+    // with the closing parenthesis.
+    fn read_args(
+        &mut self,
+        params: &[String],
+        is_variadic: bool,
+        start: &Token,
+    ) -> Result<(Vec<Vec<Token>>, Token), Diagnostic> {
+        let mut args = vec![];
+        let empty = params.is_empty() && self.peek().map(|t| &t.ty) == Some(&TokenType::RightParen);
+        if !empty {
+            loop {
+                let read_rest = is_variadic && args.len() + 1 == params.len();
+                args.push(self.read_one_arg(read_rest, start)?);
+                if !self.consume(TokenType::Comma) {
+                    break;
+                }
+            }
         }
-        v.push(self.read_one_arg());
-        while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            v.push(self.read_one_arg());
+        let rparen = self.get(TokenType::RightParen, "')' expected")?;
+
+        // __VA_ARGS__ may be left out entirely.
+        if is_variadic && args.len() + 1 == params.len() {
+            args.push(vec![]);
         }
-        v
+        if params.len() != args.len() {
+            return Err(start.bad_token("number of parameter does not match"));
+        }
+        Ok((args, rparen))
     }
 
     fn stringize(tokens: &[Token], filename: Rc<String>, buf: Rc<Vec<char>>) -> Token {
@@ -244,80 +308,226 @@ impl Preprocessor {
             sb.push_str(&t.tokstr());
         }
 
-        let len = sb.len();
-        Token::new(TokenType::Str(sb, len), 0, filename, buf)
+        let len = sb.len() + 1;
+        Token::new(TokenType::Str(sb.into_bytes(), len), 0, filename, buf)
     }
 
-    fn add_special_macro(&mut self, t: &Token) -> bool {
-        if t.is_ident("__LINE__") {
-            self.env.output.push(Token::new(
-                TokenType::Num(t.get_line_number() as i32),
-                0,
-                t.filename.clone(),
-                t.buf.clone(),
-            ));
-            true
-        } else {
-            false
+    // Concatenates two tokens and lexes the result again.
+    fn paste(lhs: &Token, rhs: &Token) -> Result<Token, Diagnostic> {
+        let s = format!("{}{}", lhs.tokstr(), rhs.tokstr());
+        let msg = format!("pasting forms '{}', an invalid token", s);
+        let mut v = tokenize_str(&s, lhs.filename.clone()).map_err(|_| lhs.bad_token(&msg))?;
+        if v.len() != 1 {
+            return Err(lhs.bad_token(&msg));
         }
+        let mut t = v.pop().unwrap();
+        t.hideset = lhs.hideset.clone();
+        Ok(t)
     }
 
-    fn apply_objlike(&mut self, tokens: Vec<Token>) {
-        for t in tokens {
-            if self.add_special_macro(&t) {
-                continue;
-            } else {
-                self.env.output.push(t);
+    // A body token as it is before macro expansion of arguments.
+    fn raw_tokens(t: &Token, args: &[Vec<Token>]) -> Vec<Token> {
+        match t.ty {
+            TokenType::Param(n) if t.stringize => {
+                vec![Self::stringize(&args[n], t.filename.clone(), t.buf.clone())]
             }
+            TokenType::Param(n) => args[n].clone(),
+            _ => vec![t.clone()],
         }
     }
 
-    fn apply_funclike(&mut self, tokens: Vec<Token>, params: &[String], start: &Token) {
-        self.get(TokenType::LeftParen, "comma expected");
-        let args = self.read_args();
-        if params.len() != args.len() {
-            start.bad_token("number of parameter does not match");
-        }
+    // Substitutes the parameters of a macro body. Operands of `#` and
+    // `##` are used as written; all other arguments are fully expanded
+    // first. Every resulting token is added to the hide-set `hs` and
+    // records `origin` as the invocation it came from.
+    fn subst(
+        &mut self,
+        body: &[Token],
+        args: &[Vec<Token>],
+        hs: &[String],
+        origin: &Rc<Token>,
+    ) -> Result<Vec<Token>, Diagnostic> {
+        let mut v = vec![];
+        let mut i = 0;
+        while i < body.len() {
+            let t = &body[i];
+            if t.ty == TokenType::HashHash {
+                return Err(t.bad_token("'##' cannot appear at either end of macro expansion"));
+            }
+
+            if body.get(i + 1).map(|t| &t.ty) == Some(&TokenType::HashHash) {
+                let mut lhs = Self::raw_tokens(t, args);
+                while body.get(i + 1).map(|t| &t.ty) == Some(&TokenType::HashHash) {
+                    let rhs = body.get(i + 2).ok_or_else(|| {
+                        body[i + 1].bad_token("'##' cannot appear at either end of macro expansion")
+                    })?;
+                    let mut rhs = Self::raw_tokens(rhs, args);
 
-        for t in tokens {
-            if self.add_special_macro(&t) {
+                    // An empty argument acts as a placemarker.
+                    if lhs.is_empty() {
+                        lhs = rhs;
+                    } else if !rhs.is_empty() {
+                        let last = lhs.pop().unwrap();
+                        lhs.push(Self::paste(&last, &rhs[0])?);
+                        lhs.extend(rhs.drain(1..));
+                    }
+                    i += 2;
+                }
+                v.extend(lhs);
+                i += 1;
                 continue;
             }
 
             match t.ty {
-                TokenType::Param(val) => {
-                    if t.stringize {
-                        self.env
-                            .output
-                            .push(Self::stringize(&args[val], t.filename, t.buf));
-                    } else {
-                        self.env.output.append(&mut args[val].clone());
-                    }
+                TokenType::Param(n) if !t.stringize => {
+                    let arg = self.expand(args[n].clone())?;
+                    v.extend(arg);
+                }
+                _ => v.extend(Self::raw_tokens(t, args)),
+            }
+            i += 1;
+        }
+
+        for t in v.iter_mut() {
+            t.origin = Some(origin.clone());
+            for name in hs {
+                if !t.hideset.contains(name) {
+                    t.hideset.push(name.clone());
                 }
-                _ => self.env.output.push(t),
             }
         }
+        Ok(v)
+    }
+
+    // Pushes tokens back to the input so that they are read next.
+    fn unget(&mut self, tokens: Vec<Token>) {
+        let pos = self.env.pos;
+        if tokens.len() <= pos {
+            // Reuse the slots of tokens that have already been read.
+            let start = pos - tokens.len();
+            for (slot, t) in self.env.input[start..pos].iter_mut().zip(tokens) {
+                *slot = t;
+            }
+            self.env.pos = start;
+        } else {
+            self.env.input.splice(pos..pos, tokens);
+        }
     }
 
-    fn apply(&mut self, m: Macro, start: &Token) {
+    fn add_special_macro(&mut self, t: &Token) -> bool {
+        if t.is_ident("__LINE__") {
+            let mut t2 = t.clone();
+            t2.ty = TokenType::Num(t.get_line_number() as i64);
+            self.env.output.push(t2);
+            true
+        } else {
+            false
+        }
+    }
+
+    // If a token is a macro name, expands it and pushes the result back
+    // to the input, so that it is rescanned. Returns false if the token
+    // is not a macro to be expanded.
+    //
+    // The algorithm is Dave Prosser's: each token carries a hide-set of
+    // the macros it came from, and is never expanded by a macro in it.
+    // That keeps self-referential macros from expanding forever.
+    fn expand_macro(&mut self, t: &Token) -> Result<bool, Diagnostic> {
+        let origin = t.origin.clone().unwrap_or_else(|| Rc::new(t.clone()));
+        if self.add_special_macro(t) {
+            return Ok(true);
+        }
+
+        let name = match t.ty {
+            TokenType::Ident(ref name) => name,
+            _ => return Ok(false),
+        };
+        if t.hideset.contains(name) {
+            return Ok(false);
+        }
+        let m = match self.macros.get(name) {
+            Some(m) => m.clone(),
+            None => return Ok(false),
+        };
+
         match m.ty {
-            MacroType::Objlike => self.apply_objlike(m.tokens),
-            MacroType::Funclike(ref params) => self.apply_funclike(m.tokens, params, start),
+            MacroType::Objlike => {
+                let mut hs = t.hideset.clone();
+                hs.push(name.clone());
+                let v = self.subst(&m.tokens, &[], &hs, &origin)?;
+                self.unget(v);
+            }
+            MacroType::Funclike(ref params, is_variadic) => {
+                // A function-like macro name not followed by '(' is an
+                // ordinary identifier. The '(' may be on a later line.
+                let newlines = self.env.input[self.env.pos..]
+                    .iter()
+                    .take_while(|t| t.ty == TokenType::NewLine)
+                    .count();
+                match self.env.input.get(self.env.pos + newlines) {
+                    Some(t) if t.ty == TokenType::LeftParen => {}
+                    _ => return Ok(false),
+                }
+                self.env.pos += newlines + 1;
+                let (args, rparen) = self.read_args(params, is_variadic, t)?;
+
+                let mut hs: Vec<String> = t
+                    .hideset
+                    .iter()
+                    .filter(|name| rparen.hideset.contains(name))
+                    .cloned()
+                    .collect();
+                hs.push(name.clone());
+                let v = self.subst(&m.tokens, &args, &hs, &origin)?;
+                self.unget(v);
+            }
         }
+        Ok(true)
+    }
+
+    // Fully macro-expands a list of tokens, such as a macro argument.
+    fn expand(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>, Diagnostic> {
+        let next = mem::take(&mut *self.env);
+        *self.env = Env::new(tokens, Some(Box::new(next)));
+        let res = self.expand_all();
+        let output = mem::take(&mut self.env.output);
+        self.env = self.env.next.take().unwrap();
+        res.map(|_| output)
     }
 
-    fn funclike_macro(&mut self, name: String) {
+    fn expand_all(&mut self) -> Result<(), Diagnostic> {
+        while let Some(t) = self.next() {
+            if !self.expand_macro(&t)? {
+                self.env.output.push(t);
+            }
+        }
+        Ok(())
+    }
+
+    fn funclike_macro(&mut self, name: String) -> Result<(), Diagnostic> {
         let mut params = vec![];
-        params.push(self.ident("parameter name expected"));
-        while !self.consume(TokenType::RightParen) {
-            self.get(TokenType::Comma, "comma expected");
-            params.push(self.ident("parameter name expected"));
+        let mut is_variadic = false;
+        if !self.consume(TokenType::RightParen) {
+            loop {
+                if self.consume(TokenType::Ellipsis) {
+                    params.push("__VA_ARGS__".to_string());
+                    is_variadic = true;
+                    self.get(TokenType::RightParen, "')' expected")?;
+                    break;
+                }
+                params.push(self.ident("parameter name expected")?);
+                if self.consume(TokenType::RightParen) {
+                    break;
+                }
+                self.get(TokenType::Comma, "comma expected")?;
+            }
         }
 
-        let mut m = Macro::new(MacroType::Funclike(params));
+        let mut m = Macro::new(MacroType::Funclike(params, is_variadic));
         m.tokens = self.read_until_eol();
         m = m.replace_params();
         self.macros.insert(name, m);
+        Ok(())
     }
 
     fn objlike_macro(&mut self, name: String) {
@@ -326,62 +536,803 @@ impl Preprocessor {
         self.macros.insert(name, m);
     }
 
-    fn define(&mut self) {
-        let name = self.ident("macro name expected");
-        if self.consume(TokenType::LeftParen) {
+    fn define(&mut self) -> Result<(), Diagnostic> {
+        let end = self.peek().map_or(0, |t| t.end);
+        let name = self.ident("macro name expected")?;
+
+        // A macro is function-like only if '(' immediately follows its
+        // name. "#define X (1)" defines an object-like macro.
+        let is_funclike = match self.peek() {
+            Some(t) => t.ty == TokenType::LeftParen && t.start == end,
+            None => false,
+        };
+        if is_funclike {
+            self.next();
             return self.funclike_macro(name);
         }
         self.objlike_macro(name);
+        Ok(())
     }
 
-    fn include(&mut self) {
-        let path = self.ident("string expected");
-        let t = self.next().expect("newline expected");
-        if t.ty != TokenType::NewLine {
-            t.bad_token("newline expected");
+    fn expect_newline(&mut self) -> Result<(), Diagnostic> {
+        match self.next() {
+            Some(t) if t.ty != TokenType::NewLine => Err(t.bad_token("newline expected")),
+            _ => Ok(()),
         }
-        let mut v = tokenize(path, self);
-        self.env.output.append(&mut v);
     }
 
-    fn preprocess_impl(&mut self, tokens: Vec<Token>) -> Vec<Token> {
-        self.env = Box::new(Env::new(tokens, Some(self.env.clone())));
+    // Directive names are not necessarily identifiers, because "if" and
+    // "else" are tokenized as keywords.
+    fn directive_name(t: &Token) -> String {
+        match t.ty {
+            TokenType::Ident(ref name) => name.clone(),
+            _ => t.tokstr(),
+        }
+    }
+
+    // Returns the name of the directive at the current position, if any.
+    fn peek_directive(&self) -> Option<String> {
+        match self.peek() {
+            Some(t) if t.ty == TokenType::HashMark => {}
+            _ => return None,
+        }
+        self.env
+            .input
+            .get(self.env.pos + 1)
+            .map(Self::directive_name)
+    }
 
+    // Skips tokens until the #elif, #else or #endif that belongs to the
+    // current conditional. Nested conditionals are skipped as a whole.
+    fn skip_cond_incl(&mut self) {
+        let mut depth = 0;
         while !self.eof() {
-            let t = self.next().unwrap();
-            let macro_name;
-            if let TokenType::Ident(ref name) = t.ty {
-                macro_name = Some(name.clone());
-            } else {
-                macro_name = None;
-            }
-            if let Some(name) = macro_name {
-                if let Some(m) = self.macros.get(&name).cloned() {
-                    self.apply(m, &t);
-                } else {
-                    self.env.output.push(t);
+            match self.peek_directive().as_deref() {
+                Some("if") | Some("ifdef") | Some("ifndef") => depth += 1,
+                Some("elif") | Some("else") if depth == 0 => return,
+                Some("endif") => {
+                    if depth == 0 {
+                        return;
+                    }
+                    depth -= 1;
                 }
+                _ => (),
+            }
+            self.next();
+        }
+    }
+
+    // Reads the rest of the line as the constant expression of #if or
+    // #elif. `defined` is resolved before macro expansion, and any
+    // identifier remaining after expansion evaluates to 0.
+    fn read_const_expr(&mut self, start: &Token) -> Result<bool, Diagnostic> {
+        let line = self.read_until_eol();
+        let mut tokens = vec![];
+        let mut iter = line.into_iter();
+        while let Some(t) = iter.next() {
+            if !t.is_ident("defined") {
+                tokens.push(t);
                 continue;
             }
 
-            if t.ty != TokenType::HashMark {
-                self.env.output.push(t);
+            let mut name = iter
+                .next()
+                .ok_or_else(|| t.bad_token("macro name expected"))?;
+            let has_paren = name.ty == TokenType::LeftParen;
+            if has_paren {
+                name = iter
+                    .next()
+                    .ok_or_else(|| t.bad_token("macro name expected"))?;
+            }
+            let defined = match name.ty {
+                TokenType::Ident(ref s) => self.macros.contains_key(s),
+                _ => return Err(name.bad_token("macro name expected")),
+            };
+            if has_paren {
+                match iter.next() {
+                    Some(ref t2) if t2.ty == TokenType::RightParen => (),
+                    _ => return Err(name.bad_token("')' expected")),
+                }
+            }
+            tokens.push(Token::new(
+                TokenType::Num(defined as i64),
+                0,
+                t.filename.clone(),
+                t.buf.clone(),
+            ));
+        }
+
+        let tokens = self.expand(tokens)?;
+        if tokens.is_empty() {
+            return Err(start.bad_token("no expression after directive"));
+        }
+        let mut e = ConstExpr {
+            tokens: &tokens,
+            pos: 0,
+            start,
+            skip: 0,
+        };
+        let val = e.expr()?;
+        if let Some(t) = e.tokens.get(e.pos) {
+            return Err(t.bad_token("extra token in constant expression"));
+        }
+        Ok(val.is_true())
+    }
+
+    fn cond_incl(&mut self, t: Token, included: bool) {
+        self.cond_incl.push(CondIncl {
+            ctx: CondCtx::Then,
+            included,
+            tok: t,
+        });
+        if !included {
+            self.skip_cond_incl();
+        }
+    }
+
+    fn ifdef(&mut self, t: Token, expect_defined: bool) -> Result<(), Diagnostic> {
+        let name = self.ident("macro name expected")?;
+        self.expect_newline()?;
+        let defined = self.macros.contains_key(&name);
+        self.cond_incl(t, defined == expect_defined);
+        Ok(())
+    }
+
+    fn elif(&mut self, t: Token) -> Result<(), Diagnostic> {
+        let included = match self.cond_incl.last() {
+            Some(c) if c.ctx == CondCtx::Else => return Err(t.bad_token("#elif after #else")),
+            Some(c) => c.included,
+            None => return Err(t.bad_token("stray #elif")),
+        };
+
+        // Earlier groups have been taken, so the rest are skipped
+        // without evaluating the condition.
+        if included {
+            self.read_until_eol();
+            self.cond_incl.last_mut().unwrap().ctx = CondCtx::Elif;
+            self.skip_cond_incl();
+            return Ok(());
+        }
+
+        let val = self.read_const_expr(&t)?;
+        let c = self.cond_incl.last_mut().unwrap();
+        c.ctx = CondCtx::Elif;
+        c.included = val;
+        if !val {
+            self.skip_cond_incl();
+        }
+        Ok(())
+    }
+
+    fn else_(&mut self, t: Token) -> Result<(), Diagnostic> {
+        self.expect_newline()?;
+        let c = match self.cond_incl.last_mut() {
+            Some(c) if c.ctx == CondCtx::Else => return Err(t.bad_token("#else after #else")),
+            Some(c) => c,
+            None => return Err(t.bad_token("stray #else")),
+        };
+        c.ctx = CondCtx::Else;
+        if c.included {
+            self.skip_cond_incl();
+        } else {
+            c.included = true;
+        }
+        Ok(())
+    }
+
+    fn endif(&mut self, t: Token) -> Result<(), Diagnostic> {
+        self.expect_newline()?;
+        if self.cond_incl.pop().is_none() {
+            return Err(t.bad_token("stray #endif"));
+        }
+        Ok(())
+    }
+
+    fn undef(&mut self) -> Result<(), Diagnostic> {
+        let name = self.ident("macro name expected")?;
+        self.expect_newline()?;
+        self.macros.remove(&name);
+        Ok(())
+    }
+
+    // Reads the operand of #include, which is either "file" or <file>.
+    // Other forms are macro-expanded first. Returns the file name and
+    // whether it was quoted.
+    fn read_include_filename(&mut self, start: &Token) -> Result<(String, bool), Diagnostic> {
+        let mut line = self.read_until_eol();
+        if let Some(TokenType::Ident(_)) = line.first().map(|t| &t.ty) {
+            line = self.expand(line)?;
+        }
+
+        let msg = "expected \"FILENAME\" or <FILENAME>";
+        match line.first().map(|t| &t.ty) {
+            Some(TokenType::Str(path, _)) => {
+                if line.len() > 1 {
+                    return Err(line[1].bad_token("extra token after #include"));
+                }
+                Ok((String::from_utf8_lossy(path).into_owned(), true))
+            }
+            Some(TokenType::LeftAngleBracket) => {
+                let end = line
+                    .iter()
+                    .position(|t| t.ty == TokenType::RightAngleBracket)
+                    .ok_or_else(|| start.bad_token(msg))?;
+                if end + 1 < line.len() {
+                    return Err(line[end + 1].bad_token("extra token after #include"));
+                }
+                let path: String = line[1..end].iter().map(|t| t.tokstr()).collect();
+                Ok((path, false))
+            }
+            _ => Err(start.bad_token(msg)),
+        }
+    }
+
+    // Finds the file to include. "file" is looked up in the directory of
+    // the including file first; both forms then search the -I directories
+    // followed by the system ones. #include_next skips every directory up
+    // to and including the one the current file was found in.
+    fn find_include(
+        &self,
+        start: &Token,
+        path: &str,
+        is_quoted: bool,
+        is_next: bool,
+    ) -> Result<IncludeFile, Diagnostic> {
+        if Path::new(path).is_absolute() {
+            return Ok(IncludeFile {
+                path: path.into(),
+                dir_idx: None,
+                contents: None,
+            });
+        }
+
+        if is_quoted && !is_next {
+            let dir = Path::new(&*start.filename)
+                .parent()
+                .unwrap_or(Path::new(""));
+            let file = dir.join(path);
+            if file.is_file() {
+                return Ok(IncludeFile {
+                    path: file.to_string_lossy().into_owned(),
+                    dir_idx: None,
+                    contents: None,
+                });
+            }
+        }
+
+        let skip = match self.include_stack.last() {
+            Some(f) if is_next => f.dir_idx.map_or(0, |i| i + 1),
+            _ => 0,
+        };
+        // Bundled headers are searched as if they were in a directory
+        // between the -I directories and the system ones.
+        let builtin_idx = self.include_paths.len();
+        let ndirs = builtin_idx + 1 + self.system_include_paths.len();
+        for i in skip..ndirs {
+            if i == builtin_idx {
+                if let Some(contents) = headers::find(path) {
+                    return Ok(IncludeFile {
+                        path: format!("{}/{}", headers::DIR, path),
+                        dir_idx: Some(i),
+                        contents: Some(contents),
+                    });
+                }
                 continue;
             }
 
-            let ident = self.ident("identifier expected");
-            if &*ident == "define" {
-                self.define();
-            } else if &*ident == "include" {
-                self.include();
+            let dir = if i < builtin_idx {
+                &self.include_paths[i]
             } else {
-                t.bad_token("unknown directive");
+                &self.system_include_paths[i - builtin_idx - 1]
+            };
+            let file = Path::new(dir).join(path);
+            if file.is_file() {
+                return Ok(IncludeFile {
+                    path: file.to_string_lossy().into_owned(),
+                    dir_idx: Some(i),
+                    contents: None,
+                });
             }
         }
+        Err(start.bad_token(&format!("{}: file not found", path)))
+    }
+
+    fn include(&mut self, start: &Token, is_next: bool) -> Result<(), Diagnostic> {
+        let (path, is_quoted) = self.read_include_filename(start)?;
+        let file = self.find_include(start, &path, is_quoted, is_next)?;
+
+        if self.include_stack.len() >= MAX_INCLUDE_DEPTH {
+            // Report the cycle if there is one; it is the likely cause.
+            let files: Vec<&str> = self.include_stack.iter().map(|f| &*f.path).collect();
+            return Err(match files.iter().rposition(|f| *f == file.path) {
+                Some(i) => start.bad_token(&format!(
+                    "#include cycle: {} -> {}",
+                    files[i..].join(" -> "),
+                    file.path
+                )),
+                None => start.bad_token("#include nested too deeply"),
+            });
+        }
+
+        self.include_stack.push(file.clone());
+        let v = match file.contents {
+            Some(contents) => tokenize_input(file.path, contents.into(), self),
+            None => tokenize(file.path, self),
+        };
+        self.include_stack.pop();
+        self.env.output.append(&mut v?);
+        Ok(())
+    }
+
+    fn preprocess_impl(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>, Diagnostic> {
+        let next = mem::take(&mut *self.env);
+        *self.env = Env::new(tokens, Some(Box::new(next)));
+        let cond_depth = self.cond_incl.len();
+
+        // The state of the enclosing file is restored even on error, so
+        // that the preprocessor can be used again.
+        let res = self.preprocess_loop(cond_depth);
+        self.cond_incl.truncate(cond_depth);
 
         let mut output = vec![];
         mem::swap(&mut self.env.output, &mut output);
         self.env = self.env.next.take().unwrap();
-        output
+        res.map(|_| output)
+    }
+
+    fn preprocess_loop(&mut self, cond_depth: usize) -> Result<(), Diagnostic> {
+        while !self.eof() {
+            let t = self.next().unwrap();
+            if self.expand_macro(&t)? {
+                continue;
+            }
+
+            if t.ty != TokenType::HashMark {
+                self.env.output.push(t);
+                continue;
+            }
+
+            // Line markers, as printed by -E, are accepted and ignored.
+            let directive = match self.next() {
+                Some(t) => t,
+                None => return Err(t.bad_token("identifier expected")),
+            };
+            if let TokenType::Num(_) = directive.ty {
+                self.read_until_eol();
+                continue;
+            }
+
+            match &*Self::directive_name(&directive) {
+                "define" => self.define()?,
+                "undef" => self.undef()?,
+                "include" => self.include(&t, false)?,
+                "include_next" => self.include(&t, true)?,
+                "if" => {
+                    let val = self.read_const_expr(&t)?;
+                    self.cond_incl(t, val);
+                }
+                "ifdef" => self.ifdef(t, true)?,
+                "ifndef" => self.ifdef(t, false)?,
+                "elif" => self.elif(t)?,
+                "else" => self.else_(t)?,
+                "endif" => self.endif(t)?,
+                "line" => {
+                    self.read_until_eol();
+                }
+                _ => return Err(t.bad_token("unknown directive")),
+            }
+        }
+
+        if self.cond_incl.len() > cond_depth {
+            let c = self.cond_incl.last().unwrap();
+            return Err(c.tok.bad_token("unterminated conditional directive"));
+        }
+        Ok(())
+    }
+}
+
+// Returns the spelling of a token for -E. Numbers and strings are
+// printed from their values, since they may have been made by the
+// preprocessor rather than read from the source.
+fn spelling(t: &Token) -> String {
+    match t.ty {
+        // Only a character constant such as '\xff' can be negative.
+        TokenType::Num(val) if val < 0 => format!("'\\{:03o}'", val as u8),
+        TokenType::Num(val) => format!("{}{}", val as u64, t.int_suffix()),
+        TokenType::Str(ref s, _) => {
+            let mut sb = String::from("\"");
+            // Bytes that are not part of valid UTF-8, such as those of
+            // `\xff`, are written as octal escapes. Three digits are
+            // always used, so that a digit after one is not read as part
+            // of it.
+            for chunk in s.utf8_chunks() {
+                for c in chunk.valid().chars() {
+                    match c {
+                        '"' => sb.push_str("\\\""),
+                        '\\' => sb.push_str("\\\\"),
+                        '\n' => sb.push_str("\\n"),
+                        '\r' => sb.push_str("\\r"),
+                        '\t' => sb.push_str("\\t"),
+                        _ if c.is_ascii_control() => sb.push_str(&format!("\\{:03o}", c as u32)),
+                        _ => sb.push(c),
+                    }
+                }
+                for b in chunk.invalid() {
+                    sb.push_str(&format!("\\{:03o}", b));
+                }
+            }
+            sb.push('"');
+            sb
+        }
+        TokenType::Ident(ref name) => name.clone(),
+        _ => t.tokstr(),
+    }
+}
+
+// Whether two tokens would be read as something else if printed without
+// a space between them, such as `+` `+` or `a` `1`.
+fn needs_space(prev: &str, cur: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let is_punct = |c: char| "+-*/%&|^<>=!#.:".contains(c);
+    match (prev.chars().last(), cur.chars().next()) {
+        (Some(a), Some(b)) => (is_word(a) && is_word(b)) || (is_punct(a) && is_punct(b)),
+        _ => false,
+    }
+}
+
+// Reconstructs C source text from preprocessed tokens, for -E.
+//
+// Tokens are placed on the lines they were read from; tokens produced
+// by macro expansion go on the line of the macro invocation. When the
+// output moves to another file, or skips many lines, a line marker
+// `# <line> "<file>"` is emitted instead of blank lines.
+pub fn source_text(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut cur: Option<(Rc<String>, usize)> = None;
+    let mut prev = String::new();
+
+    // Where the previous token ended and its line. Lines are counted on
+    // from there while the tokens move forward through a buffer, so
+    // that a file is not scanned from the start for every token.
+    let mut last: Option<(&Token, usize)> = None;
+
+    for t in tokens {
+        let s = spelling(t);
+        let pos = t.origin.as_deref().unwrap_or(t);
+        let line = match last {
+            Some((p, line)) if Rc::ptr_eq(&p.buf, &pos.buf) && p.end <= pos.end => {
+                line + pos.buf[p.end..pos.end]
+                    .iter()
+                    .filter(|c| **c == '\n')
+                    .count()
+            }
+            _ => pos.get_line_number(),
+        };
+        last = Some((pos, line));
+
+        match cur {
+            Some((ref file, l)) if *file == pos.filename && l == line => (),
+            Some((ref file, l)) if *file == pos.filename && line > l && line - l <= 8 => {
+                out.push_str(&"\n".repeat(line - l));
+                prev.clear();
+            }
+            _ => {
+                if cur.is_some() {
+                    out.push('\n');
+                }
+                out.push_str(&format!("# {} \"{}\"\n", line + 1, pos.filename));
+                prev.clear();
+            }
+        }
+        cur = Some((pos.filename.clone(), line));
+
+        if prev.is_empty() {
+            // Keep the indentation of the first token on a line.
+            let indent = pos.buf[..pos.start.min(pos.buf.len())]
+                .iter()
+                .rev()
+                .take_while(|c| **c == ' ' || **c == '\t');
+            let indent: String = indent.collect();
+            out.extend(indent.chars().rev());
+        } else {
+            let has_space =
+                t.start > 0 && t.buf.get(t.start - 1).is_some_and(|c| c.is_whitespace());
+            if has_space || needs_space(&prev, &s) {
+                out.push(' ');
+            }
+        }
+        out.push_str(&s);
+        prev = s;
+    }
+    out.push('\n');
+    out
+}
+
+// Evaluator for the constant expressions of #if and #elif. As C11
+// 6.10.1p4 says, signed values are computed in intmax_t and unsigned
+// ones in uintmax_t, which are 64 bits wide here. Operands go through
+// the usual arithmetic conversions, so one unsigned operand makes the
+// other unsigned too. Shifts by 64 or more bits, and by negative counts,
+// behave as in gcc.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Value {
+    val: i64,
+    is_unsigned: bool,
+}
+
+impl Value {
+    fn int(val: i64) -> Self {
+        Value {
+            val,
+            is_unsigned: false,
+        }
+    }
+
+    fn with(self, val: i64) -> Self {
+        Value { val, ..self }
+    }
+
+    fn is_true(self) -> bool {
+        self.val != 0
+    }
+}
+
+struct ConstExpr<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    start: &'a Token,
+    // Depth of operands that are not evaluated, like the right side of
+    // `0 &&`. Division by zero is no error there.
+    skip: usize,
+}
+
+impl<'a> ConstExpr<'a> {
+    fn peek(&self) -> Option<&TokenType> {
+        self.tokens.get(self.pos).map(|t| &t.ty)
+    }
+
+    fn consume(&mut self, ty: TokenType) -> bool {
+        if self.peek() == Some(&ty) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn bad(&self, msg: &str) -> Diagnostic {
+        match self.tokens.get(self.pos) {
+            Some(t) => t.bad_token(msg),
+            None => self.start.bad_token(msg),
+        }
+    }
+
+    // Parses an operand that is evaluated only if `eval` is true.
+    fn operand(
+        &mut self,
+        eval: bool,
+        f: fn(&mut Self) -> Result<Value, Diagnostic>,
+    ) -> Result<Value, Diagnostic> {
+        if eval {
+            return f(self);
+        }
+        self.skip += 1;
+        let val = f(self);
+        self.skip -= 1;
+        val
+    }
+
+    fn expr(&mut self) -> Result<Value, Diagnostic> {
+        let mut val = self.conditional()?;
+        while self.consume(TokenType::Comma) {
+            val = self.conditional()?;
+        }
+        Ok(val)
+    }
+
+    fn conditional(&mut self) -> Result<Value, Diagnostic> {
+        let cond = self.logor()?;
+        if !self.consume(TokenType::Question) {
+            return Ok(cond);
+        }
+        let then = self.operand(cond.is_true(), Self::expr)?;
+        if !self.consume(TokenType::Colon) {
+            return Err(self.bad("':' expected"));
+        }
+        let els = self.operand(!cond.is_true(), Self::conditional)?;
+        let is_unsigned = then.is_unsigned || els.is_unsigned;
+        let val = if cond.is_true() { then.val } else { els.val };
+        Ok(Value { val, is_unsigned })
+    }
+
+    fn binary(
+        &mut self,
+        ops: &[TokenType],
+        next: fn(&mut Self) -> Result<Value, Diagnostic>,
+    ) -> Result<Value, Diagnostic> {
+        let mut lhs = next(self)?;
+        'outer: loop {
+            for op in ops {
+                if self.consume(op.clone()) {
+                    let rhs = next(self)?;
+                    lhs = self.eval(op, lhs, rhs)?;
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn eval(&self, op: &TokenType, lhs: Value, rhs: Value) -> Result<Value, Diagnostic> {
+        use crate::TokenType::*;
+        match op {
+            SHL => return Ok(shl(lhs, rhs)),
+            SHR => return Ok(shr(lhs, rhs)),
+            _ => (),
+        }
+
+        // The usual arithmetic conversions.
+        let is_unsigned = lhs.is_unsigned || rhs.is_unsigned;
+        let ty = Value {
+            val: 0,
+            is_unsigned,
+        };
+        let (l, r) = (lhs.val, rhs.val);
+        let (ul, ur) = (l as u64, r as u64);
+        let cmp = |signed: bool, unsigned: bool| {
+            Value::int(if is_unsigned { unsigned } else { signed } as i64)
+        };
+        Ok(match op {
+            VerticalBar => ty.with(l | r),
+            Hat => ty.with(l ^ r),
+            And => ty.with(l & r),
+            EQ => Value::int((l == r) as i64),
+            NE => Value::int((l != r) as i64),
+            LeftAngleBracket => cmp(l < r, ul < ur),
+            RightAngleBracket => cmp(l > r, ul > ur),
+            LE => cmp(l <= r, ul <= ur),
+            GE => cmp(l >= r, ul >= ur),
+            Plus => ty.with(l.wrapping_add(r)),
+            Minus => ty.with(l.wrapping_sub(r)),
+            Mul => ty.with(l.wrapping_mul(r)),
+            Div | Mod if r == 0 && self.skip > 0 => ty.with(0),
+            Div | Mod if r == 0 => return Err(self.start.bad_token("division by zero in #if")),
+            Div if is_unsigned => ty.with((ul / ur) as i64),
+            Mod if is_unsigned => ty.with((ul % ur) as i64),
+            Div => ty.with(l.wrapping_div(r)),
+            Mod => ty.with(l.wrapping_rem(r)),
+            _ => unreachable!(),
+        })
+    }
+
+    fn logor(&mut self) -> Result<Value, Diagnostic> {
+        let mut lhs = self.logand()?;
+        while self.consume(TokenType::Logor) {
+            let rhs = self.operand(!lhs.is_true(), Self::logand)?;
+            lhs = Value::int((lhs.is_true() || rhs.is_true()) as i64);
+        }
+        Ok(lhs)
+    }
+
+    fn logand(&mut self) -> Result<Value, Diagnostic> {
+        let mut lhs = self.bitor()?;
+        while self.consume(TokenType::Logand) {
+            let rhs = self.operand(lhs.is_true(), Self::bitor)?;
+            lhs = Value::int((lhs.is_true() && rhs.is_true()) as i64);
+        }
+        Ok(lhs)
+    }
+
+    fn bitor(&mut self) -> Result<Value, Diagnostic> {
+        self.binary(&[TokenType::VerticalBar], Self::bitxor)
+    }
+
+    fn bitxor(&mut self) -> Result<Value, Diagnostic> {
+        self.binary(&[TokenType::Hat], Self::bitand)
+    }
+
+    fn bitand(&mut self) -> Result<Value, Diagnostic> {
+        self.binary(&[TokenType::And], Self::equality)
+    }
+
+    fn equality(&mut self) -> Result<Value, Diagnostic> {
+        self.binary(&[TokenType::EQ, TokenType::NE], Self::relational)
+    }
+
+    fn relational(&mut self) -> Result<Value, Diagnostic> {
+        use crate::TokenType::*;
+        let ops = [LeftAngleBracket, RightAngleBracket, LE, GE];
+        self.binary(&ops, Self::shift)
+    }
+
+    fn shift(&mut self) -> Result<Value, Diagnostic> {
+        self.binary(&[TokenType::SHL, TokenType::SHR], Self::add)
+    }
+
+    fn add(&mut self) -> Result<Value, Diagnostic> {
+        self.binary(&[TokenType::Plus, TokenType::Minus], Self::mul)
+    }
+
+    fn mul(&mut self) -> Result<Value, Diagnostic> {
+        use crate::TokenType::*;
+        self.binary(&[Mul, Div, Mod], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Value, Diagnostic> {
+        use crate::TokenType::*;
+        if self.consume(Plus) {
+            return self.unary();
+        }
+        if self.consume(Minus) {
+            let val = self.unary()?;
+            return Ok(val.with(val.val.wrapping_neg()));
+        }
+        if self.consume(Exclamation) {
+            return Ok(Value::int(!self.unary()?.is_true() as i64));
+        }
+        if self.consume(Tilde) {
+            let val = self.unary()?;
+            return Ok(val.with(!val.val));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Value, Diagnostic> {
+        if self.consume(TokenType::LeftParen) {
+            let val = self.expr()?;
+            if !self.consume(TokenType::RightParen) {
+                return Err(self.bad("')' expected"));
+            }
+            return Ok(val);
+        }
+
+        // A literal with a `u` suffix, or too large for intmax_t, is a
+        // uintmax_t.
+        let t = self.tokens.get(self.pos);
+        let val = match t.map(|t| &t.ty) {
+            Some(TokenType::Num(val)) => Value {
+                val: *val,
+                is_unsigned: *val < 0 || t.unwrap().int_suffix().contains(['u', 'U']),
+            },
+            Some(TokenType::Ident(_)) => Value::int(0),
+            _ => return Err(self.bad("constant expression expected")),
+        };
+        self.pos += 1;
+        Ok(val)
+    }
+}
+
+// Shifts by 64 or more bits shift everything out, and a negative count
+// shifts the other way. A count of i64::MIN has no positive counterpart,
+// but any count of 64 or more does the same.
+fn neg_count(rhs: Value) -> Value {
+    rhs.with(rhs.val.checked_neg().unwrap_or(i64::MAX))
+}
+
+fn shl(lhs: Value, rhs: Value) -> Value {
+    if !rhs.is_unsigned && rhs.val < 0 {
+        return shr(lhs, neg_count(rhs));
+    }
+    if (rhs.is_unsigned && rhs.val < 0) || rhs.val >= 64 {
+        return lhs.with(0);
+    }
+    lhs.with(lhs.val.wrapping_shl(rhs.val as u32))
+}
+
+fn shr(lhs: Value, rhs: Value) -> Value {
+    if !rhs.is_unsigned && rhs.val < 0 {
+        return shl(lhs, neg_count(rhs));
+    }
+    let sign = if lhs.is_unsigned || lhs.val >= 0 {
+        0
+    } else {
+        -1
+    };
+    if (rhs.is_unsigned && rhs.val < 0) || rhs.val >= 64 {
+        return lhs.with(sign);
+    }
+    if lhs.is_unsigned {
+        lhs.with(((lhs.val as u64) >> rhs.val) as i64)
+    } else {
+        lhs.with(lhs.val >> rhs.val)
     }
 }
@@ -1,107 +1,395 @@
-use crate::gen_ir::{Function, IROp, IRType, IR};
-use crate::irdump::IRInfo;
-use crate::REGS_N;
+use crate::cfg::Cfg;
+use crate::diagnostic::Diagnostic;
+use crate::gen_ir::{Function, IROp, IR};
+use crate::util::roundup;
 
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
 
-// Quoted from 9cc
-// > Register allocator.
+// Register allocator.
 //
-// > Before this pass, it is assumed that we have infinite number of
-// > registers. This pass maps them to a finite number of registers.
-// > We actually have only 7 registers.
+// Before this pass, it is assumed that we have infinite number of
+// registers. This pass maps them to the finite number of registers the
+// target backend provides.
 //
-// > We allocate registers only within a single expression. In other
-// > words, there are no registers that live beyond semicolons.
-// > This design choice simplifies the implementation a lot, since
-// > practically we don't have to think about the case in which
-// > registers are exhausted and need to be spilled to memory.
-
-lazy_static! {
-    static ref USED: Mutex<[bool; REGS_N]> = Mutex::new([false; REGS_N]);
-    static ref REG_MAP: Mutex<[Option<usize>; 8192]> = Mutex::new([None; 8192]);
+// We first compute liveness over the basic blocks of each function
+// (see cfg.rs), and derive a live interval for every virtual
+// register. Intervals are then assigned to registers by linear scan
+// (Poletto & Sarkar). When more intervals overlap than there are
+// registers, the one that ends furthest away is spilled to a new stack
+// slot: every use reloads it into a fresh short-lived register and
+// every definition stores it back. Spill code changes the intervals, so
+// we iterate until everything fits.
+//
+// Positions are numbered so that instruction i reads its operands at
+// 2i and writes its result at 2i+1. A register whose last use is an
+// operand of i can therefore share a physical register with i's result.
+
+// Virtual registers read by an instruction.
+fn uses(ir: &IR) -> Vec<usize> {
+    use self::IROp::*;
+    match ir.op {
+        Imm | Bprel | LabelAddr(_) | StoreArg(_) | Label | Jmp | Nop | Kill => vec![],
+        Mov | Load(_) | ULoad(_) => vec![ir.rhs.unwrap()],
+        Neg | Not | Ext(_) | UExt(_) | AddImm | SubImm | MulImm | Return | If | Unless => {
+            vec![ir.lhs.unwrap()]
+        }
+        Call(_, ref args, _) => args.clone(),
+        Store(_) | Add | Sub | Mul | Div | UDiv | Mod | UMod | EQ | NE | LE | LT | GE | GT
+        | ULE | ULT | UGE | UGT | AND | OR | XOR | SHL | SHR | USHR => {
+            vec![ir.lhs.unwrap(), ir.rhs.unwrap()]
+        }
+    }
 }
 
-fn used_get(i: usize) -> bool {
-    USED.lock().unwrap()[i]
+// Virtual register written by an instruction.
+fn def(ir: &IR) -> Option<usize> {
+    use self::IROp::*;
+    match ir.op {
+        Store(_) | Return | If | Unless | StoreArg(_) | Label | Jmp | Nop | Kill => None,
+        _ => ir.lhs,
+    }
 }
 
-fn used_set(i: usize, val: bool) {
-    USED.lock().unwrap()[i] = val;
+// Rewrites the virtual registers of an instruction through `f`.
+fn map_regs<F: FnMut(usize) -> usize>(ir: &mut IR, mut f: F) {
+    use self::IROp::*;
+    match ir.op {
+        StoreArg(_) | Label | Jmp | Nop | Kill => (),
+        Imm | Bprel | AddImm | SubImm | MulImm | LabelAddr(_) | Neg | Not | Ext(_) | UExt(_)
+        | Return | If | Unless => {
+            ir.lhs = Some(f(ir.lhs.unwrap()));
+        }
+        Call(_, ref mut args, _) => {
+            for arg in args.iter_mut() {
+                *arg = f(*arg);
+            }
+            ir.lhs = Some(f(ir.lhs.unwrap()));
+        }
+        _ => {
+            ir.lhs = Some(f(ir.lhs.unwrap()));
+            ir.rhs = Some(f(ir.rhs.unwrap()));
+        }
+    }
 }
 
-fn reg_map_get(i: usize) -> Option<usize> {
-    REG_MAP.lock().unwrap().get(i).cloned().unwrap()
+#[derive(Clone, PartialEq)]
+struct BitSet(Vec<u64>);
+
+impl BitSet {
+    fn new(n: usize) -> Self {
+        BitSet(vec![0; n.div_ceil(64)])
+    }
+
+    fn insert(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn contains(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    fn union(&mut self, other: &BitSet) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a |= *b;
+        }
+    }
+
+    fn subtract(&mut self, other: &BitSet) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a &= !*b;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(i, word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
 }
 
-fn reg_map_set(i: usize, val: usize) {
-    REG_MAP.lock().unwrap()[i] = Some(val);
+#[derive(Debug, Clone)]
+struct Interval {
+    vreg: usize,
+    start: usize,
+    end: usize,
 }
 
-fn alloc(ir_reg: usize) -> usize {
-    if REG_MAP.lock().unwrap().len() <= ir_reg {
-        panic!("program too big");
+// Computes a live interval for every virtual register of `f`.
+//
+// Liveness is solved per basic block: a register is live into a block
+// if the block reads it before writing it, or if it is live out of the
+// block and not written there. Within a block, the interval of a
+// register only needs extending to the block boundaries it is live
+// across and to the positions where it is used or defined.
+fn intervals(f: &Function) -> Vec<Interval> {
+    // Number the registers densely so that they fit in a bit set.
+    let mut index: HashMap<usize, usize> = HashMap::new();
+    let mut vregs = vec![];
+    for ir in &f.ir {
+        for r in uses(ir).into_iter().chain(def(ir)) {
+            index.entry(r).or_insert_with(|| {
+                vregs.push(r);
+                vregs.len() - 1
+            });
+        }
     }
+    let n = vregs.len();
 
-    if let Some(r) = reg_map_get(ir_reg) {
-        assert!(used_get(r));
-        return r;
+    // Position in f.ir of the first and past-the-last instruction of
+    // each block, counting the label at the head. Blocks flatten back
+    // into the same instruction list.
+    let cfg = Cfg::new(f);
+    let mut bounds = vec![];
+    let mut pos = 0;
+    for bb in &cfg.blocks {
+        let start = pos;
+        pos += bb.label.map_or(0, |_| 1);
+        bounds.push((start, pos, pos + bb.ir.len()));
+        pos += bb.ir.len();
     }
 
-    for i in 0..REGS_N {
-        if used_get(i) {
-            continue;
+    // Registers read before written (gen) and written (kill) per block.
+    let mut gen = vec![BitSet::new(n); cfg.blocks.len()];
+    let mut kill = vec![BitSet::new(n); cfg.blocks.len()];
+    for (b, bb) in cfg.blocks.iter().enumerate() {
+        for ir in &bb.ir {
+            for u in uses(ir) {
+                if !kill[b].contains(index[&u]) {
+                    gen[b].insert(index[&u]);
+                }
+            }
+            if let Some(d) = def(ir) {
+                kill[b].insert(index[&d]);
+            }
         }
-        reg_map_set(ir_reg, i);
-        used_set(i, true);
-        return i;
     }
-    panic!("register exhauseted: {}", ir_reg);
+
+    let mut live_in = gen.clone();
+    let mut live_out = vec![BitSet::new(n); cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for b in (0..cfg.blocks.len()).rev() {
+            let mut out = BitSet::new(n);
+            for s in &cfg.blocks[b].succ {
+                out.union(&live_in[*s]);
+            }
+            if out == live_out[b] {
+                continue;
+            }
+            let mut inn = out.clone();
+            inn.subtract(&kill[b]);
+            inn.union(&gen[b]);
+            live_in[b] = inn;
+            live_out[b] = out;
+            changed = true;
+        }
+    }
+
+    let mut range: Vec<Option<(usize, usize)>> = vec![None; n];
+    let mut extend = |r: usize, pos: usize| {
+        range[r] = Some(match range[r] {
+            Some((start, end)) => (start.min(pos), end.max(pos)),
+            None => (pos, pos),
+        });
+    };
+    for (b, bb) in cfg.blocks.iter().enumerate() {
+        let (start, first, end) = bounds[b];
+        for r in live_in[b].iter() {
+            extend(r, 2 * start);
+        }
+        for r in live_out[b].iter() {
+            extend(r, 2 * end.max(start + 1) - 1);
+        }
+        for (i, ir) in bb.ir.iter().enumerate() {
+            for u in uses(ir) {
+                extend(index[&u], 2 * (first + i));
+            }
+            if let Some(d) = def(ir) {
+                extend(index[&d], 2 * (first + i) + 1);
+            }
+        }
+    }
+
+    let mut v: Vec<Interval> = range
+        .into_iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let (start, end) = r.unwrap();
+            Interval {
+                vreg: vregs[i],
+                start,
+                end,
+            }
+        })
+        .collect();
+    v.sort_by_key(|x| (x.start, x.end));
+    v
 }
 
-fn visit(irv: &mut Vec<IR>) {
-    use self::IRType::*;
+enum Scan {
+    Done(HashMap<usize, usize>), // vreg -> register
+    Spill(Vec<usize>),           // vregs to be spilled
+}
 
-    for item in irv {
-        let mut ir = item.clone();
-        let info = &IRInfo::from(&ir.op);
+// Assigns registers to intervals. Returns the assignment, or the set of
+// virtual registers that have to be spilled. Registers introduced by
+// spill code can't be spilled again, so it is an error if more of them
+// are live at once than there are registers.
+fn linear_scan(
+    intervals: &[Interval],
+    regs_n: usize,
+    unspillable: &HashSet<usize>,
+) -> Result<Scan, Diagnostic> {
+    let mut map = HashMap::new();
+    let mut spilled = vec![];
+    let mut free: Vec<usize> = (0..regs_n).rev().collect();
+    let mut active: Vec<&Interval> = vec![];
 
-        match info.ty {
-            Reg | RegImm | RegLabel | LabelAddr => ir.lhs = Some(alloc(ir.lhs.unwrap())),
-            Mem | RegReg => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                ir.rhs = Some(alloc(ir.rhs.unwrap()));
+    for cur in intervals {
+        // Expire intervals that ended before this one starts.
+        active.retain(|x| {
+            if x.end < cur.start {
+                free.push(map[&x.vreg]);
+                false
+            } else {
+                true
             }
-            Call => {
-                ir.lhs = Some(alloc(ir.lhs.unwrap()));
-                match ir.op {
-                    IROp::Call(name, nargs, args) => {
-                        let mut args_new: [usize; 6] = [0; 6];
-                        for i in 0..nargs {
-                            args_new[i] = alloc(args[i]);
-                        }
-                        ir.op = IROp::Call(name, nargs, args_new);
-                    }
-                    _ => unreachable!(),
+        });
+
+        if let Some(r) = free.pop() {
+            map.insert(cur.vreg, r);
+            active.push(cur);
+            continue;
+        }
+
+        // Spill whichever interval ends last.
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| !unspillable.contains(&x.vreg))
+            .max_by_key(|(_, x)| x.end)
+            .map(|(i, x)| (i, x.end));
+        match victim {
+            Some((i, end)) if end > cur.end || unspillable.contains(&cur.vreg) => {
+                let x = active.remove(i);
+                let r = map.remove(&x.vreg).unwrap();
+                spilled.push(x.vreg);
+                map.insert(cur.vreg, r);
+                active.push(cur);
+            }
+            _ if !unspillable.contains(&cur.vreg) => spilled.push(cur.vreg),
+            _ => {
+                return Err(Diagnostic::error(&format!(
+                    "register allocation failed: more than {} registers needed at once",
+                    regs_n
+                )))
+            }
+        }
+    }
+
+    if spilled.is_empty() {
+        Ok(Scan::Done(map))
+    } else {
+        Ok(Scan::Spill(spilled))
+    }
+}
+
+// Rewrites spilled registers so that each use loads from and each
+// definition stores to a stack slot through a fresh virtual register.
+fn spill(
+    f: &mut Function,
+    spilled: &[usize],
+    next_vreg: &mut usize,
+    unspillable: &mut HashSet<usize>,
+) {
+    let mut slots = HashMap::new();
+    for r in spilled {
+        f.stacksize = roundup(f.stacksize, 8) + 8;
+        slots.insert(*r, f.stacksize);
+    }
+
+    let mut fresh = || {
+        let r = *next_vreg;
+        *next_vreg += 1;
+        unspillable.insert(r);
+        r
+    };
+
+    let mut v = vec![];
+    for mut ir in f.ir.drain(..) {
+        let mut temps: HashMap<usize, usize> = HashMap::new();
+        for u in uses(&ir) {
+            if let Some(off) = slots.get(&u) {
+                if temps.contains_key(&u) {
+                    continue;
                 }
+                let t = fresh();
+                v.push(IR::new(IROp::Bprel, Some(t), Some(*off)));
+                v.push(IR::new(IROp::Load(8), Some(t), Some(t)));
+                temps.insert(u, t);
+            }
+        }
+
+        let d = def(&ir);
+        if let Some(d) = d {
+            if slots.contains_key(&d) && !temps.contains_key(&d) {
+                temps.insert(d, fresh());
             }
-            _ => (),
         }
 
+        map_regs(&mut ir, |r| *temps.get(&r).unwrap_or(&r));
+        v.push(ir);
+
+        if let Some(d) = d {
+            if let Some(off) = slots.get(&d) {
+                let addr = fresh();
+                v.push(IR::new(IROp::Bprel, Some(addr), Some(*off)));
+                v.push(IR::new(IROp::Store(8), Some(addr), Some(temps[&d])));
+            }
+        }
+    }
+    f.ir = v;
+}
+
+fn alloc_fn(f: &mut Function, regs_n: usize) -> Result<(), Diagnostic> {
+    // Registers are no longer freed explicitly; liveness tells us.
+    for ir in f.ir.iter_mut() {
         if ir.op == IROp::Kill {
-            let lhs = ir.lhs.unwrap();
-            assert!(used_get(lhs));
-            used_set(lhs, false);
             ir.op = IROp::Nop;
         }
-        *item = ir;
+    }
+
+    let mut next_vreg =
+        f.ir.iter()
+            .flat_map(|ir| uses(ir).into_iter().chain(def(ir)))
+            .max()
+            .map_or(0, |r| r + 1);
+    let mut unspillable = HashSet::new();
+
+    loop {
+        let scan = linear_scan(&intervals(f), regs_n, &unspillable).map_err(|mut e| {
+            e.message = format!("{} in function '{}'", e.message, f.name);
+            e
+        })?;
+        match scan {
+            Scan::Done(map) => {
+                for ir in f.ir.iter_mut() {
+                    map_regs(ir, |r| map[&r]);
+                }
+                return Ok(());
+            }
+            Scan::Spill(spilled) => spill(f, &spilled, &mut next_vreg, &mut unspillable),
+        }
     }
 }
 
-pub fn alloc_regs(fns: &mut Vec<Function>) {
+// `regs_n` is the number of registers the target backend hands out.
+pub fn alloc_regs(fns: &mut Vec<Function>, regs_n: usize) -> Result<(), Diagnostic> {
     for f in fns {
-        *USED.lock().unwrap() = [false; REGS_N];
-
-        visit(&mut f.ir);
+        alloc_fn(f, regs_n)?;
     }
+    Ok(())
 }
@@ -1,9 +1,11 @@
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::fold::{const_value, fold};
 use crate::matches;
-use crate::parse::{Node, NodeType};
+use crate::parse::{Designator, Node, NodeType};
 use crate::util::roundup;
-use crate::{Ctype, Scope, TokenType, Type, Var};
+use crate::{Ctype, Data, Reloc, Scope, TokenType, Type, Var};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
 use std::sync::Mutex;
 
@@ -32,11 +34,25 @@ fn swap(p: &mut Node, q: &mut Node) {
     mem::swap(p, q);
 }
 
+// Case labels seen so far in an enclosing switch statement, and the
+// type case values are converted to.
+#[derive(Debug, Clone)]
+struct SwitchScope {
+    ty: Type,
+    cases: Vec<i64>,
+    has_default: bool,
+}
+
 lazy_static! {
     static ref GLOBALS: Mutex<Vec<Var>> = Mutex::new(vec![]);
     static ref ENV: Mutex<Env> = Mutex::new(Env::new(None));
     static ref STRLABEL: Mutex<usize> = Mutex::new(0);
     static ref STACKSIZE: Mutex<usize> = Mutex::new(0);
+    static ref RETURN_TYPE: Mutex<Type> = Mutex::new(Type::int_ty());
+    static ref SWITCHES: Mutex<Vec<SwitchScope>> = Mutex::new(vec![]);
+    static ref LABELS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    static ref GOTOS: Mutex<Vec<Node>> = Mutex::new(vec![]);
+    static ref WARNINGS: Mutex<Vec<Diagnostic>> = Mutex::new(vec![]);
 }
 
 #[derive(Debug, Clone)]
@@ -54,7 +70,10 @@ impl Env {
     }
 }
 
-fn into_new_range<T: Sized>(param: T, f: Box<dyn Fn(T) -> T>) -> T {
+fn into_new_range<T: Sized>(
+    param: T,
+    f: Box<dyn Fn(T) -> Result<T, Diagnostic>>,
+) -> Result<T, Diagnostic> {
     let env = ENV.lock().unwrap().clone();
     *ENV.lock().unwrap() = Env::new(Some(Box::new(env)));
     let ret = f(param);
@@ -64,6 +83,25 @@ fn into_new_range<T: Sized>(param: T, f: Box<dyn Fn(T) -> T>) -> T {
     ret
 }
 
+// Declares an enumerator. Its name may not be declared again in the
+// same scope.
+fn declare_enum(node: &Node, name: String, val: i64) -> Result<(), Diagnostic> {
+    let mut env = ENV.lock().unwrap();
+    if let Some(prev) = env.vars.get(&name) {
+        let mut err = node.error(&format!("redefinition of '{}'", name));
+        let note = Diagnostic::note("previous definition is here");
+        err.notes
+            .extend(prev.loc.map(|loc| note.with_span(loc.span())));
+        return Err(err);
+    }
+    let var = Var {
+        loc: node.loc.map(|(loc, _)| loc),
+        ..Var::new(node.ty.clone(), name.clone(), Scope::Enum(val))
+    };
+    env.vars.insert(name, var);
+    Ok(())
+}
+
 fn find_var(name: &str) -> Option<Var> {
     let env = ENV.lock().unwrap().clone();
     let mut next: &Option<Box<Env>> = &Some(Box::new(env));
@@ -85,65 +123,150 @@ fn maybe_decay(base: Node, decay: bool) -> Node {
         return base;
     }
 
-    if let Ctype::Ary(ary_of, _) = base.ty.ty.clone() {
-        let mut node = Node::new(NodeType::Addr(Box::new(base)));
-        node.ty = Box::new(Type::ptr_to(ary_of.clone()));
-        node
-    } else {
-        base
-    }
+    // An array decays to a pointer to its first element, and a function
+    // to a pointer to itself.
+    let ty = match base.ty.ty {
+        Ctype::Ary(ref ary_of, _) => Type::ptr_to(ary_of.clone()),
+        Ctype::Func(..) => Type::ptr_to(base.ty.clone()),
+        _ => return base,
+    };
+    let loc = base.loc;
+    let mut node = Node::new(NodeType::Addr(Box::new(base)));
+    node.ty = Box::new(ty);
+    node.loc = loc;
+    node
 }
 
-fn check_lval(node: &Node) {
+fn check_lval(node: &Node) -> Result<(), Diagnostic> {
     let op = &node.op;
     if !matches!(op, NodeType::Lvar(_))
-        && !matches!(op, NodeType::Gvar(_, _, _))
+        && !matches!(op, NodeType::Gvar(_, _))
         && !matches!(op, NodeType::Deref(_))
         && !matches!(op, NodeType::Dot(_, _, _))
     {
-        panic!("not an lvalue: {:?}", node.op);
+        return Err(node.error("not an lvalue"));
+    }
+    Ok(())
+}
+
+// Checks that node is an lvalue that can be assigned to or modified.
+fn check_assignable(node: &Node) -> Result<(), Diagnostic> {
+    check_lval(node)?;
+    match node.ty.ty {
+        Ctype::Ary(..) => Err(node.error("array type is not assignable")),
+        Ctype::Func(..) => Err(node.error("function type is not assignable")),
+        _ => Ok(()),
+    }
+}
+
+// Rejects the value of a void expression, such as a call to a void
+// function, where it would be used.
+fn check_value(node: &Node) -> Result<(), Diagnostic> {
+    if let Ctype::Void = node.ty.ty {
+        return Err(node.error("void value not ignored as it ought to be"));
+    }
+    Ok(())
+}
+
+// Walks an expression whose value is used.
+fn walk_value(node: Node) -> Result<Node, Diagnostic> {
+    let node = walk(node, true)?;
+    check_value(&node)?;
+    Ok(node)
+}
+
+// Structs can be assigned and have their members or address taken, but
+// are not values that arithmetic or conditions can use.
+fn check_scalar(node: &Node) -> Result<(), Diagnostic> {
+    check_value(node)?;
+    if let Ctype::Struct(..) = node.ty.ty {
+        let msg = format!(
+            "operand of type '{}' where arithmetic or pointer type is required",
+            type_name(&node.ty)
+        );
+        return Err(node.error(&msg));
     }
+    Ok(())
+}
+
+// Walks an expression whose value must be a scalar.
+fn walk_scalar(node: Node) -> Result<Node, Diagnostic> {
+    let node = walk(node, true)?;
+    check_scalar(&node)?;
+    Ok(node)
 }
 
-fn walk(mut node: Node, decay: bool) -> Node {
+fn walk(mut node: Node, decay: bool) -> Result<Node, Diagnostic> {
     use self::NodeType::*;
     let op = node.op.clone();
     match op {
-        Num(_) | Null | Break => (),
+        Num(_) | Null | Error | Break | Continue => (),
         Str(data, len) => {
             // Quoted from 9cc
             // > A string literal is converted to a reference to an anonymous
             // > global variable of type char array.
             let name = format!(".L.str{}", *STRLABEL.lock().unwrap());
             *STRLABEL.lock().unwrap() += 1;
+            let data = Data {
+                bytes: str_bytes(&data),
+                relocs: vec![],
+            };
             let var = Var::new_global(node.ty.clone(), name, data, len, false);
             let name = var.name.clone();
             GLOBALS.lock().unwrap().push(var);
 
-            let mut ret = Node::new(NodeType::Gvar(name, "".into(), len));
+            let mut ret = Node::new(NodeType::Gvar(name, len)).at_node(&node);
             ret.ty = node.ty;
-            return maybe_decay(ret, decay);
+            return Ok(maybe_decay(ret, decay));
         }
         Ident(ref name) => {
             if let Some(var) = find_var(name) {
                 match var.scope {
                     Scope::Local(offset) => {
-                        let mut ret = Node::new(NodeType::Lvar(Scope::Local(offset)));
+                        let mut ret =
+                            Node::new(NodeType::Lvar(Scope::Local(offset))).at_node(&node);
                         ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
+                        return Ok(maybe_decay(ret, decay));
                     }
-                    Scope::Global(ref data, len, _) => {
+                    Scope::Global(_, len, _) => {
                         let mut ret =
-                            Node::new(NodeType::Gvar(var.name.clone(), data.clone(), len));
+                            Node::new(NodeType::Gvar(var.name.clone(), len)).at_node(&node);
+                        ret.ty = var.ty.clone();
+                        return Ok(maybe_decay(ret, decay));
+                    }
+                    Scope::Enum(val) => {
+                        let mut ret = Node::new_num(val).at_node(&node);
                         ret.ty = var.ty.clone();
-                        return maybe_decay(ret, decay);
+                        return Ok(ret);
                     }
                 }
             } else {
-                panic!("undefined variable: {}", name);
+                return Err(node.error(&format!("undefined variable: {}", name)));
             }
         }
-        Vardef(name, init_may, _) => {
+        Vardef(name, _, Scope::Enum(val)) => {
+            declare_enum(&node, name, val)?;
+            node.op = Null;
+        }
+        Vardef(name, mut init_may, _) => {
+            // An initializer list gives an array of unknown length its
+            // length, which has to be known to allocate it.
+            let mut list_init = None;
+            if let Some(init) = init_may.take() {
+                if is_list_init(&node.ty, &init) {
+                    let (ty, elems) = flatten_init(&node.ty, *init)?;
+                    node.ty = Box::new(ty);
+                    list_init = Some(elems);
+                } else {
+                    init_may = Some(init);
+                }
+            }
+
+            check_complete(&node)?;
+            if node.ty.is_incomplete_array() {
+                let msg = "definition of variable with array type needs an explicit size or an initializer";
+                return Err(node.error(msg));
+            }
             let stacksize = *STACKSIZE.lock().unwrap();
             *STACKSIZE.lock().unwrap() = roundup(stacksize, node.ty.align);
             *STACKSIZE.lock().unwrap() += node.ty.size;
@@ -154,38 +277,44 @@ fn walk(mut node: Node, decay: bool) -> Node {
                 Var::new(node.ty.clone(), name.clone(), Scope::Local(offset)),
             );
 
+            if let Some(elems) = list_init {
+                node.op = VecStmt(local_init(&node.ty, offset, elems)?);
+                return Ok(node);
+            }
+
             let mut init = None;
             if let Some(init2) = init_may {
-                init = Some(Box::new(walk(*init2, true)));
+                init = Some(Box::new(cast(walk_value(*init2)?, &node.ty)));
             }
             node.op = Vardef(name, init, Scope::Local(offset));
         }
         If(mut cond, mut then, els_may) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
+            cond = Box::new(walk_scalar(*cond)?);
+            then = Box::new(walk(*then, true)?);
             let mut new_els = None;
             if let Some(els) = els_may {
-                new_els = Some(Box::new(walk(*els, true)));
+                new_els = Some(Box::new(walk(*els, true)?));
             }
             node.op = If(cond, then, new_els);
         }
         Ternary(mut cond, mut then, mut els) => {
-            cond = Box::new(walk(*cond, true));
-            then = Box::new(walk(*then, true));
-            els = Box::new(walk(*els, true));
+            cond = Box::new(walk_scalar(*cond)?);
+            let (then2, els2) = usual_arith_conv(walk(*then, true)?, walk(*els, true)?);
+            then = Box::new(then2);
+            els = Box::new(els2);
             node.ty = then.ty.clone();
             node.op = Ternary(cond, then, els);
         }
         For(init, cond, inc, body) => {
-            let f = |(init, cond, inc, body)| -> (Node, Node, Node, Node) {
-                (
-                    walk(init, true),
-                    walk(cond, true),
-                    walk(inc, true),
-                    walk(body, true),
-                )
+            let f = |(init, cond, inc, body)| -> Result<(Node, Node, Node, Node), Diagnostic> {
+                Ok((
+                    walk(init, true)?,
+                    walk_scalar(cond)?,
+                    walk(inc, true)?,
+                    walk(body, true)?,
+                ))
             };
-            let (init, cond, inc, body) = into_new_range((*init, *cond, *inc, *body), Box::new(f));
+            let (init, cond, inc, body) = into_new_range((*init, *cond, *inc, *body), Box::new(f))?;
             node.op = For(
                 Box::new(init),
                 Box::new(cond),
@@ -194,14 +323,73 @@ fn walk(mut node: Node, decay: bool) -> Node {
             );
         }
         DoWhile(body, cond) => {
-            node.op = DoWhile(Box::new(walk(*body, true)), Box::new(walk(*cond, true)));
+            node.op = DoWhile(Box::new(walk(*body, true)?), Box::new(walk_scalar(*cond)?));
+        }
+        Goto(_) => GOTOS.lock().unwrap().push(node.clone()),
+        Label(name, body) => {
+            // Labels are visible throughout the function, so gotos are
+            // checked once the whole body has been walked.
+            if !LABELS.lock().unwrap().insert(name.clone()) {
+                return Err(node.error(&format!("duplicate label: {}", name)));
+            }
+            node.op = Label(name, Box::new(walk(*body, true)?));
+        }
+        Switch(cond, body, _, _) => {
+            let cond = promote(walk_scalar(*cond)?);
+            SWITCHES.lock().unwrap().push(SwitchScope {
+                ty: *cond.ty.clone(),
+                cases: vec![],
+                has_default: false,
+            });
+            let body = walk(*body, true);
+            let sw = SWITCHES.lock().unwrap().pop().unwrap();
+            let body = body?;
+            node.op = Switch(Box::new(cond), Box::new(body), sw.cases, sw.has_default);
+        }
+        Case(val, body, _) => {
+            let val = walk(*val, true)?;
+            let (val, idx) = {
+                let mut switches = SWITCHES.lock().unwrap();
+                let sw = switches
+                    .last_mut()
+                    .ok_or_else(|| node.error("case label not within a switch statement"))?;
+                // Case values are compared in the promoted type of the
+                // controlling expression.
+                let val = fold(cast(promote(val), &sw.ty));
+                let c = match val.op {
+                    Num(c) => c,
+                    _ => {
+                        let msg = "case label does not reduce to an integer constant";
+                        return Err(node.error(msg));
+                    }
+                };
+                if sw.cases.contains(&c) {
+                    return Err(node.error(&format!("duplicate case value: {}", c)));
+                }
+                sw.cases.push(c);
+                (val, sw.cases.len() - 1)
+            };
+            node.op = Case(Box::new(val), Box::new(walk(*body, true)?), idx);
+        }
+        Default(body) => {
+            {
+                let mut switches = SWITCHES.lock().unwrap();
+                let sw = switches
+                    .last_mut()
+                    .ok_or_else(|| node.error("'default' label not within a switch statement"))?;
+                if sw.has_default {
+                    return Err(node.error("multiple default labels in one switch"));
+                }
+                sw.has_default = true;
+            }
+            node.op = Default(Box::new(walk(*body, true)?));
         }
         Dot(mut expr, name, _) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             let offset;
-            if let Ctype::Struct(ref members) = expr.ty.ty {
+            if let Ctype::Struct(_, ref members) = expr.ty.ty {
                 if members.is_empty() {
-                    panic!("incomplete type");
+                    return Err(node.error("incomplete type"));
                 }
                 let m_may = members.iter().find(|m| {
                     if let NodeType::Vardef(ref m_name, _, _) = m.op {
@@ -221,156 +409,1016 @@ fn walk(mut node: Node, decay: bool) -> Node {
                         unreachable!()
                     }
                 } else {
-                    panic!("member missing: {}", name);
+                    return Err(node.error(&format!("member missing: {}", name)));
                 }
             } else {
-                panic!("struct expected before '.'");
+                return Err(node.error("struct expected before '.'"));
             }
 
             node.op = NodeType::Dot(expr, name, offset);
-            return maybe_decay(node, decay);
+            return Ok(maybe_decay(node, decay));
         }
         BinOp(token_type, mut lhs, mut rhs) => {
             use self::TokenType::*;
             match token_type {
                 Plus | Minus => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
+                    *lhs = walk_scalar(*lhs)?;
+                    rhs = Box::new(walk_scalar(*rhs)?);
 
                     if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
                         swap(&mut lhs, &mut rhs);
                     }
                     if matches!(rhs.ty.ty, Ctype::Ptr(_)) {
-                        panic!("'pointer {:?} pointer' is not defined", node.op)
+                        let op = if token_type == Plus { "+" } else { "-" };
+                        let msg = format!("'pointer {} pointer' is not defined", op);
+                        return Err(node.error(&msg));
                     }
 
-                    if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                    if let Ctype::Ptr(ref ptr_to) = lhs.ty.ty {
+                        rhs = Box::new(Node::scale_ptr(rhs, ptr_to));
+                    } else {
+                        let (lhs2, rhs2) = usual_arith_conv(*lhs, *rhs);
+                        *lhs = lhs2;
+                        *rhs = rhs2;
                     }
 
                     node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
                 AddEQ | SubEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&*lhs);
-                    rhs = Box::new(walk(*rhs, true));
+                    *lhs = walk(*lhs, false)?;
+                    check_assignable(&lhs)?;
+                    check_scalar(&lhs)?;
+                    rhs = Box::new(walk_scalar(*rhs)?);
 
-                    if matches!(lhs.ty.ty, Ctype::Ptr(_)) {
-                        rhs = Box::new(Node::scale_ptr(rhs, &lhs.ty));
+                    if let Ctype::Ptr(ref ptr_to) = lhs.ty.ty {
+                        rhs = Box::new(Node::scale_ptr(rhs, ptr_to));
+                    } else {
+                        rhs = Box::new(assign_op_conv(&lhs, *rhs, false));
                     }
                     node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
-                Equal | MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
-                    lhs = Box::new(walk(*lhs, false));
-                    check_lval(&*lhs);
-                    node.op = BinOp(token_type, lhs.clone(), Box::new(walk(*rhs, true)));
+                Equal => {
+                    *lhs = walk(*lhs, false)?;
+                    check_assignable(&lhs)?;
+                    rhs = Box::new(cast(walk_value(*rhs)?, &lhs.ty));
+                    node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
-                _ => {
-                    lhs = Box::new(walk(*lhs, true));
-                    rhs = Box::new(walk(*rhs, true));
+                MulEQ | DivEQ | ModEQ | ShlEQ | ShrEQ | BitandEQ | XorEQ | BitorEQ => {
+                    *lhs = walk(*lhs, false)?;
+                    check_assignable(&lhs)?;
+                    check_scalar(&lhs)?;
+                    let is_shift = matches!(token_type, ShlEQ | ShrEQ);
+                    rhs = Box::new(assign_op_conv(&lhs, walk_scalar(*rhs)?, is_shift));
                     node.op = BinOp(token_type, lhs.clone(), rhs);
                     node.ty = lhs.ty;
                 }
+                SHL | SHR => {
+                    *lhs = promote(walk_scalar(*lhs)?);
+                    rhs = Box::new(promote(walk_scalar(*rhs)?));
+                    node.op = BinOp(token_type, lhs.clone(), rhs);
+                    node.ty = lhs.ty;
+                }
+                LeftAngleBracket | RightAngleBracket | LE | GE | EQ | NE => {
+                    let (lhs2, rhs2) = usual_arith_conv(walk_scalar(*lhs)?, walk_scalar(*rhs)?);
+                    node.op = BinOp(token_type, Box::new(lhs2), Box::new(rhs2));
+                    node.ty = Box::new(Type::int_ty());
+                }
+                Logand | Logor => {
+                    *lhs = walk_scalar(*lhs)?;
+                    rhs = Box::new(walk_scalar(*rhs)?);
+                    node.op = BinOp(token_type, lhs, rhs);
+                    node.ty = Box::new(Type::int_ty());
+                }
+                Comma => {
+                    *lhs = walk(*lhs, true)?;
+                    rhs = Box::new(walk(*rhs, true)?);
+                    node.ty = rhs.ty.clone();
+                    node.op = BinOp(token_type, lhs, rhs);
+                }
+                _ => {
+                    let (lhs2, rhs2) = usual_arith_conv(walk_scalar(*lhs)?, walk_scalar(*rhs)?);
+                    node.ty = lhs2.ty.clone();
+                    node.op = BinOp(token_type, Box::new(lhs2), Box::new(rhs2));
+                }
             }
         }
         PostInc(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, false)?);
+            check_assignable(&expr)?;
+            check_scalar(&expr)?;
             node.ty = expr.ty.clone();
             node.op = PostInc(expr);
         }
         PostDec(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, false)?);
+            check_assignable(&expr)?;
+            check_scalar(&expr)?;
             node.ty = expr.ty.clone();
             node.op = PostDec(expr);
         }
         Neg(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(promote(walk_scalar(*expr)?));
             node.ty = expr.ty.clone();
             node.op = Neg(expr);
         }
-        Exclamation(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+        // `+x` promotes x and is not an lvalue.
+        UnaryPlus(mut expr) => {
+            expr = Box::new(promote(walk(*expr, true)?));
+            if !expr.ty.is_integer() {
+                let msg = format!(
+                    "invalid argument type '{}' to unary expression",
+                    type_name(&expr.ty)
+                );
+                return Err(expr.error(&msg));
+            }
             node.ty = expr.ty.clone();
+            node.op = UnaryPlus(expr);
+        }
+        BitNot(mut expr) => {
+            expr = Box::new(promote(walk(*expr, true)?));
+            if !expr.ty.is_integer() {
+                let msg = format!(
+                    "invalid argument type '{}' to unary expression",
+                    type_name(&expr.ty)
+                );
+                return Err(expr.error(&msg));
+            }
+            node.ty = expr.ty.clone();
+            node.op = BitNot(expr);
+        }
+        Cast(mut expr) => {
+            expr = Box::new(walk(*expr, true)?);
+            check_cast(&node, &expr)?;
+            node.op = Cast(expr);
+        }
+        Exclamation(mut expr) => {
+            expr = Box::new(walk_scalar(*expr)?);
+            node.ty = Box::new(Type::int_ty());
             node.op = Exclamation(expr);
         }
         Addr(mut expr) => {
-            expr = Box::new(walk(*expr, true));
-            check_lval(&*expr);
+            expr = Box::new(walk(*expr, false)?);
+            check_lval(&expr)?;
             node.ty = Box::new(Type::ptr_to(expr.ty.clone()));
             node.op = Addr(expr);
         }
         Deref(mut expr) => {
-            expr = Box::new(walk(*expr, true));
+            expr = Box::new(walk(*expr, true)?);
             match expr.ty.ty {
+                Ctype::Ptr(ref ptr_to) if is_void(ptr_to) => {
+                    return Err(node.error("cannot dereference void pointer"))
+                }
                 Ctype::Ptr(ref ptr_to) => node.ty = ptr_to.clone(),
-                Ctype::Void => panic!("cannot dereference void pointer"),
-                _ => panic!("operand must be a pointer"),
+                _ => return Err(node.error("operand must be a pointer")),
             }
             node.op = Deref(expr);
-            return maybe_decay(node, decay);
+            return Ok(maybe_decay(node, decay));
+        }
+        Return(expr) => {
+            let expr = walk(*expr, true)?;
+            // A void function may return the result of a void call.
+            if !matches!(expr.ty.ty, Ctype::Void) || !is_void(&RETURN_TYPE.lock().unwrap()) {
+                check_scalar(&expr)?;
+            }
+            node.op = Return(Box::new(expr));
         }
-        Return(expr) => node.op = Return(Box::new(walk(*expr, true))),
-        ExprStmt(expr) => node.op = ExprStmt(Box::new(walk(*expr, true))),
+        ExprStmt(expr) => node.op = ExprStmt(Box::new(walk(*expr, true)?)),
         Sizeof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.size as i32)
+            *expr = walk(*expr, false)?;
+            check_sizeof(&node, &expr)?;
+            node = Node::new_int(expr.ty.size as i64).at_node(&node);
+            node.ty = Box::new(Type::long_ty().to_unsigned()); // size_t
         }
         Alignof(mut expr) => {
-            expr = Box::new(walk(*expr, false));
-            node = Node::new_int(expr.ty.align as i32)
+            *expr = walk(*expr, false)?;
+            check_sizeof(&node, &expr)?;
+            node = Node::new_int(expr.ty.align as i64).at_node(&node);
+            node.ty = Box::new(Type::long_ty().to_unsigned());
         }
         Call(name, mut args) => {
-            if let Some(var) = find_var(&name) {
-                if let Ctype::Func(returning) = var.ty.ty {
-                    node.ty = returning;
-                } else {
-                    eprint!("bad function: {}", name);
-                }
-            } else {
-                eprint!("bad function: {}", name);
+            args = args.into_iter().map(walk_value).collect::<Result<_, _>>()?;
+            if let Some(arg) = args
+                .iter()
+                .find(|arg| matches!(arg.ty.ty, Ctype::Struct(..)))
+            {
+                return Err(arg.error("passing a struct by value is not supported"));
             }
 
-            args = args.into_iter().map(|arg| walk(arg, true)).collect();
+            let var = find_var(&name).unwrap_or_else(|| {
+                // C89 implicitly declares an unknown function as `int f()`. The
+                // declaration is kept at file scope so we warn only once.
+                warn(
+                    &node,
+                    &format!("implicit declaration of function '{}'", name),
+                );
+                let ty = Type::new(Ctype::Func(Box::new(Type::int_ty()), None, false), 0);
+                let var = Var::new_global(Box::new(ty), name.clone(), Data::default(), 0, false);
+                let mut env = ENV.lock().unwrap();
+                let mut file_scope = &mut *env;
+                while let Some(ref mut next) = file_scope.next {
+                    file_scope = next;
+                }
+                file_scope.vars.insert(name.clone(), var.clone());
+                var
+            });
+            match var.ty.ty {
+                Ctype::Func(ref returning, ref params, is_variadic) => {
+                    if let Some(params) = params {
+                        check_args(&node, &var, &args, params, is_variadic)?;
+                    }
+                    // Arguments are converted to the types of their
+                    // parameters. The rest, including all arguments to
+                    // a function without a prototype, are promoted.
+                    let param_tys = params.as_deref().unwrap_or_default();
+                    args = args
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, arg)| match param_tys.get(i) {
+                            Some(param) => cast(arg, param),
+                            None => promote(arg),
+                        })
+                        .collect();
+                    node.ty = returning.clone();
+                }
+                _ => {
+                    let msg = format!(
+                        "called object type '{}' is not a function",
+                        type_name(&var.ty)
+                    );
+                    return Err(node.error(&msg));
+                }
+            }
             node.op = Call(name, args);
         }
         CompStmt(mut stmts) => {
-            let f = |stmts: Vec<Node>| -> Vec<Node> {
+            let f = |stmts: Vec<Node>| -> Result<Vec<Node>, Diagnostic> {
                 stmts.into_iter().map(|stmt| walk(stmt, true)).collect()
             };
-            stmts = into_new_range(stmts, Box::new(f));
+            stmts = into_new_range(stmts, Box::new(f))?;
             node.op = CompStmt(stmts);
         }
         VecStmt(mut stmts) => {
-            stmts = stmts.into_iter().map(|stmt| walk(stmt, true)).collect();
+            stmts = stmts
+                .into_iter()
+                .map(|stmt| walk(stmt, true))
+                .collect::<Result<_, _>>()?;
             node.op = VecStmt(stmts);
         }
         StmtExpr(body) => {
-            node.op = StmtExpr(Box::new(walk(*body, true)));
+            node.op = StmtExpr(Box::new(walk(*body, true)?));
             node.ty = Box::new(Type::int_ty())
         }
-        _ => panic!("unknown node type"),
+        _ => return Err(node.error("unknown node type")),
     };
-    node
+    Ok(node)
+}
+
+// The bytes of a string literal, including the terminating NUL.
+fn str_bytes(s: &[u8]) -> Vec<u8> {
+    let mut bytes = s.to_vec();
+    bytes.push(0);
+    bytes
+}
+
+// A scalar that an initializer stores at an offset into the object
+// being initialized.
+struct InitElem {
+    offset: usize,
+    ty: Type,
+    init: Node,
+}
+
+// Returns true if an object of type ty is initialized by an initializer
+// list or, for a char array, by a string literal.
+fn is_list_init(ty: &Type, init: &Node) -> bool {
+    match (&ty.ty, &init.op) {
+        (_, NodeType::InitList(_)) => true,
+        (Ctype::Ary(ary_of, _), NodeType::Str(_, _)) => matches!(ary_of.ty, Ctype::Char),
+        _ => false,
+    }
+}
+
+// Breaks an initializer down into the scalars it stores. Returns them
+// along with the type of the object, whose length is filled in from the
+// initializer if it was declared as `[]`. Elements that are not
+// mentioned are left out, and are zero.
+fn flatten_init(ty: &Type, init: Node) -> Result<(Type, Vec<InitElem>), Diagnostic> {
+    match (&ty.ty, &init.op) {
+        (Ctype::Ary(_, _), _) if !is_list_init(ty, &init) => {
+            return Err(init.error("array initializer must be an initializer list"));
+        }
+        (Ctype::Struct(..), _) if !is_list_init(ty, &init) => {
+            return Err(init.error("struct initializer must be an initializer list"));
+        }
+        _ => (),
+    }
+
+    let mut elems = vec![];
+    let ary_of = match ty.ty {
+        Ctype::Ary(ref ary_of, 0) => ary_of,
+        _ => {
+            init_elem(ty, 0, &mut VecDeque::from(vec![init]), &mut elems)?;
+            return Ok((ty.clone(), elems));
+        }
+    };
+    if let NodeType::Str(ref s, _) = init.op {
+        let ty = Type::ary_of(ary_of.clone(), str_bytes(s).len());
+        init_elem(&ty, 0, &mut VecDeque::from(vec![init]), &mut elems)?;
+        return Ok((ty, elems));
+    }
+    // Lay the elements out in an array long enough for any initializer,
+    // and count them.
+    let len = init_list(&Type::ary_of(ary_of.clone(), 1 << 32), 0, init, &mut elems)?;
+    Ok((Type::ary_of(ary_of.clone(), len), elems))
+}
+
+// Initializes an object of type ty from the initializers in items,
+// taking the first one and, if ty is an aggregate that the first one
+// does not initialize on its own, as many more as it needs.
+fn init_elem(
+    ty: &Type,
+    offset: usize,
+    items: &mut VecDeque<Node>,
+    out: &mut Vec<InitElem>,
+) -> Result<(), Diagnostic> {
+    let item = items.pop_front().unwrap();
+    match (&ty.ty, &item.op) {
+        (_, NodeType::InitList(_)) => {
+            init_list(ty, offset, item, out)?;
+        }
+        (Ctype::Ary(ary_of, len), NodeType::Str(ref s, _)) if matches!(ary_of.ty, Ctype::Char) => {
+            let mut bytes = str_bytes(s);
+            // The NUL is dropped if the array has no room for it.
+            if bytes.len() > len + 1 {
+                warn(&item, "initializer-string for char array is too long");
+            }
+            bytes.truncate(*len);
+            for (i, b) in bytes.into_iter().enumerate() {
+                let mut init = Node::new_num(b as i8 as i64).at_node(&item);
+                init.ty = Box::new(Type::char_ty());
+                out.push(InitElem {
+                    offset: offset + i,
+                    ty: (**ary_of).clone(),
+                    init,
+                });
+            }
+        }
+        // The braces around a nested aggregate may be left out.
+        (Ctype::Ary(_, _), _) | (Ctype::Struct(..), _) => {
+            items.push_front(item);
+            init_items(ty, offset, items, false, false, out)?;
+        }
+        _ => out.push(InitElem {
+            offset,
+            ty: ty.clone(),
+            init: item,
+        }),
+    }
+    Ok(())
+}
+
+// Initializes an object from a brace-enclosed initializer list. Returns
+// the number of elements it covers, which an array of unknown length
+// takes as its length.
+fn init_list(
+    ty: &Type,
+    offset: usize,
+    list: Node,
+    out: &mut Vec<InitElem>,
+) -> Result<usize, Diagnostic> {
+    let mut items = match list.op {
+        NodeType::InitList(inits) => VecDeque::from(inits),
+        _ => unreachable!(),
+    };
+    let n = init_items(ty, offset, &mut items, true, false, out)?;
+    if let Some(item) = items.front() {
+        let kind = match ty.ty {
+            Ctype::Ary(_, _) => "array",
+            Ctype::Struct(..) => "struct",
+            _ => "scalar",
+        };
+        warn(item, &format!("excess elements in {} initializer", kind));
+    }
+    Ok(n)
+}
+
+// Initializes the elements of an aggregate from items in order. Inside
+// braces, a designator moves the position to the element it names, and
+// the following items go on from there. Without braces, the elements
+// take initializers from the enclosing list, and a designator belongs
+// to that list, unless `designated` says the first item is one that
+// names an element of this aggregate.
+fn init_items(
+    ty: &Type,
+    offset: usize,
+    items: &mut VecDeque<Node>,
+    braced: bool,
+    mut designated: bool,
+    out: &mut Vec<InitElem>,
+) -> Result<usize, Diagnostic> {
+    let len = match ty.ty {
+        Ctype::Ary(_, len) => len,
+        Ctype::Struct(_, ref members) => members.len(),
+        _ => 1,
+    };
+    // Returns the offset and type of the i-th element.
+    let elem = |i: usize| match ty.ty {
+        Ctype::Ary(ref ary_of, _) => (offset + i * ary_of.size, (**ary_of).clone()),
+        Ctype::Struct(_, ref members) => match members[i].op {
+            NodeType::Vardef(_, _, Scope::Local(off)) => (offset + off, (*members[i].ty).clone()),
+            _ => unreachable!(),
+        },
+        _ => (offset, ty.clone()),
+    };
+
+    let (mut i, mut n) = (0, 0);
+    while let Some(item) = items.front() {
+        if let NodeType::Designation(ref desigs, _) = item.op {
+            if !braced && !designated {
+                break;
+            }
+            designated = false;
+            i = designated_index(ty, &desigs[0], item)?;
+            let item = items.pop_front().unwrap();
+            let (mut desigs, init) = match item.op {
+                NodeType::Designation(desigs, init) => (desigs, init),
+                _ => unreachable!(),
+            };
+            desigs.remove(0);
+            let (off, elem_ty) = elem(i);
+            if desigs.is_empty() {
+                items.push_front(*init);
+                init_elem(&elem_ty, off, items, out)?;
+            } else {
+                items.push_front(Node {
+                    op: NodeType::Designation(desigs, init),
+                    ..item
+                });
+                init_items(&elem_ty, off, items, false, true, out)?;
+            }
+        } else {
+            if i >= len {
+                break;
+            }
+            let (off, elem_ty) = elem(i);
+            init_elem(&elem_ty, off, items, out)?;
+        }
+        i += 1;
+        n = n.max(i);
+    }
+    Ok(n)
+}
+
+// Returns the index of the element that a designator names.
+fn designated_index(ty: &Type, desig: &Designator, item: &Node) -> Result<usize, Diagnostic> {
+    match (&ty.ty, desig) {
+        (Ctype::Ary(_, len), Designator::Index(idx)) => {
+            if idx >= len {
+                let msg = format!(
+                    "array designator index ({}) exceeds array bounds ({})",
+                    idx, len
+                );
+                return Err(item.error(&msg));
+            }
+            Ok(*idx)
+        }
+        (Ctype::Struct(_, members), Designator::Member(name)) => members
+            .iter()
+            .position(|m| match m.op {
+                NodeType::Vardef(ref m_name, _, _) => m_name == name,
+                _ => false,
+            })
+            .ok_or_else(|| {
+                item.error(&format!(
+                    "field designator '{}' does not refer to any field in type '{}'",
+                    name,
+                    type_name(ty)
+                ))
+            }),
+        (_, Designator::Index(_)) => Err(item.error(&format!(
+            "array designator cannot initialize non-array type '{}'",
+            type_name(ty)
+        ))),
+        (_, Designator::Member(_)) => Err(item.error(&format!(
+            "field designator cannot initialize a non-struct type '{}'",
+            type_name(ty)
+        ))),
+    }
+}
+
+// Evaluates the initializer of a global variable into its data. Every
+// scalar in it must be an integer constant or an address constant, that
+// is, the address of a global plus or minus a constant.
+fn global_init(ty: &Type, init: Node) -> Result<(Type, Data), Diagnostic> {
+    let (ty, elems) = flatten_init(ty, init)?;
+    let mut data = Data {
+        bytes: vec![0; ty.size],
+        relocs: vec![],
+    };
+    for elem in elems {
+        let node = fold(cast(walk(elem.init, true)?, &elem.ty));
+        let (offset, size) = (elem.offset, elem.ty.size);
+        // A later initializer of the same bytes overrides an address
+        // stored there.
+        data.relocs
+            .retain(|r| r.offset + 8 <= offset || offset + size <= r.offset);
+        if let Some(val) = const_value(&node) {
+            data.bytes[offset..offset + size].copy_from_slice(&val.to_le_bytes()[..size]);
+        } else if let Some((label, addend)) = eval_addr(&node).filter(|_| size == 8) {
+            data.bytes[offset..offset + size].fill(0);
+            data.relocs.push(Reloc {
+                offset,
+                label,
+                addend,
+            });
+        } else {
+            return Err(node.error("initializer element is not a compile-time constant"));
+        }
+    }
+    data.relocs.sort_by_key(|r| r.offset);
+    Ok((ty, data))
+}
+
+// Bytes of zeros cleared by a loop rather than by separate stores.
+const ZERO_FILL_MIN: usize = 64;
+
+// Turns the initializer of a local variable at offset into statements
+// that store each element and zero the bytes between them.
+fn local_init(ty: &Type, offset: usize, elems: Vec<InitElem>) -> Result<Vec<Node>, Diagnostic> {
+    let lvar = |off: usize, ty: Type| {
+        let mut node = Node::new(NodeType::Lvar(Scope::Local(offset - off)));
+        node.ty = Box::new(ty);
+        node
+    };
+    let assign = |lhs: Node, rhs: Node| {
+        let ty = lhs.ty.clone();
+        let loc = rhs.loc;
+        let mut node = Node::new(NodeType::BinOp(
+            TokenType::Equal,
+            Box::new(lhs),
+            Box::new(rhs),
+        ));
+        node.ty = ty;
+        node.loc = loc;
+        Node::new(NodeType::ExprStmt(Box::new(node)))
+    };
+
+    // Zero the bytes no element covers. Long runs of aligned words are
+    // cleared by a loop, and the rest in the widest stores their
+    // alignment allows.
+    let mut covered: Vec<(usize, usize)> = elems
+        .iter()
+        .map(|elem| (elem.offset, elem.offset + elem.ty.size))
+        .collect();
+    covered.sort();
+    covered.push((ty.size, ty.size));
+
+    let mut stmts = vec![];
+    let mut off = 0;
+    for (start, end) in covered {
+        while off < start {
+            if (offset - off).is_multiple_of(8) && start - off >= ZERO_FILL_MIN {
+                let words = (start - off) / 8;
+                stmts.push(Node::new(NodeType::ZeroFill(offset - off, words)));
+                off += words * 8;
+                continue;
+            }
+            let n = [8, 4, 2, 1]
+                .into_iter()
+                .find(|n| off + n <= start && (offset - off).is_multiple_of(*n))
+                .unwrap();
+            let zero_ty = match n {
+                8 => Type::long_ty(),
+                4 => Type::int_ty(),
+                2 => Type::short_ty(),
+                _ => Type::char_ty(),
+            };
+            let mut zero = Node::new_num(0);
+            zero.ty = Box::new(zero_ty.clone());
+            stmts.push(assign(lvar(off, zero_ty), zero));
+            off += n;
+        }
+        off = off.max(end);
+    }
+
+    for elem in elems {
+        let rhs = cast(walk_value(elem.init)?, &elem.ty);
+        stmts.push(assign(lvar(elem.offset, elem.ty), rhs));
+    }
+    Ok(stmts)
+}
+
+// Returns the label and offset of an address constant.
+fn eval_addr(node: &Node) -> Option<(String, i64)> {
+    match node.op {
+        NodeType::Addr(ref expr) => eval_lval_addr(expr),
+        NodeType::Cast(ref expr) => eval_addr(expr),
+        NodeType::BinOp(TokenType::Plus, ref lhs, ref rhs) => {
+            let (label, addend) = eval_addr(lhs)?;
+            Some((label, addend + const_value(rhs)?))
+        }
+        NodeType::BinOp(TokenType::Minus, ref lhs, ref rhs) => {
+            let (label, addend) = eval_addr(lhs)?;
+            Some((label, addend - const_value(rhs)?))
+        }
+        _ => None,
+    }
+}
+
+fn eval_lval_addr(node: &Node) -> Option<(String, i64)> {
+    match node.op {
+        NodeType::Gvar(ref name, _) => Some((name.clone(), 0)),
+        NodeType::Dot(ref expr, _, offset) => {
+            let (label, addend) = eval_lval_addr(expr)?;
+            Some((label, addend + offset as i64))
+        }
+        NodeType::Deref(ref expr) => eval_addr(expr),
+        _ => None,
+    }
 }
 
-pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
+// Converts an expression from one integer type to another by wrapping
+// it in a Cast node. Other conversions need no code and are left as is.
+fn cast(node: Node, ty: &Type) -> Node {
+    if !node.ty.is_integer() || !ty.is_integer() || same_type(&node.ty, ty) {
+        return node;
+    }
+    let loc = node.loc;
+    let mut cast = Node::new(NodeType::Cast(Box::new(node)));
+    cast.loc = loc;
+    cast.ty = Box::new(ty.clone());
+    cast
+}
+
+// Checks an explicit cast of expr to the type of node. Any integer or
+// pointer can be converted to any other integer or pointer, and
+// anything to void.
+fn check_cast(node: &Node, expr: &Node) -> Result<(), Diagnostic> {
+    let is_scalar = |ty: &Type| ty.is_integer() || matches!(ty.ty, Ctype::Ptr(_));
+    let (to, from) = (&node.ty, &expr.ty);
+    if matches!(to.ty, Ctype::Void) {
+        return Ok(());
+    }
+    if !is_scalar(to) {
+        return Err(node.error(&format!(
+            "used type '{}' where arithmetic or pointer type is required",
+            type_name(to)
+        )));
+    }
+    if !is_scalar(from) {
+        return Err(expr.error(&format!(
+            "operand of type '{}' where arithmetic or pointer type is required",
+            type_name(from)
+        )));
+    }
+    if matches!(from.ty, Ctype::Ptr(_)) && to.is_integer() && to.size < from.size {
+        warn(
+            node,
+            &format!(
+                "cast to smaller integer type '{}' from '{}'",
+                type_name(to),
+                type_name(from)
+            ),
+        );
+    }
+    Ok(())
+}
+
+// Integer promotion: values of types narrower than int are converted to
+// int, which can represent all of them.
+fn promote(mut node: Node) -> Node {
+    // An enum is an int under another name.
+    if let Ctype::Enum(_) = node.ty.ty {
+        node.ty = Box::new(Type::int_ty());
+    }
+    if node.ty.is_integer() && node.ty.size < 4 {
+        cast(node, &Type::int_ty())
+    } else {
+        node
+    }
+}
+
+// Returns the type that the usual arithmetic conversions bring two
+// promoted integer types to. Of two types of the same signedness, the
+// wider one wins. Otherwise the unsigned type wins unless the signed
+// one is wider, and so can represent all of its values.
+fn common_type(x: &Type, y: &Type) -> Type {
+    if x.is_unsigned == y.is_unsigned {
+        return if x.size >= y.size { x } else { y }.clone();
+    }
+    let (unsigned, signed) = if x.is_unsigned { (x, y) } else { (y, x) };
+    if unsigned.size >= signed.size {
+        unsigned.clone()
+    } else {
+        signed.clone()
+    }
+}
+
+// Applies the usual arithmetic conversions to the operands of a binary
+// operator, so that both have the same type.
+fn usual_arith_conv(lhs: Node, rhs: Node) -> (Node, Node) {
+    if !lhs.ty.is_integer() || !rhs.ty.is_integer() {
+        return (lhs, rhs);
+    }
+    let (lhs, rhs) = (promote(lhs), promote(rhs));
+    let ty = common_type(&lhs.ty, &rhs.ty);
+    (cast(lhs, &ty), cast(rhs, &ty))
+}
+
+// Converts the right operand of a compound assignment such as `x /= y`
+// to the type `x op y` is computed in. gen_ir reads that type from the
+// operand when it converts x. The result is converted back to the type
+// of x when stored.
+fn assign_op_conv(lhs: &Node, rhs: Node, is_shift: bool) -> Node {
+    if !lhs.ty.is_integer() || !rhs.ty.is_integer() {
+        return rhs;
+    }
+    let rhs = promote(rhs);
+    if is_shift {
+        return rhs;
+    }
+    let lty = if lhs.ty.size < 4 || matches!(lhs.ty.ty, Ctype::Enum(_)) {
+        Type::int_ty()
+    } else {
+        (*lhs.ty).clone()
+    };
+    let ty = common_type(&lty, &rhs.ty);
+    cast(rhs, &ty)
+}
+
+// Spells a type the way it would be written in C, for diagnostics.
+fn type_name(ty: &Type) -> String {
+    let int_name = |name: &str| {
+        if ty.is_unsigned {
+            format!("unsigned {}", name)
+        } else {
+            name.into()
+        }
+    };
+    match ty.ty {
+        Ctype::Char => int_name("char"),
+        Ctype::Short => int_name("short"),
+        Ctype::Int => int_name("int"),
+        Ctype::Long => int_name("long"),
+        Ctype::Enum(ref tag) if tag.is_empty() => "enum (anonymous)".into(),
+        Ctype::Enum(ref tag) => format!("enum {}", tag),
+        Ctype::Void => "void".into(),
+        Ctype::Ptr(ref ptr_to) => match ptr_to.ty {
+            Ctype::Ptr(_) => format!("{}*", type_name(ptr_to)),
+            _ => format!("{} *", type_name(ptr_to)),
+        },
+        Ctype::Ary(ref ary_of, 0) => format!("{}[]", type_name(ary_of)),
+        Ctype::Ary(ref ary_of, len) => format!("{}[{}]", type_name(ary_of), len),
+        Ctype::Struct(..) => "struct".into(),
+        Ctype::Func(ref returning, _, _) => format!("{} ()", type_name(returning)),
+    }
+}
+
+// Void, or an array of it, has no size, so no object can have the type.
+fn is_void(ty: &Type) -> bool {
+    match ty.ty {
+        Ctype::Void => true,
+        Ctype::Ary(ref ary_of, _) => is_void(ary_of),
+        _ => false,
+    }
+}
+
+fn check_complete(node: &Node) -> Result<(), Diagnostic> {
+    if is_void(&node.ty) {
+        let msg = format!("variable has incomplete type '{}'", type_name(&node.ty));
+        return Err(node.error(&msg));
+    }
+    Ok(())
+}
+
+// Rejects sizeof and _Alignof of an array of unknown length.
+fn check_sizeof(node: &Node, expr: &Node) -> Result<(), Diagnostic> {
+    if expr.ty.is_incomplete_array() {
+        let msg = format!(
+            "invalid application of '{}' to an incomplete type '{}'",
+            if matches!(node.op, NodeType::Sizeof(_)) {
+                "sizeof"
+            } else {
+                "_Alignof"
+            },
+            type_name(&expr.ty)
+        );
+        return Err(node.error(&msg));
+    }
+    Ok(())
+}
+
+fn same_type(x: &Type, y: &Type) -> bool {
+    match (&x.ty, &y.ty) {
+        (Ctype::Char, Ctype::Char)
+        | (Ctype::Short, Ctype::Short)
+        | (Ctype::Int, Ctype::Int)
+        | (Ctype::Long, Ctype::Long) => x.is_unsigned == y.is_unsigned,
+        (Ctype::Enum(x), Ctype::Enum(y)) => x == y,
+        (Ctype::Enum(_), Ctype::Int) | (Ctype::Int, Ctype::Enum(_)) => {
+            !x.is_unsigned && !y.is_unsigned
+        }
+        (Ctype::Void, Ctype::Void) => true,
+        (Ctype::Ptr(x), Ctype::Ptr(y)) => same_type(x, y),
+        (Ctype::Ary(x, xlen), Ctype::Ary(y, ylen)) => xlen == ylen && same_type(x, y),
+        (Ctype::Struct(x, _), Ctype::Struct(y, _)) => x == y,
+        _ => false,
+    }
+}
+
+// Function types are compatible if their return types are the same and
+// their parameters agree. A declaration without a parameter list is
+// compatible with any parameters.
+fn same_func_type(x: &Type, y: &Type) -> bool {
+    match (&x.ty, &y.ty) {
+        (Ctype::Func(xret, xparams, xvar), Ctype::Func(yret, yparams, yvar)) => {
+            same_type(xret, yret)
+                && match (xparams, yparams) {
+                    (Some(xs), Some(ys)) => {
+                        xvar == yvar
+                            && xs.len() == ys.len()
+                            && xs.iter().zip(ys).all(|(x, y)| same_type(x, y))
+                    }
+                    _ => true,
+                }
+        }
+        _ => false,
+    }
+}
+
+// Checks the arguments of a call against the parameters of the called
+// function. Arguments are converted to parameter types as if by
+// assignment; conversions between integers and pointers are allowed
+// with a warning, as most C compilers do.
+fn check_args(
+    node: &Node,
+    func: &Var,
+    args: &[Node],
+    params: &[Type],
+    is_variadic: bool,
+) -> Result<(), Diagnostic> {
+    let declared_here = || {
+        let note = Diagnostic::note(&format!("'{}' declared here", func.name));
+        func.loc.map(|loc| note.with_span(loc.span()))
+    };
+
+    if args.len() < params.len() || (args.len() > params.len() && !is_variadic) {
+        let msg = format!(
+            "too {} arguments to function call, expected {}{}, have {}",
+            if args.len() < params.len() {
+                "few"
+            } else {
+                "many"
+            },
+            if is_variadic { "at least " } else { "" },
+            params.len(),
+            args.len()
+        );
+        let mut err = node.error(&msg);
+        err.notes.extend(declared_here());
+        return Err(err);
+    }
+
+    for (arg, param) in args.iter().zip(params) {
+        let from = type_name(&arg.ty);
+        let to = type_name(param);
+        let msg = match (&arg.ty.ty, &param.ty) {
+            _ if arg.ty.is_integer() && param.is_integer() => continue,
+            (Ctype::Ptr(x), Ctype::Ptr(y)) => {
+                if same_type(x, y) || matches!(x.ty, Ctype::Void) || matches!(y.ty, Ctype::Void) {
+                    continue;
+                }
+                format!(
+                    "incompatible pointer types passing '{}' to parameter of type '{}'",
+                    from, to
+                )
+            }
+            (_, Ctype::Ptr(_)) if arg.ty.is_integer() => {
+                if matches!(arg.op, NodeType::Num(0)) {
+                    continue;
+                }
+                format!(
+                    "incompatible integer to pointer conversion passing '{}' to parameter of type '{}'",
+                    from, to
+                )
+            }
+            (Ctype::Ptr(_), _) if param.is_integer() => format!(
+                "incompatible pointer to integer conversion passing '{}' to parameter of type '{}'",
+                from, to
+            ),
+            _ if same_type(&arg.ty, param) => continue,
+            _ => {
+                let msg = format!(
+                    "passing '{}' to parameter of incompatible type '{}'",
+                    from, to
+                );
+                let mut err = arg.error(&msg);
+                err.notes.extend(declared_here());
+                return Err(err);
+            }
+        };
+        let mut w = arg.error(&msg);
+        w.severity = Severity::Warning;
+        w.notes.extend(declared_here());
+        WARNINGS.lock().unwrap().push(w);
+    }
+    Ok(())
+}
+
+// Structs are not passed to or returned from functions by value.
+fn check_func_type(node: &Node) -> Result<(), Diagnostic> {
+    if let Ctype::Func(ref returning, ref params, _) = node.ty.ty {
+        if let Ctype::Struct(..) = returning.ty {
+            return Err(node.error("returning a struct by value is not supported"));
+        }
+        let params = params.as_deref().unwrap_or_default();
+        if params.iter().any(|ty| matches!(ty.ty, Ctype::Struct(..))) {
+            return Err(node.error("passing a struct by value is not supported"));
+        }
+    }
+    Ok(())
+}
+
+fn warn(node: &Node, msg: &str) {
+    let mut w = node.error(msg);
+    w.severity = Severity::Warning;
+    WARNINGS.lock().unwrap().push(w);
+}
+
+// Checks and annotates the nodes of a translation unit. Warnings are
+// appended to `warnings` whether or not an error is found.
+pub fn sema(
+    nodes: Vec<Node>,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<(Vec<Node>, Vec<Var>), Diagnostic> {
+    // Start afresh, in case an earlier run stopped at an error.
+    GLOBALS.lock().unwrap().clear();
+    *ENV.lock().unwrap() = Env::new(None);
+    *STRLABEL.lock().unwrap() = 0;
+    *STACKSIZE.lock().unwrap() = 0;
+    SWITCHES.lock().unwrap().clear();
+    LABELS.lock().unwrap().clear();
+    GOTOS.lock().unwrap().clear();
+
+    let res = sema_impl(nodes);
+    warnings.append(&mut WARNINGS.lock().unwrap());
+    res
+}
+
+fn sema_impl(nodes: Vec<Node>) -> Result<(Vec<Node>, Vec<Var>), Diagnostic> {
     let mut new_nodes = vec![];
 
     for mut node in nodes {
-        if let NodeType::Vardef(name, _, Scope::Global(data, len, is_extern)) = node.op {
-            let var = Var::new_global(node.ty, name.clone(), data, len, is_extern);
-            GLOBALS.lock().unwrap().push(var.clone());
-            ENV.lock().unwrap().vars.insert(name, var);
+        if matches!(node.op, NodeType::Error) {
+            continue;
+        }
+
+        if let NodeType::Vardef(_, _, Scope::Global(_, _, false)) = node.op {
+            check_complete(&node)?;
+        }
+        if let NodeType::Vardef(name, init, Scope::Global(data, len, is_extern)) = node.op {
+            let mut var = Var {
+                loc: node.loc.map(|(loc, _)| loc),
+                ..Var::new_global(node.ty, name.clone(), data, len, is_extern)
+            };
+            // The initializer may refer to the variable itself.
+            ENV.lock().unwrap().vars.insert(name.clone(), var.clone());
+            if let Some(init) = init {
+                let (ty, data) = global_init(&var.ty, *init)?;
+                var.scope = Scope::Global(data, ty.size, false);
+                var.ty = Box::new(ty);
+                ENV.lock().unwrap().vars.insert(name, var.clone());
+            }
+            GLOBALS.lock().unwrap().push(var);
+            continue;
+        }
+
+        if let NodeType::Vardef(ref name, _, Scope::Enum(val)) = node.op {
+            declare_enum(&node, name.clone(), val)?;
             continue;
         }
 
         let var;
         match &node.op {
             NodeType::Func(name, _, _, _) | NodeType::Decl(name) => {
-                var = Var::new_global(node.ty.clone(), name.clone(), "".into(), 0, false);
+                check_func_type(&node)?;
+                if let Some(prev) = ENV.lock().unwrap().vars.get(name) {
+                    if !same_func_type(&prev.ty, &node.ty) {
+                        let msg = format!("conflicting types for '{}'", name);
+                        let mut err = node.error(&msg);
+                        let note = Diagnostic::note("previous declaration is here");
+                        err.notes
+                            .extend(prev.loc.map(|loc| note.with_span(loc.span())));
+                        return Err(err);
+                    }
+                }
+                var = Var {
+                    loc: node.loc.map(|(loc, _)| loc),
+                    ..Var::new_global(node.ty.clone(), name.clone(), Data::default(), 0, false)
+                };
                 ENV.lock().unwrap().vars.insert(name.clone(), var);
             }
             _ => unreachable!(),
@@ -381,11 +1429,31 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
         }
 
         if let NodeType::Func(name, args, body, _) = node.op {
-            let mut args2 = vec![];
-            for arg in args {
-                args2.push(walk(arg, true));
+            if let Ctype::Func(ref returning, _, _) = node.ty.ty {
+                *RETURN_TYPE.lock().unwrap() = (**returning).clone();
+            }
+            // Parameters are visible only in the function they belong to.
+            let (args2, body2) = into_new_range(
+                (args, *body),
+                Box::new(|(args, body)| {
+                    let mut args2 = vec![];
+                    for arg in args {
+                        args2.push(walk(arg, true)?);
+                    }
+                    Ok((args2, fold(walk(body, true)?)))
+                }),
+            )?;
+            for goto in GOTOS.lock().unwrap().drain(..) {
+                let label = match goto.op {
+                    NodeType::Goto(ref label) => label,
+                    _ => unreachable!(),
+                };
+                if !LABELS.lock().unwrap().contains(label) {
+                    let msg = format!("label used but not defined: {}", label);
+                    return Err(goto.error(&msg));
+                }
             }
-            let body2 = walk(*body, true);
+            LABELS.lock().unwrap().clear();
             node.op = NodeType::Func(
                 name.clone(),
                 args2,
@@ -396,5 +1464,5 @@ pub fn sema(nodes: Vec<Node>) -> (Vec<Node>, Vec<Var>) {
             new_nodes.push(node);
         }
     }
-    (new_nodes, GLOBALS.lock().unwrap().clone())
+    Ok((new_nodes, GLOBALS.lock().unwrap().clone()))
 }
@@ -1,51 +1,97 @@
+use crate::diagnostic::{Diagnostic, Span};
 use crate::preprocess;
 use crate::CharacterType;
 use crate::TokenType;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::rc::Rc;
 
-pub fn tokenize(path: String, ctx: &mut preprocess::Preprocessor) -> Vec<Token> {
-    let mut tokenizer = Tokenizer::new(Rc::new(path));
+pub fn tokenize(
+    path: String,
+    ctx: &mut preprocess::Preprocessor,
+) -> Result<Vec<Token>, Diagnostic> {
+    let input = Tokenizer::read_file(&path)?;
+    tokenize_input(path, input, ctx)
+}
+
+// Tokenizes and preprocesses a file whose contents are already in memory.
+pub fn tokenize_input(
+    path: String,
+    input: String,
+    ctx: &mut preprocess::Preprocessor,
+) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokenizer = Tokenizer::new(Rc::new(path), input);
     tokenizer.canonicalize_newline();
     tokenizer.remove_backslash_newline();
-    tokenizer.scan(&keyword_map());
+    tokenizer.scan(&keyword_map())?;
 
-    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, ctx);
+    tokenizer.tokens = preprocess::preprocess(tokenizer.tokens, ctx)?;
     tokenizer.strip_newlines_tokens();
     tokenizer.join_string_literals();
-    tokenizer.tokens
+    Ok(tokenizer.tokens)
+}
+
+// Tokenizes a string that does not come from a file, such as the result
+// of the preprocessor's `##` operator. No preprocessing is done.
+pub fn tokenize_str(s: &str, filename: Rc<String>) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokenizer = Tokenizer {
+        p: Rc::new(s.chars().collect()),
+        filename,
+        pos: 0,
+        tokens: vec![],
+    };
+    tokenizer.scan(&keyword_map())
 }
 
 fn keyword_map() -> HashMap<String, TokenType> {
     let mut map = HashMap::new();
     map.insert("_Alignof".into(), TokenType::Alignof);
     map.insert("break".into(), TokenType::Break);
+    map.insert("case".into(), TokenType::Case);
     map.insert("char".into(), TokenType::Char);
+    map.insert("continue".into(), TokenType::Continue);
+    map.insert("default".into(), TokenType::Default);
     map.insert("void".into(), TokenType::Void);
     map.insert("do".into(), TokenType::Do);
     map.insert("else".into(), TokenType::Else);
+    map.insert("enum".into(), TokenType::Enum);
     map.insert("extern".into(), TokenType::Extern);
     map.insert("for".into(), TokenType::For);
+    map.insert("goto".into(), TokenType::Goto);
     map.insert("if".into(), TokenType::If);
     map.insert("int".into(), TokenType::Int);
+    map.insert("long".into(), TokenType::Long);
     map.insert("return".into(), TokenType::Return);
+    map.insert("short".into(), TokenType::Short);
+    map.insert("signed".into(), TokenType::Signed);
     map.insert("sizeof".into(), TokenType::Sizeof);
     map.insert("struct".into(), TokenType::Struct);
+    map.insert("switch".into(), TokenType::Switch);
     map.insert("typedef".into(), TokenType::Typedef);
+    map.insert("unsigned".into(), TokenType::Unsigned);
     map.insert("while".into(), TokenType::While);
     map
 }
 
+pub fn keyword_spelling(ty: &TokenType) -> Option<String> {
+    keyword_map()
+        .into_iter()
+        .find(|(_, t)| t == ty)
+        .map(|(name, _)| name)
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     pub ty: TokenType, // Token type
 
     // For preprocessor
     pub stringize: bool,
+    pub hideset: Vec<String>,      // Macros that must not be expanded again
+    pub origin: Option<Rc<Token>>, // Macro invocation this token came from
 
     // For error reporting
     pub buf: Rc<Vec<char>>,
@@ -63,6 +109,8 @@ impl Default for Token {
             start: 0,
             end: 0,
             stringize: false,
+            hideset: vec![],
+            origin: None,
         }
     }
 }
@@ -78,9 +126,37 @@ impl Token {
         }
     }
 
-    pub fn bad_token(&self, msg: &str) -> ! {
-        print_line(&*self.buf, &*self.filename, self.start);
-        panic!("{}", msg);
+    // Returns an error pointing at this token. If the token came from a
+    // macro expansion, a note points at the invocation.
+    pub fn bad_token(&self, msg: &str) -> Diagnostic {
+        let mut d = Diagnostic::error(msg).with_span(self.span());
+        if let Some(ref origin) = self.origin {
+            d = d.with_note(Diagnostic::note("in expansion of macro").with_span(origin.span()));
+        }
+        d
+    }
+
+    pub fn span(&self) -> Span {
+        Span::new(&self.filename, &self.buf, self.start, self.end)
+    }
+
+    // Whether only whitespace precedes the token on its line.
+    pub fn starts_line(&self) -> bool {
+        let before = self.buf[..self.start.min(self.buf.len())].iter().rev();
+        before
+            .take_while(|c| **c != '\n')
+            .all(|c| c.is_whitespace())
+    }
+
+    // Returns the suffix of an integer literal, such as "UL".
+    pub fn int_suffix(&self) -> String {
+        let s = self.tokstr();
+        let len = s
+            .chars()
+            .rev()
+            .take_while(|c| matches!(c, 'u' | 'U' | 'l' | 'L'))
+            .count();
+        s[s.len() - len..].to_string()
     }
 
     pub fn tokstr(&self) -> String {
@@ -97,6 +173,64 @@ impl Token {
             _ => false,
         }
     }
+
+    // Returns where the token is, and the macro invocation it came from.
+    pub fn loc(&self) -> (Loc, Option<Loc>) {
+        (Loc::new(self), self.origin.as_deref().map(Loc::new))
+    }
+}
+
+// A range of a source buffer. Unlike a token, it is small and plain
+// data, so that AST nodes can keep one each and still live in sema's
+// globals. The buffer is looked up by id when a diagnostic is made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loc {
+    file: u32,
+    start: u32,
+    end: u32,
+}
+
+// The buffers locations refer to. They are shared with the tokens, so
+// keeping them here costs no copies.
+#[derive(Default)]
+struct Files {
+    bufs: Vec<(Rc<String>, Rc<Vec<char>>)>,
+    ids: HashMap<*const Vec<char>, u32>,
+}
+
+thread_local! {
+    static FILES: RefCell<Files> = RefCell::default();
+}
+
+impl Loc {
+    fn new(t: &Token) -> Self {
+        let file = FILES.with(|files| {
+            let mut files = files.borrow_mut();
+            let next = files.bufs.len() as u32;
+            let id = *files.ids.entry(Rc::as_ptr(&t.buf)).or_insert(next);
+            if id == next {
+                files.bufs.push((t.filename.clone(), t.buf.clone()));
+            }
+            id
+        });
+        Loc {
+            file,
+            start: t.start as u32,
+            end: t.end as u32,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        FILES.with(|files| {
+            let (ref filename, ref buf) = files.borrow().bufs[self.file as usize];
+            Span::new(filename, buf, self.start as usize, self.end as usize)
+        })
+    }
+
+    // Forgets all buffers. Locations made before are no longer valid.
+    pub fn clear() {
+        FILES.with(|files| *files.borrow_mut() = Files::default());
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -113,9 +247,11 @@ impl Symbol {
 
 lazy_static! {
     static ref SYMBOLS: Vec<Symbol> = [
+        Symbol::new("...", TokenType::Ellipsis),
         Symbol::new("<<=", TokenType::ShlEQ),
         Symbol::new(">>=", TokenType::ShrEQ),
         Symbol::new("!=", TokenType::NE),
+        Symbol::new("##", TokenType::HashHash),
         Symbol::new("&&", TokenType::Logand),
         Symbol::new("++", TokenType::Inc),
         Symbol::new("--", TokenType::Dec),
@@ -149,27 +285,26 @@ struct Tokenizer {
 }
 
 impl Tokenizer {
-    fn new(filename: Rc<String>) -> Self {
+    fn new(filename: Rc<String>, input: String) -> Self {
         Tokenizer {
-            p: Rc::new(Self::read_file(&filename).chars().collect()),
+            p: Rc::new(input.chars().collect()),
             filename,
             pos: 0,
             tokens: vec![],
         }
     }
 
-    fn read_file(filename: &str) -> String {
+    fn read_file(filename: &str) -> Result<String, Diagnostic> {
         let mut input = String::new();
-        let mut fp = io::stdin();
-        if filename != &"-".to_string() {
-            let mut fp = File::open(filename).expect("file not found");
-            fp.read_to_string(&mut input)
-                .expect("something went wrong reading the file");
-            return input;
+        let result = if filename != "-" {
+            File::open(filename).and_then(|mut fp| fp.read_to_string(&mut input))
+        } else {
+            io::stdin().read_to_string(&mut input)
+        };
+        match result {
+            Ok(_) => Ok(input),
+            Err(e) => Err(Diagnostic::error(&format!("{}: {}", filename, e))),
         }
-        fp.read_to_string(&mut input)
-            .expect("something went wrong reading the file");
-        input
     }
 
     fn new_token(&self, ty: TokenType) -> Token {
@@ -193,7 +328,7 @@ impl Tokenizer {
         })
     }
 
-    fn scan(&mut self, keywords: &HashMap<String, TokenType>) -> Vec<Token> {
+    fn scan(&mut self, keywords: &HashMap<String, TokenType>) -> Result<Vec<Token>, Diagnostic> {
         'outer: while let Some(head_char) = self.get_character(0) {
             match head_char {
                 CharacterType::NewLine => {
@@ -203,14 +338,14 @@ impl Tokenizer {
                     self.tokens.push(t);
                 }
                 CharacterType::Whitespace => self.pos += 1,
-                CharacterType::Alphabetic => self.ident(&keywords),
+                CharacterType::Alphabetic => self.ident(keywords),
                 CharacterType::Digit => self.number(),
 
-                CharacterType::NonAlphabetic('\'') => self.char_literal(),
-                CharacterType::NonAlphabetic('\"') => self.string_literal(),
+                CharacterType::NonAlphabetic('\'') => self.char_literal()?,
+                CharacterType::NonAlphabetic('\"') => self.string_literal()?,
                 CharacterType::NonAlphabetic('/') => match self.p.get(self.pos + 1) {
                     Some('/') => self.line_comment(),
-                    Some('*') => self.block_comment(),
+                    Some('*') => self.block_comment()?,
                     Some('=') => {
                         let mut t = self.new_token(TokenType::DivEQ);
                         self.pos += 2;
@@ -254,108 +389,156 @@ impl Tokenizer {
                         self.tokens.push(t);
                         continue 'outer;
                     }
-                    self.bad_position("Unknown symbol.");
+                    return Err(self.bad_position("unknown symbol"));
                 }
-                CharacterType::Unknown(_) => self.bad_position("Unknwon character type."),
+                CharacterType::Unknown(_) => return Err(self.bad_position("unknown character")),
             }
         }
 
-        self.tokens.clone()
+        Ok(self.tokens.clone())
     }
 
     fn line_comment(&mut self) {
-        while self.p.get(self.pos) != Some(&'\n') {
+        while !matches!(self.p.get(self.pos), Some('\n') | None) {
             self.pos += 1;
         }
     }
 
-    fn block_comment(&mut self) {
+    fn block_comment(&mut self) -> Result<(), Diagnostic> {
+        let start = self.pos;
         self.pos += 2;
         loop {
             if let Some(two_char) = self.p.get(self.pos..self.pos + 2) {
                 self.pos += 1;
                 if two_char == ['*', '/'] {
                     self.pos += 1;
-                    return;
+                    return Ok(());
                 }
             } else {
-                self.bad_position("unclosed comment");
+                self.pos = start;
+                return Err(self.bad_position("unclosed comment"));
             }
         }
     }
 
     fn escaped(c: char) -> Option<char> {
-        // Issue: https://github.com/rust-lang/rfcs/issues/751
+        // Rust has no \a, \b, \f or \v.
         match c {
-            // 'a' => Some('\a'),
-            // 'b' => Some('\b'),
-            // 'f' => Some('\f'),
+            'a' => Some('\x07'),
+            'b' => Some('\x08'),
+            'f' => Some('\x0c'),
             'n' => Some('\n'),
             'r' => Some('\r'),
             't' => Some('\t'),
-            // 'v' => Some('\v'),
+            'v' => Some('\x0b'),
             _ => None,
         }
     }
 
-    fn char_literal(&mut self) {
+    // Reads the escape sequence whose backslash is just before p[pos].
+    // Returns the bytes it stands for and the number of characters read.
+    // Octal escapes have up to three digits, hexadecimal ones any number,
+    // and both give a single byte even above 0x7f.
+    fn read_escape(&self, pos: usize) -> Option<(Vec<u8>, usize)> {
+        let c = *self.p.get(pos)?;
+        let (base, skip, max) = match c {
+            '0'..='7' => (8, 0, 3),
+            'x' => (16, 1, usize::MAX),
+            _ => {
+                let c = Self::escaped(c).unwrap_or(c);
+                return Some((c.to_string().into_bytes(), 1));
+            }
+        };
+        let mut val: u32 = 0;
+        let mut len = skip;
+        while len - skip < max {
+            match self.p.get(pos + len).and_then(|c| c.to_digit(base)) {
+                Some(d) => val = val.wrapping_mul(base).wrapping_add(d),
+                None => break,
+            }
+            len += 1;
+        }
+        Some((vec![val as u8], len))
+    }
+
+    fn char_literal(&mut self) -> Result<(), Diagnostic> {
+        let start = self.pos;
         self.pos += 1;
-        let result: char;
-        let c = self.p.get(self.pos).expect("premature end of input");
+        let result: u8;
+        let c = match self.p.get(self.pos) {
+            Some(c) => c,
+            None => return Err(self.bad_position("premature end of input")),
+        };
         if c != &'\\' {
-            result = *c;
+            result = *c as u8;
             self.pos += 1;
         } else {
             self.pos += 1;
-            let c2 = self.p.get(self.pos).unwrap();
-            result = if let Some(esc) = Self::escaped(*c2) {
-                esc
-            } else {
-                *c2
+            let (bytes, len) = match self.read_escape(self.pos) {
+                Some(esc) => esc,
+                None => return Err(self.bad_position("premature end of input")),
             };
-            self.pos += 1;
+            result = bytes[0];
+            self.pos += len;
         }
 
         if self.p.get(self.pos) != Some(&'\'') {
-            panic!("unclosed character literal");
+            self.pos = start;
+            return Err(self.bad_position("unclosed character literal"));
         }
 
-        let mut t = self.new_token(TokenType::Num(result as u8 as i32));
+        // A character constant has type int, with the value of the char
+        // it holds, and char is signed.
+        let mut t = self.new_token(TokenType::Num(result as i8 as i64));
+        t.start = start;
         self.pos += 1;
-        t.end = self.pos + 1;
+        t.end = self.pos;
         self.tokens.push(t);
+        Ok(())
     }
 
-    fn string_literal(&mut self) {
+    fn string_literal(&mut self) -> Result<(), Diagnostic> {
+        let start = self.pos;
         self.pos += 1;
-        let mut sb = String::new();
+        let mut sb = vec![];
         let mut len = 0;
         loop {
-            let mut c2 = self.p.get(self.pos + len).expect("PREMATURE end of input");
+            let c2 = match self.p.get(self.pos + len) {
+                Some(c) if *c != '\n' => c,
+                _ => {
+                    self.pos = start;
+                    return Err(self.bad_position("unclosed string literal"));
+                }
+            };
             if c2 == &'"' {
                 len += 1;
                 self.pos += len;
-                let mut t = self.new_token(TokenType::Str(sb, len));
+                let size = sb.len() + 1; // With the terminating '\0'
+                let mut t = self.new_token(TokenType::Str(sb, size));
                 t.start = self.pos - len - 1;
-                t.end = self.pos + 1;
+                t.end = self.pos;
                 self.tokens.push(t);
-                return;
+                return Ok(());
             }
 
             if c2 != &'\\' {
                 len += 1;
-                sb.push(c2.clone());
+                let mut buf = [0; 4];
+                sb.extend_from_slice(c2.encode_utf8(&mut buf).as_bytes());
                 continue;
             }
 
             len += 1;
-            c2 = self.p.get(self.pos + len).unwrap();
-            if let Some(esc) = Self::escaped(*c2) {
-                sb.push(esc);
-            } else {
-                sb.push(c2.clone());
+            match self.read_escape(self.pos + len) {
+                Some((bytes, n)) => {
+                    sb.extend(bytes);
+                    len += n;
+                }
+                None => {
+                    self.pos = start;
+                    return Err(self.bad_position("unclosed string literal"));
+                }
             }
-            len += 1;
         }
     }
 
@@ -382,30 +565,37 @@ impl Tokenizer {
     }
 
     fn number(&mut self) {
+        let start = self.pos;
         match self.p.get(self.pos..self.pos + 2) {
             Some(&['0', 'x']) | Some(&['0', 'X']) => {
                 self.pos += 2;
-                self.parse_number(16);
+                self.parse_number(16, start);
             }
             Some(&['0', _]) => {
-                self.parse_number(8);
+                self.parse_number(8, start);
             }
-            _ => self.parse_number(10),
+            _ => self.parse_number(10, start),
         }
     }
 
-    fn parse_number(&mut self, base: u32) {
-        let mut sum: i32 = 0;
+    fn parse_number(&mut self, base: u32, start: usize) {
+        let mut sum: u64 = 0;
         let mut len = 0;
         for c in self.p[self.pos..].iter() {
             if let Some(val) = c.to_digit(base) {
-                sum = sum * base as i32 + val as i32;
+                sum = sum.wrapping_mul(base as u64).wrapping_add(val as u64);
                 len += 1;
             } else {
                 break;
             }
         }
-        let mut t = self.new_token(TokenType::Num(sum as i32));
+        // Integer suffixes are left in the spelling of the token, which
+        // is where the parser reads them from to type the literal.
+        while let Some('u' | 'U' | 'l' | 'L') = self.p.get(self.pos + len) {
+            len += 1;
+        }
+        let mut t = self.new_token(TokenType::Num(sum as i64));
+        t.start = start;
         self.pos += len;
         t.end = self.pos;
         self.tokens.push(t);
@@ -447,15 +637,22 @@ impl Tokenizer {
         }
     }
 
-    fn append(&mut self, x_str: &str, y_str: &str, start: usize) -> Token {
-        let concated = format!("{}{}", x_str, y_str);
+    // Joins two adjacent string literals. The result spans from the
+    // start of the first to the end of the second, if both were read
+    // from the same buffer, and comes from wherever the first came from.
+    fn append(x: &Token, y: &Token) -> Token {
+        let (x_str, y_str) = match (&x.ty, &y.ty) {
+            (TokenType::Str(x_str, _), TokenType::Str(y_str, _)) => (x_str, y_str),
+            _ => unreachable!(),
+        };
+        let concated = [&x_str[..], &y_str[..]].concat();
         let l = concated.len() + 1; // Because `+1` has `\0`.
-        Token::new(
-            TokenType::Str(concated, l),
-            start,
-            self.filename.clone(),
-            self.p.clone(),
-        )
+        let mut t = x.clone();
+        t.ty = TokenType::Str(concated, l);
+        if Rc::ptr_eq(&x.buf, &y.buf) && x.origin.is_none() && y.origin.is_none() {
+            t.end = y.end;
+        }
+        t
     }
 
     fn join_string_literals(&mut self) {
@@ -464,12 +661,11 @@ impl Tokenizer {
 
         for t in self.tokens.clone().into_iter() {
             if let Some(ref last) = last_may {
-                if let (TokenType::Str(ref last_str, _), TokenType::Str(ref t_str, _)) =
-                    (&last.ty, &t.ty)
-                {
-                    let new = self.append(last_str, t_str, last.start);
+                if let (TokenType::Str(..), TokenType::Str(..)) = (&last.ty, &t.ty) {
+                    let new = Self::append(last, &t);
                     v.pop();
-                    v.push(new);
+                    v.push(new.clone());
+                    last_may = Some(new);
                     continue;
                 }
             }
@@ -489,47 +685,7 @@ impl Tokenizer {
             .collect()
     }
 
-    fn bad_position(&self, msg: &'static str) {
-        print_line(&self.p, &self.filename, self.pos);
-        panic!("{}", msg);
-    }
-}
-
-// Finds a line pointed by a given pointer from the input file
-// to print it out.
-fn print_line(buf: &[char], path: &str, pos: usize) {
-    let mut p = 0;
-    let mut start = 0;
-    let mut line = 0;
-    let mut col = 0;
-    for c in buf.iter() {
-        if c == &'\n' {
-            start = pos + 1;
-            line += 1;
-            col = 0;
-            p += 1;
-            continue;
-        }
-
-        if p != pos {
-            col += 1;
-            p += 1;
-            continue;
-        }
-
-        print!("error at {}:{}:{}\n\n", path, line + 1, col);
-        break;
-    }
-
-    for p in buf[start..].iter() {
-        if p == &'\n' {
-            break;
-        }
-        print!("{}", p);
-    }
-    println!();
-    for _ in 0..col - 1 {
-        print!(" ");
+    fn bad_position(&self, msg: &str) -> Diagnostic {
+        Diagnostic::error(msg).with_span(Span::new(&self.filename, &self.p, self.pos, self.pos + 1))
     }
-    print!("^\n\n");
 }
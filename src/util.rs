@@ -1,3 +1,85 @@
+use crate::{Scope, Var};
+
 pub fn roundup(x: usize, align: usize) -> usize {
     (x + align - 1) & !(align - 1)
 }
+
+pub fn backslash_escape(s: &[u8]) -> String {
+    let mut sb = String::new();
+    for &b in s {
+        // Issue: https://github.com/rust-lang/rfcs/issues/751
+        let escaped = match b {
+            // b'\b' => Some('b'),
+            // b'\f' => Some('f'),
+            b'\n' => Some('n'),
+            b'\r' => Some('r'),
+            b'\t' => Some('t'),
+            b'\\' => Some('\\'),
+            b'\'' => Some('\''),
+            b'"' => Some('"'),
+            _ => None,
+        };
+        if let Some(esc) = escaped {
+            sb.push('\\');
+            sb.push(esc);
+        } else if b.is_ascii_graphic() || b == b' ' {
+            sb.push(b as char);
+        } else {
+            sb.push_str(&format!("\\{:03o}", b));
+        }
+    }
+    sb
+}
+
+// Emits a global variable in GNU assembler syntax. The directives used
+// here mean the same thing on every target we support.
+pub fn gen_data(var: &Var) {
+    if let Scope::Global(ref data, len, is_extern) = var.scope {
+        if is_extern {
+            return;
+        }
+
+        let size = len.max(var.ty.size).max(data.bytes.len());
+        let is_zero = data.relocs.is_empty() && data.bytes.iter().all(|b| *b == 0);
+        if is_zero {
+            println!(".bss");
+        } else {
+            println!(".data");
+        }
+        if !var.name.starts_with(".L") {
+            println!(".global {}", var.name);
+        }
+        println!("\t.balign {}", var.ty.align.max(1));
+        println!("{}:", var.name);
+
+        // Addresses are left to the linker. The bytes between them
+        // are written out as they are.
+        let mut pos = 0;
+        if !is_zero {
+            for reloc in &data.relocs {
+                gen_bytes(&data.bytes[pos..reloc.offset]);
+                println!("\t.quad {}{:+}", reloc.label, reloc.addend);
+                pos = reloc.offset + 8;
+            }
+            gen_bytes(&data.bytes[pos..]);
+            pos = data.bytes.len();
+        }
+        if size > pos {
+            println!("\t.zero {}", size - pos);
+        }
+        return;
+    }
+    unreachable!();
+}
+
+// Trailing zeros are emitted as `.zero`, since arrays are often
+// initialized only in part.
+fn gen_bytes(bytes: &[u8]) {
+    let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    if len > 0 {
+        println!("\t.ascii \"{}\"", backslash_escape(&bytes[..len]));
+    }
+    if bytes.len() > len {
+        println!("\t.zero {}", bytes.len() - len);
+    }
+}
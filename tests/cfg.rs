@@ -0,0 +1,125 @@
+// Checks the control-flow graph built from hand-written IR, and the
+// removal of unreachable code from compiled functions.
+
+mod common;
+
+use seu9cc::cfg::Cfg;
+use seu9cc::gen_ir::{Function, IROp, IR};
+
+fn func(ir: Vec<IR>) -> Function {
+    Function {
+        name: "f".into(),
+        ir,
+        stacksize: 0,
+    }
+}
+
+fn ir(op: IROp, lhs: usize, rhs: Option<usize>) -> IR {
+    IR::new(op, Some(lhs), rhs)
+}
+
+// Spells out instructions so that they can be compared.
+fn ops(ir: &[IR]) -> Vec<String> {
+    ir.iter()
+        .map(|ir| format!("{:?} {:?} {:?}", ir.op, ir.lhs, ir.rhs))
+        .collect()
+}
+
+fn edges(cfg: &Cfg) -> Vec<(Vec<usize>, Vec<usize>)> {
+    cfg.blocks
+        .iter()
+        .map(|bb| (bb.succ.clone(), bb.pred.clone()))
+        .collect()
+}
+
+#[test]
+fn code_after_return() {
+    let f = func(vec![
+        ir(IROp::Imm, 1, Some(1)),
+        ir(IROp::Return, 1, None),
+        ir(IROp::Imm, 2, Some(2)),
+        ir(IROp::Return, 2, None),
+    ]);
+    let mut cfg = Cfg::new(&f);
+    assert_eq!(edges(&cfg), [(vec![], vec![]), (vec![], vec![])]);
+
+    cfg.remove_unreachable();
+    assert_eq!(cfg.blocks.len(), 1);
+    assert_eq!(ops(&cfg.flatten()), ops(&f.ir[..2]));
+}
+
+#[test]
+fn code_skipped_by_jump() {
+    let f = func(vec![
+        ir(IROp::Jmp, 2, None),
+        ir(IROp::Label, 1, None),
+        ir(IROp::Imm, 1, Some(1)),
+        ir(IROp::Label, 2, None),
+        ir(IROp::Imm, 2, Some(2)),
+        ir(IROp::Return, 2, None),
+    ]);
+    let mut cfg = Cfg::new(&f);
+    assert_eq!(
+        edges(&cfg),
+        [(vec![2], vec![]), (vec![2], vec![]), (vec![], vec![0, 1])]
+    );
+
+    cfg.remove_unreachable();
+    assert_eq!(cfg.blocks.len(), 2);
+    assert_eq!(cfg.blocks[1].label, Some(2));
+    assert_eq!(edges(&cfg), [(vec![1], vec![]), (vec![], vec![0])]);
+    let expected = [&f.ir[..1], &f.ir[3..]].concat();
+    assert_eq!(ops(&cfg.flatten()), ops(&expected));
+}
+
+#[test]
+fn loop_back_edges_are_kept() {
+    // r1 = 10; while (r1) r1--; return r1;
+    let f = func(vec![
+        ir(IROp::Imm, 1, Some(10)),
+        ir(IROp::Label, 1, None),
+        ir(IROp::Unless, 1, Some(2)),
+        ir(IROp::SubImm, 1, Some(1)),
+        ir(IROp::Jmp, 1, None),
+        ir(IROp::Label, 2, None),
+        ir(IROp::Return, 1, None),
+    ]);
+    let mut cfg = Cfg::new(&f);
+    let expected = [
+        (vec![1], vec![]),
+        (vec![2, 3], vec![0, 2]),
+        (vec![1], vec![1]),
+        (vec![], vec![1]),
+    ];
+    assert_eq!(edges(&cfg), expected);
+
+    cfg.remove_unreachable();
+    assert_eq!(edges(&cfg), expected);
+    assert_eq!(ops(&cfg.flatten()), ops(&f.ir));
+}
+
+#[test]
+fn unreachable_loop() {
+    // A loop after a return is removed, although it jumps to itself.
+    let f = func(vec![
+        ir(IROp::Imm, 1, Some(0)),
+        ir(IROp::Return, 1, None),
+        ir(IROp::Label, 1, None),
+        ir(IROp::AddImm, 1, Some(1)),
+        ir(IROp::Jmp, 1, None),
+    ]);
+    let mut cfg = Cfg::new(&f);
+    assert_eq!(edges(&cfg)[1], (vec![1], vec![1]));
+
+    cfg.remove_unreachable();
+    assert_eq!(ops(&cfg.flatten()), ops(&f.ir[..2]));
+}
+
+#[test]
+fn statements_after_goto() {
+    let _guard = common::lock();
+    let src = "int main() { goto end; return 1; end: return 0; }";
+    let (_, fns) = common::compile("t.c", src).unwrap();
+    let returns = fns[0].ir.iter().filter(|ir| ir.op == IROp::Return);
+    assert_eq!(returns.count(), 1);
+}
@@ -0,0 +1,42 @@
+// Helpers shared by the integration tests. Not every test uses all of
+// them.
+#![allow(dead_code)]
+
+use seu9cc::cfg::remove_unreachable;
+use seu9cc::diagnostic::Diagnostic;
+use seu9cc::gen_ir::{gen_ir, Function};
+use seu9cc::parse::parse;
+use seu9cc::preprocess::Preprocessor;
+use seu9cc::sema::sema;
+use seu9cc::token::tokenize_input;
+use seu9cc::Var;
+
+use std::fs;
+use std::sync::{Mutex, MutexGuard};
+
+// The compiler keeps its state in globals, so tests that compile must
+// not run at the same time.
+static LOCK: Mutex<()> = Mutex::new(());
+
+pub fn lock() -> MutexGuard<'static, ()> {
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Compiles C source to IR. The caller must hold lock().
+pub fn compile(path: &str, src: &str) -> Result<(Vec<Var>, Vec<Function>), Diagnostic> {
+    let tokens = tokenize_input(path.into(), src.into(), &mut Preprocessor::new())?;
+    let mut errors = vec![];
+    let nodes = parse(&tokens, &mut errors);
+    if let Some(e) = errors.into_iter().next() {
+        return Err(e);
+    }
+    let (nodes, globals) = sema(nodes, &mut vec![])?;
+    let mut fns = gen_ir(nodes)?;
+    remove_unreachable(&mut fns);
+    Ok((globals, fns))
+}
+
+pub fn read_example(name: &str) -> String {
+    let path = format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), name);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
@@ -0,0 +1,393 @@
+// Checks the errors and warnings reported for invalid programs.
+
+mod common;
+
+use seu9cc::parse::parse;
+use seu9cc::preprocess::Preprocessor;
+use seu9cc::sema::sema;
+use seu9cc::token::tokenize_input;
+
+// Compiles source up to sema and returns the messages of the
+// diagnostics, errors last.
+fn diagnose(src: &str) -> Vec<String> {
+    let _guard = common::lock();
+    let tokens = tokenize_input("t.c".into(), src.into(), &mut Preprocessor::new())
+        .unwrap_or_else(|e| panic!("{}", e));
+    let mut errors = vec![];
+    let nodes = parse(&tokens, &mut errors);
+    if !errors.is_empty() {
+        return errors.into_iter().map(|e| e.message).collect();
+    }
+    let mut warnings = vec![];
+    let res = sema(nodes, &mut warnings);
+    let mut v: Vec<String> = warnings.into_iter().map(|w| w.message).collect();
+    if let Err(e) = res {
+        v.push(e.message);
+    }
+    v
+}
+
+#[test]
+fn structs_are_distinct_types() {
+    let src = "struct A { int x; }; struct B { int x; };
+               int f(struct A *p) { return p->x; }
+               int main() { struct B b; return f(&b); }";
+    assert_eq!(
+        diagnose(src),
+        ["incompatible pointer types passing 'struct *' to parameter of type 'struct *'"]
+    );
+    let src = "struct A { int x; }; struct A *p; int f(struct A *q); int g() { return f(p); }";
+    assert!(diagnose(src).is_empty());
+}
+
+#[test]
+fn forward_declared_struct() {
+    let src = "struct S; int f(struct S *p);
+               struct S { struct S *next; };
+               int f(struct S *p) { return f(p->next); }";
+    assert!(diagnose(src).is_empty());
+}
+
+#[test]
+fn conflicting_function_types() {
+    assert_eq!(
+        diagnose("int f(int); int f(char);"),
+        ["conflicting types for 'f'"]
+    );
+    assert_eq!(
+        diagnose("int f(int); long f(int);"),
+        ["conflicting types for 'f'"]
+    );
+    assert!(diagnose("int f(); int f(int a) { return a; } int f(int);").is_empty());
+}
+
+#[test]
+fn parameters_are_local() {
+    let src = "int f(int x) { return x; } int g() { return x; }";
+    assert_eq!(diagnose(src), ["undefined variable: x"]);
+    assert!(diagnose("int f(unsigned short g) { return g; } unsigned g(unsigned x);").is_empty());
+}
+
+#[test]
+fn void_objects() {
+    let msg = "variable has incomplete type 'void'";
+    assert_eq!(diagnose("int main() { void v; return 0; }"), [msg]);
+    assert_eq!(diagnose("void g;"), [msg]);
+    assert_eq!(diagnose("int f(void v) { return 0; }"), [msg]);
+    assert_eq!(
+        diagnose("int main() { void v[3]; return 0; }"),
+        ["variable has incomplete type 'void[3]'"]
+    );
+    assert_eq!(
+        diagnose("struct S { void v; };"),
+        ["field has incomplete type"]
+    );
+    assert_eq!(
+        diagnose("int f(void *p) { return p[1]; }"),
+        ["cannot dereference void pointer"]
+    );
+    assert!(diagnose("extern void g; void *f(void *p) { return p; }").is_empty());
+}
+
+#[test]
+fn duplicate_enumerators() {
+    let msg = ["redefinition of 'A'"];
+    assert_eq!(diagnose("enum E { A, A };"), msg);
+    assert_eq!(diagnose("int A; enum E { A };"), msg);
+    assert_eq!(diagnose("int f() { enum { A, B, A }; return 0; }"), msg);
+    assert!(diagnose("enum E { A }; int f() { enum F { A }; return A; }").is_empty());
+}
+
+#[test]
+fn enumerator_range() {
+    assert_eq!(
+        diagnose("enum E { A = 2147483647, B };"),
+        ["enumerator value 2147483648 is not representable in int"]
+    );
+    assert_eq!(
+        diagnose("enum E { A = -2147483649 };"),
+        ["enumerator value -2147483649 is not representable in int"]
+    );
+    assert!(diagnose("enum E { A = -2147483648, B = 2147483647 };").is_empty());
+}
+
+#[test]
+fn errors_point_at_the_source() {
+    let _guard = common::lock();
+    let src = "#define DEREF(x) *x\nint main() {\n  int a;\n  return DEREF(a);\n}\n";
+    let err = common::compile("t.c", src).unwrap_err();
+    let span = err.span.unwrap();
+    assert_eq!((span.line, span.col, span.len), (1, 18, 1));
+    assert_eq!(err.notes.len(), 1);
+    let span = err.notes[0].span.as_ref().unwrap();
+    assert_eq!((span.line, span.col, span.len), (4, 10, 5));
+    assert_eq!(span.source, "  return DEREF(a);");
+}
+
+#[test]
+fn structs_by_value() {
+    let decl = "struct S { int a; }; ";
+    let diag = |src: &str| diagnose(&format!("{}{}", decl, src));
+    assert_eq!(
+        diag("int f(struct S s);"),
+        ["passing a struct by value is not supported"]
+    );
+    assert_eq!(
+        diag("struct S f();"),
+        ["returning a struct by value is not supported"]
+    );
+    assert_eq!(
+        diag("int f(); int g() { struct S s; return f(s); }"),
+        ["passing a struct by value is not supported"]
+    );
+    let msg = ["operand of type 'struct' where arithmetic or pointer type is required"];
+    assert_eq!(diag("int g() { struct S s; return s + 1; }"), msg);
+    assert_eq!(
+        diag("int g() { struct S s; if (s) return 1; return 0; }"),
+        msg
+    );
+    assert!(diag("int g() { struct S s; struct S t; s = t; t = s = t; return s.a; }").is_empty());
+}
+
+#[test]
+fn duplicate_case_values() {
+    let sw = |ty: &str, cases: &str| {
+        diagnose(&format!(
+            "int f({} x) {{ switch (x) {{ {} }} return 0; }}",
+            ty, cases
+        ))
+    };
+    assert_eq!(
+        sw("unsigned", "case -1: case 4294967295: ;"),
+        ["duplicate case value: 4294967295"]
+    );
+    assert_eq!(
+        sw("int", "case 1: case 4294967297l: ;"),
+        ["duplicate case value: 1"]
+    );
+    assert_eq!(
+        sw("char", "case 'a': case 97: ;"),
+        ["duplicate case value: 97"]
+    );
+    assert!(sw("long", "case -1: case 4294967295: ;").is_empty());
+    assert!(sw("char", "case -1: case 255: ;").is_empty());
+}
+
+#[test]
+fn labels() {
+    assert_eq!(
+        diagnose("int main() { a: a: return 0; }"),
+        ["duplicate label: a"]
+    );
+    assert_eq!(
+        diagnose("int main() { goto out; return 0; }"),
+        ["label used but not defined: out"]
+    );
+    // Labels belong to the function they are defined in.
+    assert_eq!(
+        diagnose("int f() { out: return 0; } int main() { goto out; return 1; }"),
+        ["label used but not defined: out"]
+    );
+    assert!(diagnose("int f() { a: return 0; } int g() { a: goto a; return 0; }").is_empty());
+    let _guard = common::lock();
+    let err = common::compile("t.c", "int main() { continue; return 0; }").unwrap_err();
+    assert_eq!(err.message, "stray 'continue' statement");
+}
+
+#[test]
+fn arrays_and_functions_are_not_assignable() {
+    let msg = ["array type is not assignable"];
+    assert_eq!(diagnose("int main() { int a[3]; a = 0; return 0; }"), msg);
+    assert_eq!(diagnose("int main() { int a[3]; a += 1; return 0; }"), msg);
+    assert_eq!(diagnose("int main() { int a[3]; a++; return 0; }"), msg);
+    assert_eq!(
+        diagnose("int main() { int a[2][2]; a[0] = 0; return 0; }"),
+        msg
+    );
+    assert_eq!(
+        diagnose("struct S { int a[2]; } s; int main() { s.a = 0; return 0; }"),
+        msg
+    );
+    assert_eq!(
+        diagnose("int f(); int main() { f = 0; return 0; }"),
+        ["function type is not assignable"]
+    );
+    assert_eq!(
+        diagnose("int main() { int x; (x + 1)++; return 0; }"),
+        ["not an lvalue"]
+    );
+    // Their addresses can still be taken.
+    assert!(
+        diagnose("int f(); int main() { int a[3]; int (*p)[3] = &a; return &f != 0; }").is_empty()
+    );
+}
+
+// Returns the line and message of each syntax error.
+fn syntax_errors(src: &str) -> Vec<(usize, String)> {
+    let _guard = common::lock();
+    let tokens = tokenize_input("t.c".into(), src.into(), &mut Preprocessor::new())
+        .unwrap_or_else(|e| panic!("{}", e));
+    let mut errors = vec![];
+    parse(&tokens, &mut errors);
+    errors
+        .into_iter()
+        .map(|e| (e.span.unwrap().line, e.message))
+        .collect()
+}
+
+#[test]
+fn expected_tokens_are_spelled() {
+    assert_eq!(diagnose("int x int y;"), ["';' expected"]);
+    assert_eq!(diagnose("int main() { return (1; }"), ["')' expected"]);
+    assert_eq!(
+        diagnose("int main() { do ; (1); return 0; }"),
+        ["'while' expected"]
+    );
+}
+
+#[test]
+fn errors_after_recovery() {
+    // Every statement with an error is reported, once.
+    let src = "int main() {
+  int a = 1
+  int b = 2;
+  if (a { b = 3; }
+  while (b return 1;
+  return a + b
+}
+int f() { return 2 }
+int g() { return (1 + ; }
+";
+    let msg = |line, s: &str| (line, s.to_string());
+    assert_eq!(
+        syntax_errors(src),
+        [
+            msg(3, "';' expected"),
+            msg(4, "')' expected"),
+            msg(5, "')' expected"),
+            msg(7, "';' expected"),
+            msg(8, "';' expected"),
+            msg(9, "number expected"),
+        ]
+    );
+
+    // An error inside the braces of a struct or an initializer skips to
+    // the end of the declaration, without errors for what follows.
+    let src = "struct S { int a; int b } s;
+int v[3] = { 1, 2 3 };
+struct T { int a; int 3; int c; };
+int x = ;
+int main() { return 0; }
+";
+    assert_eq!(
+        syntax_errors(src),
+        [
+            msg(1, "';' expected"),
+            msg(2, "'}' expected"),
+            msg(3, "bad direct-declarator"),
+            msg(4, "number expected"),
+        ]
+    );
+}
+
+#[test]
+fn void_values() {
+    let decl = "void f(); int g(int x); int printf(); ";
+    let diag = |body: &str| diagnose(&format!("{}int main() {{ {} }}", decl, body));
+    let msg = ["void value not ignored as it ought to be"];
+    assert_eq!(diag("int x = f(); return 0;"), msg);
+    assert_eq!(diag("int x; x = f(); return 0;"), msg);
+    assert_eq!(diag("int a[2] = { 1, f() }; return 0;"), msg);
+    assert_eq!(diag("return g(f());"), msg);
+    assert_eq!(diag("return printf(\"%d\", f());"), msg);
+    assert_eq!(diag("return 1 + f();"), msg);
+    assert_eq!(diag("if (f()) return 1; return 0;"), msg);
+    assert_eq!(diag("return f();"), msg);
+    assert_eq!(diag("int x = 1 ? f() : f(); return 0;"), msg);
+    assert!(diag("f(); (void)f(); 1 ? f() : f(); return (f(), 0);").is_empty());
+    // A void function may return a void expression.
+    assert!(diagnose("void f(); void h() { return f(); }").is_empty());
+}
+
+#[test]
+fn unary_plus() {
+    let msg = |ty: &str| {
+        vec![format!(
+            "invalid argument type '{}' to unary expression",
+            ty
+        )]
+    };
+    assert_eq!(
+        diagnose("int main() { int *p = 0; return +p; }"),
+        msg("int *")
+    );
+    assert_eq!(
+        diagnose("int main() { int a[3]; return +a; }"),
+        msg("int *")
+    );
+    assert_eq!(
+        diagnose("struct S { int a; } s; int main() { return +s; }"),
+        msg("struct")
+    );
+    assert_eq!(
+        diagnose("int main() { int x; +x = 1; return 0; }"),
+        ["not an lvalue"]
+    );
+}
+
+#[test]
+fn incomplete_arrays() {
+    let sizeof = "invalid application of 'sizeof' to an incomplete type 'int[]'";
+    let alignof = "invalid application of '_Alignof' to an incomplete type 'int[]'";
+    assert_eq!(diagnose("int main() { return sizeof(int[]); }"), [sizeof]);
+    assert_eq!(
+        diagnose("int main() { return _Alignof(int[]); }"),
+        [alignof]
+    );
+    assert_eq!(
+        diagnose("int main() { int a[]; return 0; }"),
+        ["definition of variable with array type needs an explicit size or an initializer"]
+    );
+    assert_eq!(
+        diagnose("struct S { int a[]; int n; };"),
+        ["flexible array member is not at the end of struct"]
+    );
+    assert_eq!(
+        diagnose("struct S { int a[]; };"),
+        ["flexible array member in otherwise empty struct"]
+    );
+    assert_eq!(
+        diagnose("int a[2][];"),
+        ["array has incomplete element type"]
+    );
+    assert_eq!(
+        diagnose("int f(int a[][]);"),
+        ["array has incomplete element type"]
+    );
+    // An array of unknown length is fine as a parameter, as the last
+    // member of a struct, or with an initializer.
+    let src = "struct S { int n; int a[]; }; int f(int a[]); int g[] = { 1, 2 };";
+    assert!(diagnose(src).is_empty());
+    assert!(diagnose("int main() { int a[] = { 1 }; return sizeof(a); }").is_empty());
+}
+
+#[test]
+fn shadowed_enumerators() {
+    // A variable hides an enumerator, which then can't size an array.
+    let msg = ["constant expression expected"];
+    let enums = "enum { N = 3 }; ";
+    let diag = |src: &str| diagnose(&format!("{}{}", enums, src));
+    assert_eq!(
+        diag("int main() { int N = 5; { int a[N]; } return 0; }"),
+        msg
+    );
+    assert_eq!(diag("int main() { for (int N = 0;;) { int a[N]; } }"), msg);
+    assert_eq!(diag("int f(int N) { int a[N]; return 0; }"), msg);
+    assert_eq!(
+        diag("int main() { int x = ({ int N = 1; sizeof(int[N]); }); }"),
+        msg
+    );
+    // It is back in view once the variable's scope ends.
+    let src = "int f(int N) { return N; } int main() { { int N; } for (int N;;); int a[N]; }";
+    assert!(diag(src).is_empty());
+}
@@ -0,0 +1,94 @@
+// Checks constant folding through the IR of compiled expressions.
+
+mod common;
+
+use seu9cc::gen_ir::{IROp, IR};
+use seu9cc::Scope;
+
+fn compile(expr: &str) -> Vec<IR> {
+    let _guard = common::lock();
+    let src = format!("long f() {{ return {}; }}", expr);
+    let (_, mut fns) = common::compile("t.c", &src).unwrap();
+    fns.remove(0).ir
+}
+
+// Returns the value of a fully folded expression.
+fn fold(expr: &str) -> Option<i64> {
+    let ir = compile(expr);
+    match (&ir[0].op, &ir[1..]) {
+        (IROp::Imm, [ret]) if ret.op == IROp::Return => Some(ir[0].rhs? as i64),
+        _ => None,
+    }
+}
+
+fn has_op(expr: &str, op: IROp) -> bool {
+    compile(expr).iter().any(|ir| ir.op == op)
+}
+
+#[test]
+fn signed_arithmetic() {
+    assert_eq!(fold("1 + 2 * 3"), Some(7));
+    assert_eq!(fold("-7 / 2"), Some(-3));
+    assert_eq!(fold("-7 % 2"), Some(-1));
+    assert_eq!(fold("2147483647 + 1"), Some(-2147483648));
+    assert_eq!(fold("(-2147483647 - 1) / -1"), Some(-2147483648));
+    assert_eq!(fold("9223372036854775807 + 1"), Some(i64::MIN));
+    assert_eq!(fold("-8 >> 1"), Some(-4));
+}
+
+#[test]
+fn unsigned_wraparound() {
+    assert_eq!(fold("0u - 1"), Some(4294967295));
+    assert_eq!(fold("4294967295u + 2"), Some(1));
+    assert_eq!(fold("-1u >> 31"), Some(1));
+    assert_eq!(fold("-1u / 2"), Some(2147483647));
+    assert_eq!(fold("0ul - 1"), Some(-1));
+    assert_eq!(fold("-1 < 0u"), Some(0));
+    assert_eq!(fold("-1 < 0"), Some(1));
+    assert_eq!(fold("(unsigned char)300"), Some(44));
+    assert_eq!(fold("(signed char)200"), Some(-56));
+    assert_eq!(fold("(unsigned short)-1"), Some(65535));
+}
+
+#[test]
+fn division_by_zero() {
+    // Left for the program to hit at runtime.
+    assert_eq!(fold("1 / 0"), None);
+    assert!(has_op("1 / 0", IROp::Div));
+    assert!(has_op("1 % 0", IROp::Mod));
+    assert!(has_op("1u / 0", IROp::UDiv));
+    assert!(has_op("1u % (2 - 2)", IROp::UMod));
+}
+
+#[test]
+fn shifts_of_width_or_more() {
+    assert_eq!(fold("1 << 31"), Some(-2147483648));
+    assert_eq!(fold("1l << 63"), Some(i64::MIN));
+    assert_eq!(fold("1 << 32"), None);
+    assert!(has_op("1 << 32", IROp::SHL));
+    assert!(has_op("1 >> 40", IROp::SHR));
+    assert!(has_op("1u >> 32", IROp::USHR));
+    assert!(has_op("1 << -1", IROp::SHL));
+    assert!(has_op("1l << 64", IROp::SHL));
+}
+
+#[test]
+fn identities() {
+    let ir = compile("f() * 1 + 0");
+    assert!(!ir.iter().any(|ir| ir.op == IROp::Mul || ir.op == IROp::Add));
+}
+
+#[test]
+fn array_lengths() {
+    let _guard = common::lock();
+    let src = "int a[4294967295u + 2]; int b[1 << 3 | 1]; int c[-1 < 0u ? 2 : 3];";
+    let (globals, _) = common::compile("t.c", src).unwrap();
+    let sizes: Vec<_> = globals
+        .iter()
+        .map(|v| match v.scope {
+            Scope::Global(_, len, _) => len,
+            _ => panic!("{} is not a global", v.name),
+        })
+        .collect();
+    assert_eq!(sizes, [4, 36, 12]);
+}
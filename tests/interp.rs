@@ -0,0 +1,412 @@
+// Runs programs through the IR interpreter and checks what they print
+// and the status they exit with.
+
+mod common;
+
+use seu9cc::gen_ir::{Function, IROp, IR};
+use seu9cc::interp::Interpreter;
+
+// Returns the exit status and the output, or the runtime error message.
+fn run(path: &str, src: &str) -> Result<(i32, String), String> {
+    let _guard = common::lock();
+    let (globals, fns) = common::compile(path, src).unwrap_or_else(|e| panic!("{}", e));
+    let mut out = vec![];
+    let status = Interpreter::new(&globals, &fns)
+        .and_then(|mut interp| interp.run(&mut out))
+        .map_err(|e| e.message)?;
+    Ok((status, String::from_utf8(out).unwrap()))
+}
+
+fn run_example(name: &str) -> (i32, String) {
+    run(name, &common::read_example(name)).unwrap()
+}
+
+#[test]
+fn examples_exit_successfully() {
+    for name in ["example.c", "fib.c", "nqueen.c", "prime.c"] {
+        assert_eq!(run_example(name).0, 0, "{}", name);
+    }
+}
+
+#[test]
+fn prime_prints_primes() {
+    let (_, out) = run_example("prime.c");
+    assert!(
+        out.starts_with("prime!: 3\nprime!: 5\nprime!: 7\n"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn exit_status() {
+    assert_eq!(run("t.c", "int main() { return 42; }"), Ok((42, "".into())));
+    let src = "void exit(int); int f() { exit(3); return 1; } int main() { return f(); }";
+    assert_eq!(run("t.c", src), Ok((3, "".into())));
+    let src = "int printf(); int main() { printf(\"%d-%s\", 7, \"x\"); return 0; }";
+    assert_eq!(run("t.c", src), Ok((0, "7-x".into())));
+}
+
+#[test]
+fn runtime_errors() {
+    let err = run("t.c", "int main() { int *p = 0; return *p; }").unwrap_err();
+    assert_eq!(err, "segmentation fault at address 0x0");
+    let err = run("t.c", "int main() { int z = 0; return 1 / z; }").unwrap_err();
+    assert_eq!(err, "division by zero in main");
+    let err = run("t.c", "int foo(); int main() { return foo(); }").unwrap_err();
+    assert_eq!(err, "undefined function: foo");
+}
+
+#[test]
+fn invalid_access_sizes() {
+    // r1 = address of a local; op r1, r1
+    let run_ir = |op: IROp| {
+        let ir = vec![
+            IR::new(IROp::Bprel, Some(1), Some(8)),
+            IR::new(op, Some(1), Some(1)),
+            IR::new(IROp::Return, Some(1), None),
+        ];
+        let fns = [Function {
+            name: "main".into(),
+            ir,
+            stacksize: 16,
+        }];
+        let mut interp = Interpreter::new(&[], &fns).unwrap();
+        interp.run(&mut vec![]).map_err(|e| e.message)
+    };
+    assert_eq!(run_ir(IROp::Load(3)).unwrap_err(), "invalid load size: 3");
+    assert_eq!(run_ir(IROp::Load(0)).unwrap_err(), "invalid load size: 0");
+    assert_eq!(
+        run_ir(IROp::Store(16)).unwrap_err(),
+        "invalid store size: 16"
+    );
+    assert!(run_ir(IROp::Load(8)).is_ok());
+}
+
+#[test]
+fn deep_recursion() {
+    let f = "int f(int n) { if (n == 0) return 0; return 1 + f(n - 1); }";
+    let src = format!("{} int main() {{ return f(10000); }}", f);
+    assert_eq!(run("t.c", &src), Ok((10000, "".into())));
+    // The program's stack runs out long before the host's would.
+    let src = format!("{} int main() {{ return f(1000000); }}", f);
+    assert_eq!(run("t.c", &src).unwrap_err(), "stack overflow in f");
+}
+
+#[test]
+fn switch_statements() {
+    let src = "
+int printf();
+int classify(int n) {
+  int r = 0;
+  switch (n) {
+  case 1:
+    r += 1;
+  case 2:
+    r += 10;
+    break;
+  default:
+    r = -1;
+  case 3:
+    r += 100;
+    break;
+  case 4:
+    switch (n * 2) {
+    case 8:
+      r = 8;
+      break;
+    default:
+      r = 9;
+    }
+    r += 1000;
+  }
+  return r;
+}
+int main() {
+  for (int i = 0; i < 6; i++)
+    printf(\"%d \", classify(i));
+  return 0;
+}
+";
+    assert_eq!(run("t.c", src), Ok((0, "99 11 10 100 1008 99 ".into())));
+}
+
+#[test]
+fn switch_case_conversion() {
+    // Case values are converted to the promoted type of the condition.
+    let src = "
+int main() {
+  unsigned u = 4294967295u;
+  switch (u) {
+  case -1:
+    break;
+  default:
+    return 1;
+  }
+  char c = -1;
+  switch (c) {
+  case 255:
+    return 2;
+  case -1:
+    break;
+  }
+  unsigned char uc = 255;
+  switch (uc) {
+  case -1:
+    return 3;
+  case 255:
+    break;
+  }
+  switch (1l << 32) {
+  case 0:
+    return 4;
+  case 1l << 32:
+    break;
+  }
+  return 0;
+}
+";
+    assert_eq!(run("t.c", src), Ok((0, "".into())));
+}
+
+#[test]
+fn continue_statements() {
+    let src = "
+int printf();
+int main() {
+  for (int i = 0; i < 10; i++) {
+    if (i % 3)
+      continue;
+    printf(\"%d \", i);
+  }
+  int j = 0;
+  do {
+    j++;
+    if (j == 2)
+      continue;
+    printf(\"d%d \", j);
+  } while (j < 4);
+  int k = 0;
+  while (k < 5) {
+    k++;
+    if (k & 1)
+      continue;
+    printf(\"w%d \", k);
+  }
+  for (int a = 0; a < 2; a++)
+    for (int b = 0; b < 3; b++) {
+      if (b == 1)
+        continue;
+      printf(\"%d%d \", a, b);
+    }
+  return 0;
+}
+";
+    let out = "0 3 6 9 d1 d3 d4 w2 w4 00 02 10 12 ";
+    assert_eq!(run("t.c", src), Ok((0, out.into())));
+}
+
+#[test]
+fn goto_statements() {
+    let src = "
+int printf();
+int main() {
+  int i = 0;
+again:
+  printf(\"%d \", i);
+  if (++i < 3)
+    goto again;
+  goto skip;
+  printf(\"not printed \");
+skip:
+  for (int a = 0; a < 10; a++)
+    for (int b = 0; b < 10; b++)
+      if (a * b == 12)
+        goto found;
+  return 1;
+found:
+  printf(\"found\");
+  return 0;
+}
+";
+    assert_eq!(run("t.c", src), Ok((0, "0 1 2 found".into())));
+}
+
+#[test]
+fn bundled_headers() {
+    let src = "
+#include <limits.h>
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+int main() {
+  char buf[4];
+  memset(buf, 'a', 3);
+  buf[3] = 0;
+  bool ok = sizeof(size_t) == 8 && INT_MAX == 2147483647;
+  printf(\"%d %s \", ok, buf);
+  putchar('!');
+  return EXIT_SUCCESS;
+}
+";
+    assert_eq!(run("t.c", src), Ok((0, "1 aaa !".into())));
+}
+
+#[test]
+fn high_bytes_in_literals() {
+    let src = "
+int printf();
+char g[] = \"\\xff\\200a\";
+char *p = \"\\377é\";
+int main() {
+  char s[] = \"\\xff\\200\";
+  printf(\"%d %d %d %d \", sizeof(s), s[0], s[1], sizeof(\"é\"));
+  printf(\"%d %d %d %d \", sizeof(g), g[0], g[1], g[2]);
+  printf(\"%d %d %d %d \", p[0], p[1], p[2], p[3]);
+  printf(\"%d %d %d\", '\\xff', '\\200', '\\x41');
+  return 0;
+}
+";
+    let out = "3 -1 -128 3 4 -1 -128 97 -1 -61 -87 0 -1 -128 65";
+    assert_eq!(run("t.c", src), Ok((0, out.into())));
+}
+
+#[test]
+fn integer_conversions() {
+    // The expected output is what gcc gives on x86-64.
+    let src = "
+int printf();
+int main() {
+  char c = 200;
+  unsigned char uc = 200;
+  short s = 70000;
+  unsigned short us = -1;
+  int i = -1;
+  unsigned u = 1;
+  long l = -1;
+  unsigned long ul = 1;
+  printf(\"%d %d %d %d\\n\", c, uc, s, us);
+  printf(\"%d %d %d\\n\", c + 1000, uc * 2, (char)(c + 100));
+  printf(\"%d %d %d %d\\n\", i < u, i < l, l < ul, (long)i < (long)u);
+  printf(\"%u %ld %lu %ld\\n\", i + u, i + l, u + l, (long)u - 2);
+  printf(\"%ld %ld %ld\\n\", (long)i, (long)(unsigned)i, (unsigned long)(unsigned short)i);
+  printf(\"%d %d %d\\n\", (int)4294967297l, (short)65537, (signed char)255);
+  printf(\"%u %d %u\\n\", -1u / 2, -7 / 2, -7u % 3);
+  printf(\"%d %d\\n\", (unsigned)-1 >> 31, -1 >> 31);
+  printf(\"%d %d %d\\n\", c == -56, uc == 200, us == 65535);
+  c += 100;
+  uc += 100;
+  us *= 2;
+  s = 32767;
+  s++;
+  printf(\"%d %d %d %d\\n\", c, uc, us, s);
+  unsigned x = 3000000000u;
+  long y = x;
+  int z = x;
+  printf(\"%ld %d %d\\n\", y, z, x > 0);
+  printf(\"%d %d\\n\", sizeof(c + c), sizeof(ul + i));
+  return 0;
+}
+";
+    let out = "\
+-56 200 4464 65535\n\
+944 400 44\n\
+0 0 0 1\n\
+0 -2 0 -1\n\
+-1 4294967295 65535\n\
+1 1 -1\n\
+2147483647 -3 0\n\
+1 -1\n\
+1 1 1\n\
+44 44 65534 -32768\n\
+3000000000 -1294967296 1\n\
+4 8\n\
+";
+    assert_eq!(run("t.c", src), Ok((0, out.into())));
+}
+
+#[test]
+fn unary_plus() {
+    // `+` promotes its operand, and folds in constant expressions.
+    let src = "
+int printf();
+enum E { A = 5 };
+int main() {
+  char c = -1;
+  unsigned char u = 200;
+  int a[+3];
+  printf(\"%d %d %d %d %d \", sizeof(+c), sizeof(c), sizeof(a), +u, +-c);
+  switch (5) {
+  case +A:
+    printf(\"%d\", +(unsigned short)-1);
+  }
+  return 0;
+}
+";
+    assert_eq!(run("t.c", src), Ok((0, "4 1 12 200 1 65535".into())));
+}
+
+#[test]
+fn initializers() {
+    // The expected output is what gcc gives. `big` and `t` are large
+    // enough for their gaps to be cleared by ZeroFill.
+    let src = "
+int printf();
+struct P { int x; int y; };
+struct S { char c; struct P p[2]; int n; };
+int x = 7;
+int a[5] = { [3] = 4, 5, [1] = 2 };
+int b[2][3] = { 1, 2, 3, { 4 }, };
+struct S s = { 'z', { 2, 3 }, .p[1].y = 6, 9 };
+int *ptrs[3] = { &x, [0] = 0, [2] = &x };
+long vals[2] = { (long)&x, [0] = 5 };
+struct { int *p; int v; } sp = { .p = 0, .v = 1, .p = &x };
+int main() {
+  int i;
+  for (i = 0; i < 5; i++) printf(\"%d \", a[i]);
+  for (i = 0; i < 6; i++) printf(\"%d \", b[i / 3][i % 3]);
+  printf(\"%c %d %d %d %d %d \", s.c, s.p[0].x, s.p[0].y, s.p[1].x, s.p[1].y, s.n);
+  printf(\"%d %d %d %d %d \", ptrs[0] == 0, *ptrs[2], ptrs[1] == 0, (int)vals[0], vals[1] == 0);
+  printf(\"%d %d \", *sp.p, sp.v);
+  int big[20] = { [5] = 1, 2, [18] = 3 };
+  int sum = 0;
+  for (i = 0; i < 20; i++) sum = sum * 3 + big[i];
+  printf(\"%d \", sum);
+  struct { long l[8]; char c[2]; } t = { { [7] = 8 }, 'a', .l[0] = 1 };
+  printf(\"%ld %ld %ld %d %d \", t.l[0], t.l[1], t.l[7], t.c[0], t.c[1]);
+  int m[2][2] = { [1] = { 3 }, [0][1] = 2 };
+  printf(\"%d %d %d %d\", m[0][0], m[0][1], m[1][0], m[1][1]);
+  return 0;
+}
+";
+    let out = "0 2 0 4 5 1 2 3 4 0 0 z 2 3 0 6 9 1 7 1 5 1 7 1 7971624 1 0 8 97 0 0 2 3 0";
+    assert_eq!(run("t.c", src), Ok((0, out.into())));
+}
+
+#[test]
+fn shadowed_enumerators() {
+    let src = "
+int printf();
+enum { N = 3 };
+int main() {
+  int a[N];
+  {
+    int N = 5;
+    printf(\"%d \", N);
+    {
+      enum { N = 2 };
+      int b[N];
+      printf(\"%d %d \", N, sizeof(b));
+    }
+    printf(\"%d \", N);
+  }
+  printf(\"%d %d\", N, sizeof(a));
+  return 0;
+}
+";
+    assert_eq!(run("t.c", src), Ok((0, "5 2 8 5 3 12".into())));
+}
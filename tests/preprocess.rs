@@ -0,0 +1,287 @@
+// Checks the preprocessor: the evaluation of #if expressions, the text
+// printed by -E and the search for included files.
+
+use seu9cc::preprocess::{source_text, Preprocessor};
+use seu9cc::token::{tokenize, tokenize_input};
+use seu9cc::TokenType;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn eval_if(expr: &str) -> bool {
+    let src = format!("#if {}\nyes\n#else\nno\n#endif\n", expr);
+    let tokens = tokenize_input("t.c".into(), src, &mut Preprocessor::new())
+        .unwrap_or_else(|e| panic!("{}", e));
+    match tokens[0].ty {
+        TokenType::Ident(ref s) => s == "yes",
+        _ => panic!("{:?}", tokens[0].ty),
+    }
+}
+
+#[test]
+fn shifts() {
+    assert!(!eval_if("1 << 70"));
+    assert!(eval_if("(1 << 63) < 0"));
+    assert!(!eval_if("1 << -1"));
+    assert!(eval_if("(-1 >> 70) == -1"));
+    assert!(eval_if("(-1 >> 63) == -1"));
+    assert!(eval_if("(8 >> -1) == 16"));
+    // The most negative count has no positive counterpart.
+    let min = "(-9223372036854775807 - 1)";
+    assert!(!eval_if(&format!("1 << {}", min)));
+    assert!(!eval_if(&format!("1 >> {}", min)));
+    assert!(eval_if(&format!("(-1 << {}) == -1", min)));
+    assert!(eval_if(&format!("(-1 >> {}) == 0", min)));
+}
+
+#[test]
+fn comma_and_conditional() {
+    assert!(eval_if("(1, 2) == 2"));
+    assert!(eval_if("(0 ? 1 : 2) == 2"));
+}
+
+#[test]
+fn unevaluated_operands() {
+    assert!(!eval_if("0 && 1 / 0"));
+    assert!(eval_if("1 || 1 / 0"));
+    assert!(eval_if("0 ? 1 / 0 : 2"));
+}
+
+#[test]
+fn large_literals_are_unsigned() {
+    assert!(eval_if("0xFFFFFFFFFFFFFFFF > 0"));
+    assert!(eval_if("18446744073709551615 > 0"));
+    assert!(eval_if("9223372036854775807 + 1 < 0"));
+}
+
+#[test]
+fn unsigned_suffix() {
+    assert!(eval_if("-1 > 0u"));
+    assert!(eval_if("-1 / 2u > 0"));
+    assert!(eval_if("-1 > 0UL"));
+    assert!(!eval_if("-1 > 0L"));
+    assert!(eval_if("(1 ? -1 : 0u) > 0"));
+}
+
+#[test]
+fn limits_h() {
+    let src = "#include <limits.h>\n#if ULONG_MAX > UINT_MAX && LONG_MIN < INT_MIN && UINT_MAX > INT_MAX\nyes\n#endif\n";
+    let tokens = tokenize_input("t.c".into(), src.into(), &mut Preprocessor::new())
+        .unwrap_or_else(|e| panic!("{}", e));
+    assert!(tokens
+        .first()
+        .is_some_and(|t| t.ty == TokenType::Ident("yes".into())));
+}
+
+fn preprocess_only(src: &str) -> String {
+    let tokens = tokenize_input("t.c".into(), src.into(), &mut Preprocessor::new())
+        .unwrap_or_else(|e| panic!("{}", e));
+    source_text(&tokens)
+}
+
+#[test]
+fn joined_strings_keep_their_line() {
+    let out = preprocess_only("int a;\nchar *c = \"x\" \"y\";\nint b;\n");
+    assert_eq!(out, "# 1 \"t.c\"\nint a;\nchar *c = \"xy\";\nint b;\n");
+}
+
+#[test]
+fn control_characters_are_escaped() {
+    let out = preprocess_only("char *s = \"a\\001b\\x7f\\t\";\n");
+    assert_eq!(out, "# 1 \"t.c\"\nchar *s = \"a\\001b\\177\\t\";\n");
+    // Reading the output back gives the same string.
+    let again = preprocess_only("char *s = \"a\\001b\\177\\t\";\n");
+    assert_eq!(again, out);
+
+    // Escapes above 0x7f stand for single bytes; other characters are
+    // kept as UTF-8.
+    let out = preprocess_only("char *s = \"\\xff\\200é\"; int c = '\\xff';\n");
+    assert_eq!(
+        out,
+        "# 1 \"t.c\"\nchar *s = \"\\377\\200é\"; int c = '\\377';\n"
+    );
+    let again = preprocess_only("char *s = \"\\377\\200é\"; int c = '\\377';\n");
+    assert_eq!(again, out);
+}
+
+#[test]
+fn macro_call_across_lines() {
+    let src = "#define ADD(a, b) a + b\nADD\n(3, 4);\n";
+    assert_eq!(preprocess_only(src), "# 2 \"t.c\"\n3 + 4\n;\n");
+}
+
+#[test]
+fn macro_name_without_call() {
+    let src = "#define F(x) x\nint F;\n#define G 1\nF\n#undef G\nG;\n";
+    assert_eq!(preprocess_only(src), "# 2 \"t.c\"\nint F;\n\nF\n\nG;\n");
+}
+
+// Creates an empty temporary directory holding the given files.
+fn temp_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("seu9cc-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for (path, contents) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    dir
+}
+
+// Preprocesses a file with the given -I directories, and returns the
+// spellings of the tokens or the error message.
+fn include(path: &Path, dirs: &[PathBuf]) -> Result<String, String> {
+    let mut ctx = Preprocessor::new();
+    for dir in dirs {
+        ctx.add_include_path(dir.to_string_lossy().into_owned());
+    }
+    let path = path.to_string_lossy().into_owned();
+    let tokens = tokenize(path, &mut ctx).map_err(|e| e.message)?;
+    let words: Vec<String> = tokens.iter().map(|t| t.tokstr()).collect();
+    Ok(words.join(" "))
+}
+
+#[test]
+fn quoted_include_starts_in_the_current_directory() {
+    let dir = temp_dir(
+        "quoted",
+        &[
+            (
+                "main.c",
+                "#include \"a.h\"\n#include \"sub/b.h\"\n#include \"c.h\"\n",
+            ),
+            ("a.h", "main_a"),
+            ("sub/b.h", "#include \"a.h\"\n"),
+            ("sub/a.h", "sub_a"),
+            ("inc/a.h", "inc_a"),
+            ("inc/c.h", "inc_c"),
+        ],
+    );
+    let out = include(&dir.join("main.c"), &[dir.join("inc")]);
+    assert_eq!(out.unwrap(), "main_a sub_a inc_c");
+}
+
+#[test]
+fn include_search_order() {
+    let dir = temp_dir(
+        "order",
+        &[
+            (
+                "main.c",
+                "#include <x.h>\n#include <y.h>\n#include \"y.h\"\n",
+            ),
+            ("y.h", "main_y"),
+            ("i1/x.h", "i1_x"),
+            ("i2/x.h", "i2_x"),
+            ("i2/y.h", "i2_y"),
+        ],
+    );
+    let dirs = [dir.join("i1"), dir.join("i2")];
+    assert_eq!(
+        include(&dir.join("main.c"), &dirs).unwrap(),
+        "i1_x i2_y main_y"
+    );
+    let dirs = [dir.join("i2"), dir.join("i1")];
+    assert_eq!(
+        include(&dir.join("main.c"), &dirs).unwrap(),
+        "i2_x i2_y main_y"
+    );
+    let out = include(&dir.join("main.c"), &[]);
+    assert_eq!(out.unwrap_err(), "x.h: file not found");
+}
+
+#[test]
+fn include_next() {
+    let dir = temp_dir(
+        "next",
+        &[
+            ("main.c", "#include <x.h>\n"),
+            ("i1/x.h", "i1_x\n#include_next <x.h>\n"),
+            ("i2/x.h", "i2_x\n#include_next \"x.h\"\n"),
+            ("i3/x.h", "i3_x\n"),
+        ],
+    );
+    let dirs = [dir.join("i1"), dir.join("i2"), dir.join("i3")];
+    let out = include(&dir.join("main.c"), &dirs);
+    assert_eq!(out.unwrap(), "i1_x i2_x i3_x");
+    // The search continues after the directory the file was found in,
+    // not after the first directory that has it.
+    let dirs = [dir.join("i2"), dir.join("i1"), dir.join("i3")];
+    let out = include(&dir.join("main.c"), &dirs);
+    assert_eq!(out.unwrap(), "i2_x i1_x i3_x");
+    let out = include(&dir.join("main.c"), &dirs[..2]);
+    assert_eq!(out.unwrap_err(), "x.h: file not found");
+}
+
+#[test]
+fn include_depth_limit() {
+    // f0.h includes f1.h, and so on up to f<n>.h.
+    let chain = |n: usize| {
+        let mut files: Vec<(String, String)> = (0..n)
+            .map(|i| (format!("f{}.h", i), format!("#include \"f{}.h\"\n", i + 1)))
+            .collect();
+        files.push((format!("f{}.h", n), "end".into()));
+        files.push(("main.c".into(), "#include \"f0.h\"\n".into()));
+        let files: Vec<(&str, &str)> = files.iter().map(|(p, s)| (&**p, &**s)).collect();
+        let dir = temp_dir(&format!("depth{}", n), &files);
+        include(&dir.join("main.c"), &[])
+    };
+    assert_eq!(chain(199).unwrap(), "end");
+    assert_eq!(chain(200).unwrap_err(), "#include nested too deeply");
+}
+
+#[test]
+fn include_cycle() {
+    let dir = temp_dir(
+        "cycle",
+        &[
+            ("main.c", "#include \"a.h\"\n"),
+            ("a.h", "#include \"b.h\"\n"),
+            ("b.h", "#include \"a.h\"\n"),
+        ],
+    );
+    let (a, b) = (dir.join("a.h"), dir.join("b.h"));
+    let msg = format!(
+        "#include cycle: {} -> {} -> {}",
+        a.display(),
+        b.display(),
+        a.display()
+    );
+    assert_eq!(include(&dir.join("main.c"), &[]).unwrap_err(), msg);
+
+    // Include guards break the cycle.
+    let guarded = "#ifndef A\n#define A\na\n#include \"b.h\"\n#endif\n";
+    fs::write(&a, guarded).unwrap();
+    assert_eq!(include(&dir.join("main.c"), &[]).unwrap(), "a");
+}
+
+#[test]
+fn bundled_headers() {
+    let dir = temp_dir(
+        "bundled",
+        &[
+            ("main.c", "#include <stddef.h>\nsize_t\n"),
+            ("quoted.c", "#include \"stddef.h\"\nsize_t\n"),
+            ("i1/stddef.h", "mine\n"),
+            ("i2/stddef.h", "#include_next <stddef.h>\nwrapped\n"),
+        ],
+    );
+    let body = "typedef unsigned long size_t ; typedef long ptrdiff_t ; typedef int wchar_t ;";
+    // The bundled headers are found without any -I directory.
+    let out = include(&dir.join("main.c"), &[]);
+    assert_eq!(out.unwrap(), format!("{} size_t", body));
+    let out = include(&dir.join("quoted.c"), &[]);
+    assert_eq!(out.unwrap(), format!("{} size_t", body));
+    // -I directories come first, and can wrap a bundled header.
+    let out = include(&dir.join("main.c"), &[dir.join("i1")]);
+    assert_eq!(out.unwrap(), "mine size_t");
+    let out = include(&dir.join("main.c"), &[dir.join("i2")]);
+    assert_eq!(out.unwrap(), format!("{} wrapped size_t", body));
+
+    // The system directories come last, even if they have the header.
+    let mut ctx = Preprocessor::new();
+    let path = dir.join("t.c").to_string_lossy().into_owned();
+    let tokens = tokenize_input(path, "#include <limits.h>\nINT_MAX\n".into(), &mut ctx).unwrap();
+    assert_eq!(tokens[0].tokstr(), "2147483647");
+    assert_eq!(*tokens[0].filename, "<built-in>/limits.h");
+}
@@ -0,0 +1,194 @@
+// Compiles programs with `-target riscv64` and checks the assembly for
+// the frame layout and calling convention. The tests that also assemble
+// and link the output with a riscv64 cross compiler and run it under
+// qemu's user-mode emulator are ignored by default, since the tools are
+// not commonly installed. Run them with `cargo test -- --ignored`.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const CC: &str = "riscv64-linux-gnu-gcc";
+const QEMU: &str = "qemu-riscv64";
+
+// Compiles `src` to riscv64 assembly with the seu9cc binary.
+fn compile(name: &str, src: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("seu9cc-riscv-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, src).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_seu9cc"))
+        .args(["-target", "riscv64"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}: {}",
+        name,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+// Assembles, links and runs the assembly under qemu.
+fn run(name: &str, asm: &str) -> Output {
+    let dir = std::env::temp_dir().join(format!("seu9cc-riscv-{}", std::process::id()));
+    let exe: PathBuf = dir.join(name).with_extension("");
+    let mut cc = Command::new(CC)
+        .args(["-static", "-x", "assembler", "-", "-o"])
+        .arg(&exe)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("{}: {}", CC, e));
+    cc.stdin.take().unwrap().write_all(asm.as_bytes()).unwrap();
+    assert!(cc.wait().unwrap().success(), "{}: assembly failed", name);
+    Command::new(QEMU)
+        .arg(&exe)
+        .output()
+        .unwrap_or_else(|e| panic!("{}: {}", QEMU, e))
+}
+
+// Returns the instructions of a function, from its label to its `ret`.
+fn function<'a>(asm: &'a str, name: &str) -> Vec<&'a str> {
+    let label = format!("{}:", name);
+    let lines: Vec<&str> = asm
+        .lines()
+        .skip_while(|l| *l != label)
+        .skip(1)
+        .map(|l| l.trim())
+        .collect();
+    let end = lines.iter().position(|l| *l == "ret").unwrap();
+    lines[..=end].to_vec()
+}
+
+// Checks that `lines` contains `expected` as a contiguous run.
+fn assert_contains(lines: &[&str], expected: &[&str]) {
+    assert!(
+        lines.windows(expected.len()).any(|w| w == expected),
+        "{:?} not found in:\n{}",
+        expected,
+        lines.join("\n")
+    );
+}
+
+fn read_example(name: &str) -> String {
+    let path = format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), name);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+fn check(name: &str, src: &str, status: i32, stdout: &str) {
+    let out = run(name, &compile(name, src));
+    assert_eq!(out.status.code(), Some(status), "{}", name);
+    assert_eq!(String::from_utf8_lossy(&out.stdout), stdout, "{}", name);
+}
+
+#[test]
+fn prologue_and_epilogue() {
+    let asm = compile("frame.c", "int main() { int x = 3; return x; }");
+    let body = function(&asm, "main");
+    // ra and s0 are saved above the frame, and s0 points at them.
+    assert_eq!(
+        body[..4],
+        [
+            "addi sp, sp, -16",
+            "sd ra, 8(sp)",
+            "sd s0, 0(sp)",
+            "mv s0, sp"
+        ]
+    );
+    // 16 bytes of locals, then the 11 callee-saved registers in 96.
+    assert_eq!(body[4], "addi sp, sp, -112");
+    assert_eq!(body[5], "sd s1, -24(s0)");
+    assert_eq!(body[15], "sd s11, -104(s0)");
+    assert_eq!(
+        body[body.len() - 5..],
+        [
+            "mv sp, s0",
+            "ld ra, 8(sp)",
+            "ld s0, 0(sp)",
+            "addi sp, sp, 16",
+            "ret"
+        ]
+    );
+}
+
+#[test]
+fn stack_arguments() {
+    let src = "
+int sum(int a, int b, int c, int d, int e, int f, int g, int h, int i, int j, int k) {
+  return i + j + k;
+}
+int main() { return sum(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11); }
+";
+    let asm = compile("args.c", src);
+
+    // The callee finds argument n > 8 at 16+(n-9)*8(s0), just above
+    // the saved ra and s0, in the caller's outgoing area.
+    let sum = function(&asm, "sum");
+    assert_contains(&sum, &["sw a7, -32(s0)", "ld t1, 16(s0)", "sw t1, -36(s0)"]);
+    assert_contains(&sum, &["ld t1, 24(s0)", "sw t1, -40(s0)"]);
+    assert_contains(&sum, &["ld t1, 32(s0)", "sw t1, -44(s0)"]);
+
+    // The caller passes arguments 7 and 8 in a6 and a7 and stores the
+    // other three at the bottom of its frame, padded to 16 bytes.
+    let main = function(&asm, "main");
+    let call: Vec<&str> = main
+        .iter()
+        .skip_while(|l| !l.starts_with("mv a0,"))
+        .take(16)
+        .cloned()
+        .collect();
+    assert!(call[6].starts_with("ld a6, -"), "{:?}", call);
+    assert!(call[7].starts_with("ld a7, -"), "{:?}", call);
+    assert_eq!(call[8], "addi sp, sp, -32");
+    assert_eq!(
+        [call[10], call[12], call[14]],
+        ["sd t1, 0(sp)", "sd t1, 8(sp)", "sd t1, 16(sp)"]
+    );
+    assert_eq!(call[15], "call sum");
+    assert_eq!(
+        main[main.iter().position(|l| *l == "call sum").unwrap() + 1],
+        "addi sp, sp, 32"
+    );
+}
+
+#[test]
+#[ignore = "needs riscv64-linux-gnu-gcc and qemu-riscv64"]
+fn examples() {
+    for name in ["example.c", "fib.c", "nqueen.c"] {
+        let out = run(name, &compile(name, &read_example(name)));
+        assert_eq!(out.status.code(), Some(0), "{}", name);
+    }
+    let out = run("prime.c", &compile("prime.c", &read_example("prime.c")));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.starts_with("prime!: 3\nprime!: 5\n"), "{}", stdout);
+}
+
+#[test]
+#[ignore = "needs riscv64-linux-gnu-gcc and qemu-riscv64"]
+fn exit_status() {
+    check("ret.c", "int main() { return 42; }", 42, "");
+    let src = "void exit(int); int main() { exit(3); return 0; }";
+    check("exit.c", src, 3, "");
+}
+
+#[test]
+#[ignore = "needs riscv64-linux-gnu-gcc and qemu-riscv64"]
+fn arithmetic_and_calls() {
+    let src = "
+int printf();
+int sum(int a, int b, int c, int d, int e, int f, int g, int h, int i) {
+  return a + b + c + d + e + f + g + h + i;
+}
+int main() {
+  unsigned char c = 250;
+  c += 10;
+  long l = -5;
+  printf(\"%d %d %ld %d\\n\", sum(1, 2, 3, 4, 5, 6, 7, 8, 9), c, l * l, ~3 >= -4);
+  return 0;
+}
+";
+    check("calls.c", src, 0, "45 4 25 1\n");
+}
@@ -0,0 +1,164 @@
+// Compiles programs to x86-64 assembly, then assembles and links them
+// with gcc and runs them.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("seu9cc-x86-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Compiles `src` to x86-64 assembly with the seu9cc binary.
+fn compile(name: &str, src: &str) -> String {
+    let path = dir().join(name);
+    fs::write(&path, src).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_seu9cc"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(
+        out.status.success(),
+        "{}: {}",
+        name,
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+// Assembles, links and runs the assembly.
+fn run(name: &str, asm: &str) -> Output {
+    let exe = dir().join(name).with_extension("");
+    let mut cc = Command::new("gcc")
+        .args(["-x", "assembler", "-", "-o"])
+        .arg(&exe)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    cc.stdin.take().unwrap().write_all(asm.as_bytes()).unwrap();
+    assert!(cc.wait().unwrap().success(), "{}: assembly failed", name);
+    Command::new(&exe).output().unwrap()
+}
+
+fn read_example(name: &str) -> String {
+    let path = format!("{}/examples/{}", env!("CARGO_MANIFEST_DIR"), name);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e))
+}
+
+fn check(name: &str, src: &str, status: i32, stdout: &str) {
+    let out = run(name, &compile(name, src));
+    assert_eq!(out.status.code(), Some(status), "{}", name);
+    assert_eq!(String::from_utf8_lossy(&out.stdout), stdout, "{}", name);
+}
+
+#[test]
+fn examples() {
+    for name in ["example.c", "fib.c", "nqueen.c"] {
+        let out = run(name, &compile(name, &read_example(name)));
+        assert_eq!(out.status.code(), Some(0), "{}", name);
+    }
+    let out = run("prime.c", &compile("prime.c", &read_example("prime.c")));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.starts_with("prime!: 3\nprime!: 5\n"), "{}", stdout);
+}
+
+#[test]
+fn exit_status() {
+    check("ret.c", "int main() { return 42; }", 42, "");
+    let src = "void exit(int); int main() { exit(3); return 0; }";
+    check("exit.c", src, 3, "");
+}
+
+#[test]
+fn struct_assignment() {
+    let src = "
+int printf();
+struct P { char c; short s; };
+struct S { int a; int b; int c; };
+struct Big { long v[20]; struct P p; };
+struct S g;
+int main() {
+  struct S s;
+  struct S t;
+  struct S u;
+  t.a = 1;
+  t.b = 2;
+  t.c = 3;
+  s = t;
+  g = u = s;
+  struct S w = g;
+  struct S *p = &w;
+  t.c = 4;
+  *p = t;
+  struct Big x;
+  struct Big y;
+  for (int i = 0; i < 20; i++)
+    x.v[i] = i * 3;
+  x.p.c = 7;
+  x.p.s = -9;
+  y = x;
+  struct P q = y.p;
+  printf(\"%d %d %d %d %d %ld %d %d\\n\", s.a, s.c, u.b, g.c, w.c, y.v[19], q.c, q.s);
+  return 0;
+}
+";
+    check("struct.c", src, 0, "1 3 2 3 4 57 7 -9\n");
+}
+
+#[test]
+fn switch_case_conversion() {
+    let src = "
+int main() {
+  unsigned u = 4294967295u;
+  switch (u) { case -1: break; default: return 1; }
+  char c = -1;
+  switch (c) { case 255: return 2; case -1: break; }
+  unsigned char uc = 255;
+  switch (uc) { case -1: return 3; case 255: break; }
+  switch (1l << 32) { case 0: return 4; case 1l << 32: break; }
+  return 0;
+}
+";
+    check("switch.c", src, 0, "");
+}
+
+#[test]
+fn high_bytes_in_literals() {
+    let src = "
+int printf();
+char g[] = \"\\xff\\200a\";
+int main() {
+  char s[] = \"\\xff\\200\";
+  char *p = \"\\377é\";
+  printf(\"%d %d %d %d \", sizeof(s), s[0], s[1], sizeof(g));
+  printf(\"%d %d %d %d %d\\n\", g[0], g[1], p[0], p[1], '\\xff');
+  return 0;
+}
+";
+    check("bytes.c", src, 0, "3 -1 -128 4 -1 -128 -1 -61 -1\n");
+}
+
+#[test]
+fn function_designators() {
+    // A function name that is not called decays to its address.
+    let src = "int f() { return 3; } int main() { long p = (long)&f; long q = (long)f; return (p == q) + (p != 0); }";
+    check("func.c", src, 2, "");
+}
+
+#[test]
+fn overridden_initializers() {
+    // An address that a later initializer overrides must not be
+    // relocated over the new value, and the other way round.
+    let src = "
+int printf();
+int x = 7;
+int *p[2] = { &x, [0] = 0 };
+long l[2] = { (long)&x, [0] = 5 };
+struct { int *p; int v; } s = { .p = 0, .v = 1, .p = &x };
+int main() { printf(\"%d %ld %d\\n\", p[0] == 0, l[0], *s.p); return 0; }
+";
+    check("init.c", src, 0, "1 5 7\n");
+}